@@ -1,66 +1,618 @@
 use std::env;
-use std::{thread, time};
+use std::io::{self, BufRead, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::Instant;
 extern crate cj_8;
+use crate::cj_8::cli::{self, CheckOptions, Command, RunOptions};
+use crate::cj_8::config::{ConfigSource, MachineOptions, Settings};
+use crate::cj_8::database::RomInfo;
+use crate::cj_8::detect::CONFIDENCE_THRESHOLD;
+use crate::cj_8::disasm;
+#[cfg(unix)]
+use crate::cj_8::frontend::terminal::TerminalFrontend;
+use crate::cj_8::frontend::units::UnitFrontend;
+use crate::cj_8::frontend::watch::Watch;
+use crate::cj_8::frontend::{run, Session};
+use crate::cj_8::movie::Movie;
+use crate::cj_8::palette::Palette;
+use crate::cj_8::patch::{self, PatchFormat};
+use crate::cj_8::romfile::{Format, UnpackError};
 use crate::cj_8::system::*;
 use crate::cj_8::units::au::*;
 use crate::cj_8::units::cu::*;
 use crate::cj_8::units::gu::*;
+use crate::cj_8::units::keymap::*;
 use crate::cj_8::units::ku::*;
-use crate::cj_8::units::platform::*;
+use crate::cj_8::units::tu::*;
+use crate::cj_8::variant::Variant;
 
 fn main() {
-    // Accept args and throw errors if necessary
-    let args: Vec<String> = env::args().collect();
-    if args.len() != 3 {
-        eprintln!("ERROR: Failed to parse args");
-        panic!("usage: cj-8 resolution-scale path-to-ROM");
-    }
-    println!("Args accepted");
+    // Bad usage exits with 2, a ROM that can't be run or a failed check with 1
+    let command = match cli::parse(env::args().skip(1)) {
+        Ok(command) => command,
+        Err(e) => {
+            eprintln!("ERROR: {}", e);
+            eprintln!("Run cj-8 --help for usage");
+            process::exit(2);
+        }
+    };
 
-    let scale = args[1].parse::<u32>().unwrap();
+    let result = match command {
+        Command::Help => {
+            print!("{}", cli::USAGE);
+            Ok(())
+        }
+        Command::Version => {
+            println!("cj-8 {}", env!("CARGO_PKG_VERSION"));
+            Ok(())
+        }
+        Command::Run(options) => run_rom(options),
+        Command::Disasm(rom, patches, machine) => disassemble(&rom, &patches, &machine),
+        Command::Info(rom, patches, machine) => info(&rom, &patches, &machine),
+        Command::Bench(options) => bench(options),
+        Command::Test(options) => test(options),
+        Command::MakePatch {
+            original,
+            modified,
+            output,
+        } => make_patch(&original, &modified, &output),
+    };
+    if let Err(e) = result {
+        eprintln!("ERROR: {}", e);
+        process::exit(1);
+    }
+}
 
+fn run_rom(options: RunOptions) -> Result<(), String> {
     // Init cartridge unit
-    let cartridge = CU::new(&args[2]).unwrap();
+    let cartridge = load_rom(&options.rom, &options.patches)?;
+    for (patch, format) in cartridge.patches.iter() {
+        println!("Applied {} patch {}", format, patch);
+    }
+    let rom_hash = cartridge.sha1();
+    let rom_info = cartridge.lookup();
+    if let Some(info) = &rom_info {
+        println!("Found in ROM database: {}", info);
+    }
+
+    // What the ROM database knows, then the config file, then the command line
+    let mut settings = rom_settings(
+        &cartridge,
+        rom_info.as_ref(),
+        &options.config,
+        &options.settings.machine,
+    )?;
+    settings.merge(&options.settings);
+
+    // The scale only sets the starting window size, the window can be resized afterwards
+    const DEFAULT_SCALE: u32 = 10;
+    let scale = settings.scale.unwrap_or(DEFAULT_SCALE);
+    let palette = settings.palette.unwrap_or_default();
+    let tone = settings.tone();
 
     // Init blank slate system
     let mut system = System::new();
-    println!("New CJ-8 created with cartridge path: {}", args[2]);
-
-    // Setup render system and input
-    const WINDOW_WIDTH: u32 = 64;
-    const WINDOW_HEIGHT: u32 = 32;
-    let context = Platform::new();
-    println!("SDL context created");
-
-    let mut graphical_unit = GU::new(
-        &context.context,
-        "CJ-8",
-        WINDOW_WIDTH * scale,
-        WINDOW_HEIGHT * scale,
+    settings.machine.configure(&mut system)?;
+    println!(
+        "New CJ-8 created with cartridge path: {}",
+        options.rom.display()
     );
-    let mut keyboard_unit = KU::new(&context.context);
-    let mut audio_unit = AU::new(&context.context);
-    graphical_unit.init();
-    println!("Front-End Units Initialized");
 
-    // Clear memory and load ROM
-    system.init(cartridge.buffer);
+    // A movie being played back decides the seed, speed, quirks, memory and load address,
+    // recording captures them
+    let mut session = Session {
+        max_frames: options.max_frames,
+        max_cycles: options.max_cycles,
+        ..Session::default()
+    };
+    if let Some(path) = &options.play_movie {
+        session.playback = Some(load_movie(path, &rom_hash, &mut system)?);
+    }
+    if options.record_movie.is_some() {
+        let mut movie = Movie::new(&rom_hash, system.seed(), system.ipf, system.quirks);
+        movie.platform = Some(settings.machine.platform.unwrap_or(Variant::Chip8));
+        movie.load_address = Some(system.load_address());
+        movie.entry_point = Some(usize::from(system.entry_point()));
+        session.recording = Some(movie);
+    }
+
+    if options.watch {
+        // A file picked from a zip is the one to reload, not the question again
+        let path = match cartridge.formats.iter().find_map(|format| match format {
+            Format::Zip(entry) if split_entry(&options.rom).1.is_none() => Some(entry),
+            _ => None,
+        }) {
+            Some(entry) => format!("{}#{}", options.rom.display(), entry).into(),
+            None => options.rom.clone(),
+        };
+        println!("Watching {} for changes", options.rom.display());
+        session.watch = Some(Watch::new(
+            path,
+            options.patches.clone(),
+            options.watch_keep_state,
+        ));
+    }
+
+    // Clear memory and load ROM, keeping a copy for resets
+    session.rom = cartridge.buffer;
+    let platform = session.playback.as_ref().and_then(|movie| movie.platform);
+    // The terminal and the opcode trace would fight over stdout, and headless runs only want the
+    // results, so the trace goes before init prints what it loaded
+    if options.terminal.is_some() || options.headless {
+        system.trace = false;
+    }
+    init_system(
+        &mut system,
+        &session.rom,
+        platform.or(settings.machine.platform),
+    )?;
+
+    if let Some(mode) = options.terminal {
+        run_terminal(&mut system, &mut session, mode, palette, options.bell)?;
+    } else {
+        // Setup render system and input
+        let mut frontend = if options.headless {
+            // Nothing touches SDL, frames and sound are only kept for recordings
+            UnitFrontend::headless(GU::headless(scale), KU::headless(), AU::headless(tone))
+        } else {
+            let title = match &rom_info {
+                Some(info) => format!("CJ-8 - {}", info.title),
+                None => "CJ-8".to_string(),
+            };
+            UnitFrontend::sdl(&title, scale, tone)
+        };
+        frontend.keyboard_unit.keymap =
+            load_keymap(settings.keymap.as_deref(), &options.rom, rom_info.as_ref())?;
+        frontend.graphical_unit.palette = palette;
+        frontend.graphical_unit.integer_scaling = settings.integer_scale.unwrap_or(false);
+        frontend
+            .graphical_unit
+            .set_fullscreen(settings.fullscreen.unwrap_or(false));
+        frontend.graphical_unit.init();
+        frontend.record_limit = options.record_frames;
+        println!("Front-End Units Initialized");
+
+        // Start recording straight away when asked to on the command line
+        let record_audio = options.record_audio.as_deref();
+        match (&options.record, record_audio) {
+            (Some(path), _) => frontend.start_recording(path, scale, record_audio),
+            (None, Some(path)) => frontend.start_audio_recording(path),
+            (None, None) => {}
+        }
+
+        run(&mut system, &mut frontend, &mut session);
+    }
+
+    if let (Some(path), Some(mut movie)) = (&options.record_movie, session.recording.take()) {
+        movie.final_state = Some(system.state_hash());
+        match movie.save(path) {
+            Ok(()) => println!(
+                "Movie of {} frames saved to {}",
+                movie.frames.len(),
+                path.display()
+            ),
+            Err(e) => eprintln!("ERROR: Failed to save movie: {}", e),
+        }
+    }
+    if options.headless {
+        println!(
+            "Ran {} frames, {} cycles, {:.3}s emulated",
+            session.frames,
+            session.cycles,
+            system.emulated_time()
+        );
+    }
+    match &session.playback {
+        Some(playback) => verify_movie(playback, &system, session.frames),
+        None => Ok(()),
+    }
+}
+
+// List the ROM as it sits in memory
+fn disassemble(rom: &Path, patches: &[PathBuf], machine: &MachineOptions) -> Result<(), String> {
+    let cartridge = load_rom(rom, patches)?;
+    for line in disasm::listing(&cartridge.buffer, machine.load_address() as u16) {
+        println!("{}", line);
+    }
+    Ok(())
+}
 
-    // Emu loop
-    while let Ok(keys) = keyboard_unit.process_input() {
-        // Program Cycle
-        system.emulate_cycle(&mut audio_unit, &keys, &keyboard_unit);
+fn info(rom: &Path, patches: &[PathBuf], machine: &MachineOptions) -> Result<(), String> {
+    let cartridge = match load_rom(rom, patches) {
+        Ok(cartridge) => cartridge,
+        Err(e) => {
+            // A cartridge whose program doesn't assemble still has its options to show
+            if let Err(CartridgeError::Unpack(_, UnpackError::Octo(octo, _))) = CU::new(rom) {
+                println!("Octo:  {}", octo.describe());
+            }
+            return Err(e);
+        }
+    };
+    println!("ROM:   {}", cartridge.name);
+    if !cartridge.formats.is_empty() {
+        let formats: Vec<String> = cartridge.formats.iter().map(|f| f.to_string()).collect();
+        println!("From:  {}", formats.join(" > "));
+    }
+    for (patch, format) in cartridge.patches.iter() {
+        println!("Patch: {} ({})", patch, format);
+    }
+    println!("Size:  {} bytes", cartridge.size());
+    println!("SHA-1: {}", cartridge.sha1());
+    println!("CRC32: {:08x}", cartridge.crc32());
+    let fits: Vec<String> = Variant::ALL
+        .iter()
+        .map(|&platform| match cartridge.check_fits(platform) {
+            Ok(()) => format!("{} yes", platform),
+            Err(_) => format!(
+                "{} no, {} bytes over",
+                platform,
+                cartridge.size() - platform.max_rom_size()
+            ),
+        })
+        .collect();
+    println!("Fits:  {}", fits.join(", "));
 
-        // Check drawflag
-        if system.draw_flag == true {
-            graphical_unit.draw(scale, &system.gfx);
-            system.falsify_df();
+    match cartridge.lookup() {
+        Some(info) => {
+            println!("Title: {}", info);
+            if let Some(description) = &info.description {
+                println!("About: {}", description);
+            }
+            match (&info.platform_id, info.platform) {
+                (Some(id), Some(platform)) => println!("Platform: {} (runs as {})", id, platform),
+                _ => println!("Platform: none CJ-8 can run"),
+            }
+            if let Some(quirks) = info.quirks {
+                println!("Quirks: {}", quirks);
+            }
+            if let Some(tickrate) = info.tickrate {
+                println!("Speed: {} IPF", tickrate);
+            }
+            if !info.keys.is_empty() {
+                let keys: Vec<String> = info
+                    .keys
+                    .iter()
+                    .map(|(name, key)| format!("{}={:X}", name, key))
+                    .collect();
+                println!("Keys:  {}", keys.join(", "));
+            }
         }
+        None => println!("Not in the ROM database"),
+    }
+
+    if let Some(octo) = &cartridge.cartridge {
+        println!("Octo:  {}", octo.describe());
+    }
+
+    let detection = cartridge.detect(machine.load_address(), machine.entry_point());
+    println!(
+        "Guess: {}{} ({:.0}% sure{})",
+        detection.platform,
+        if detection.hires { ", HIRES 64x64" } else { "" },
+        detection.confidence * 100.0,
+        if detection.confidence >= CONFIDENCE_THRESHOLD {
+            ""
+        } else {
+            ", too unsure to use"
+        }
+    );
+    for evidence in detection.evidence.iter() {
+        println!("  {}", evidence);
+    }
+    Ok(())
+}
+
+// Set a system up with the ROM loaded for bench and test, which run without any frontend, along
+// with the movie a test plays
+fn check_system(options: &CheckOptions) -> Result<(System, Option<Movie>), String> {
+    let cartridge = load_rom(&options.rom, &options.patches)?;
+    let mut machine = rom_settings(
+        &cartridge,
+        cartridge.lookup().as_ref(),
+        &options.config,
+        &options.machine,
+    )?
+    .machine;
+    machine.merge(&options.machine);
+
+    let mut system = System::new();
+    system.trace = false;
+    machine.configure(&mut system)?;
+    let movie = match &options.movie {
+        Some(path) => Some(load_movie(path, &cartridge.sha1(), &mut system)?),
+        None => None,
+    };
+    let platform = movie.as_ref().and_then(|movie| movie.platform);
+    init_system(
+        &mut system,
+        &cartridge.buffer,
+        platform.or(machine.platform),
+    )?;
+    Ok((system, movie))
+}
+
+// Run flat out with no keys held and report how fast the core goes
+fn bench(options: CheckOptions) -> Result<(), String> {
+    let (mut system, _) = check_system(&options)?;
+
+    let keys = [false; 16];
+    let start = Instant::now();
+    for _ in 0..options.frames {
+        system.run_frame(&keys);
+        system.take_buzzer_events();
+    }
+    let elapsed = start.elapsed().as_secs_f64().max(f64::EPSILON);
+
+    let instructions = options.frames * u64::from(system.ipf);
+    println!(
+        "Ran {} frames, {} instructions in {:.3}s",
+        options.frames, instructions, elapsed
+    );
+    println!(
+        "{:.0} frames/s, {:.0} instructions/s, {:.1}x real time",
+        options.frames as f64 / elapsed,
+        instructions as f64 / elapsed,
+        system.emulated_time() / elapsed
+    );
+    Ok(())
+}
 
-        // Reset Keydown state
-        keyboard_unit.reset_key_down();
+// Run for a number of frames, or through a movie, and check the screen and state it ends on
+fn test(options: CheckOptions) -> Result<(), String> {
+    let (mut system, movie) = check_system(&options)?;
 
-        // Execute roughly at 500hz
-        thread::sleep(time::Duration::from_millis(2));
+    let frames = movie
+        .as_ref()
+        .map_or(options.frames, |movie| movie.frames.len() as u64);
+    for frame in 0..frames {
+        let keys = movie
+            .as_ref()
+            .and_then(|movie| movie.keys(frame as usize))
+            .unwrap_or([false; 16]);
+        system.run_frame(&keys);
+        system.take_buzzer_events();
+    }
+
+    let display = system.display_hash();
+    println!("Ran {} frames, display {}", frames, display);
+    if let Some(movie) = &movie {
+        verify_movie(movie, &system, frames)?;
+    }
+    match &options.expect {
+        Some(expected) if *expected != display => {
+            Err(format!("Display is {} but expected {}", display, expected))
+        }
+        Some(_) => {
+            println!("Display matches");
+            Ok(())
+        }
+        None => Ok(()),
+    }
+}
+
+// Describe how to turn one ROM into another, BPS unless the patch is named .ips
+fn make_patch(original: &Path, modified: &Path, output: &Path) -> Result<(), String> {
+    let original = load_rom(original, &[])?;
+    let modified = load_rom(modified, &[])?;
+    let format = match output.extension().and_then(|extension| extension.to_str()) {
+        Some(extension) if extension.eq_ignore_ascii_case("ips") => PatchFormat::Ips,
+        _ => PatchFormat::Bps,
+    };
+    let data = patch::create(&original.buffer, &modified.buffer, format)?;
+    std::fs::write(output, &data)
+        .map_err(|e| format!("Unable to write patch {}: {}", output.display(), e))?;
+    println!(
+        "Wrote {} patch {} ({} bytes)",
+        format,
+        output.display(),
+        data.len()
+    );
+    Ok(())
+}
+
+// Settings for a ROM before the command line: the ROM database's, or a guess from the ROM's
+// instructions if it isn't in there, then the config file's. The guess follows the code from
+// the command line's entry point
+fn rom_settings(
+    cartridge: &CU,
+    rom_info: Option<&RomInfo>,
+    config: &ConfigSource,
+    machine: &MachineOptions,
+) -> Result<Settings, String> {
+    let mut settings = match rom_info {
+        Some(info) => info.settings(),
+        None => {
+            let detection = cartridge.detect(machine.load_address(), machine.entry_point());
+            if detection.confidence >= CONFIDENCE_THRESHOLD {
+                println!(
+                    "Detected platform {} ({:.0}% sure)",
+                    detection.platform,
+                    detection.confidence * 100.0
+                );
+            }
+            detection.settings()
+        }
+    };
+    // What the author saved an Octo cartridge with beats a guess or a database entry
+    if let Some(octo) = &cartridge.cartridge {
+        println!("Using the Octo cartridge's options");
+        settings.merge(&octo.settings());
+    }
+    settings.merge(&config.load()?.settings(&cartridge.sha1()));
+    Ok(settings)
+}
+
+// Load a ROM and apply its patches in order
+fn load_rom(path: &Path, patches: &[PathBuf]) -> Result<CU, String> {
+    let mut cartridge = open_rom(path)?;
+    for patch in patches {
+        cartridge.patch(patch).map_err(|e| e.to_string())?;
+    }
+    Ok(cartridge)
+}
+
+fn open_rom(path: &Path) -> Result<CU, String> {
+    match CU::new(path) {
+        // Several files in a zip, ask which one when there's someone to ask
+        Err(CartridgeError::Unpack(_, UnpackError::Ambiguous(entries)))
+            if path != Path::new("-") && io::stdin().is_terminal() =>
+        {
+            let entry = pick_entry(&entries)?;
+            CU::open(path, Some(&entry)).map_err(|e| e.to_string())
+        }
+        result => result.map_err(|e| e.to_string()),
+    }
+}
+
+fn pick_entry(entries: &[String]) -> Result<String, String> {
+    println!("The archive holds several files:");
+    for (i, entry) in entries.iter().enumerate() {
+        println!("{:>3}. {}", i + 1, entry);
+    }
+    loop {
+        print!("Load which one? ");
+        io::stdout().flush().ok();
+        let mut line = String::new();
+        if io::stdin().lock().read_line(&mut line).unwrap_or(0) == 0 {
+            return Err("No file picked from the archive".to_string());
+        }
+        let line = line.trim();
+        let picked = match line.parse::<usize>() {
+            Ok(n) if (1..=entries.len()).contains(&n) => Some(&entries[n - 1]),
+            _ => entries.iter().find(|entry| entry.as_str() == line),
+        };
+        match picked {
+            Some(entry) => return Ok(entry.clone()),
+            None => println!("Enter a number from 1 to {}", entries.len()),
+        }
+    }
+}
+
+// Load the ROM into a configured system, naming the platform and load address if it doesn't fit
+fn init_system(system: &mut System, rom: &[u8], platform: Option<Variant>) -> Result<(), String> {
+    let load_address = system.load_address();
+    system.init(rom).map_err(|e| {
+        format!(
+            "{} on {} loading at {:#05X}",
+            e,
+            platform.unwrap_or(Variant::Chip8),
+            load_address
+        )
+    })
+}
+
+// Run in the current terminal without touching SDL
+#[cfg(unix)]
+fn run_terminal(
+    system: &mut System,
+    session: &mut Session,
+    mode: TerminalMode,
+    palette: Palette,
+    bell: bool,
+) -> Result<(), String> {
+    let terminal_unit =
+        TU::new(mode, palette, bell).map_err(|e| format!("Failed to set up terminal: {}", e))?;
+
+    let mut frontend = TerminalFrontend::new(terminal_unit);
+    run(system, &mut frontend, session);
+    let error = frontend.take_error();
+    // Give the terminal back before reporting anything
+    drop(frontend);
+    match error {
+        Some(e) => Err(format!("Failed to draw to terminal: {}", e)),
+        None => Ok(()),
+    }
+}
+
+#[cfg(not(unix))]
+fn run_terminal(
+    _system: &mut System,
+    _session: &mut Session,
+    _mode: TerminalMode,
+    _palette: Palette,
+    _bell: bool,
+) -> Result<(), String> {
+    Err("The terminal front end is only available on Unix".to_string())
+}
+
+// Load a movie for playback and set the system up the way it was recorded
+fn load_movie(path: &Path, rom_hash: &str, system: &mut System) -> Result<Movie, String> {
+    let movie = Movie::load(path).map_err(|e| format!("Failed to load movie: {}", e))?;
+    if movie.rom_hash != rom_hash {
+        eprintln!(
+            "ERROR: Movie was recorded with a different ROM ({}), playback will desync",
+            movie.rom_hash
+        );
+    }
+    system.set_seed(movie.seed);
+    system.ipf = movie.ipf;
+    system.quirks = movie.quirks;
+    // Before the ROM is loaded, resizing memory clears it
+    if let Some(platform) = movie.platform {
+        system.set_memory_size(platform.memory_size());
+    }
+    if let Some(load_address) = movie.load_address {
+        let entry_point = movie.entry_point.unwrap_or(load_address);
+        system.set_load_address(load_address, entry_point)?;
+    }
+    println!(
+        "Playing {} frames from {}",
+        movie.frames.len(),
+        path.display()
+    );
+    Ok(movie)
+}
+
+// Compare the state after playback with the one saved at the end of recording
+fn verify_movie(movie: &Movie, system: &System, frames: u64) -> Result<(), String> {
+    if frames < movie.frames.len() as u64 {
+        println!(
+            "Playback stopped after {} of {} frames",
+            frames,
+            movie.frames.len()
+        );
+        return Ok(());
+    }
+    match &movie.final_state {
+        Some(expected) if *expected == system.state_hash() => {
+            println!("Movie verified, final state {}", expected);
+            Ok(())
+        }
+        Some(expected) => Err(format!(
+            "Movie desynced, final state {} but expected {}",
+            system.state_hash(),
+            expected
+        )),
+        None => {
+            println!("Movie has no final state to verify against");
+            Ok(())
+        }
+    }
+}
+
+// Default bindings, then the controller layout from the ROM database, then the keymap from the config
+// file or --keymap, then a keymap next to the ROM for that game alone
+fn load_keymap(
+    path: Option<&Path>,
+    rom: &Path,
+    rom_info: Option<&RomInfo>,
+) -> Result<Keymap, String> {
+    let mut keymap = Keymap::default();
+    for (name, key) in rom_info.map_or(&[][..], |info| &info.keys) {
+        keymap.bind_pad(name, *key);
+    }
+    if let Some(path) = path {
+        keymap
+            .load(path)
+            .map_err(|e| format!("Failed to load keymap: {}", e))?;
+    }
+    let rom_keymap = Keymap::rom_path(rom);
+    if rom_keymap.is_file() {
+        match keymap.load(&rom_keymap) {
+            Ok(()) => println!("Keymap loaded from {}", rom_keymap.display()),
+            Err(e) => eprintln!("ERROR: Failed to load keymap: {}", e),
+        }
     }
+    Ok(keymap)
 }
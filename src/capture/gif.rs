@@ -0,0 +1,56 @@
+use crate::palette::Palette;
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::path::Path;
+
+// Animated GIF writer using a two color global palette
+pub struct GifWriter {
+    encoder: ::gif::Encoder<BufWriter<File>>,
+    width: u16,
+    height: u16,
+}
+
+impl GifWriter {
+    pub fn create(
+        path: &Path,
+        width: u32,
+        height: u32,
+        palette: &Palette,
+    ) -> io::Result<GifWriter> {
+        let width = u16::try_from(width).map_err(io::Error::other)?;
+        let height = u16::try_from(height).map_err(io::Error::other)?;
+
+        let mut colors = Vec::with_capacity(6);
+        colors.extend_from_slice(&palette.background);
+        colors.extend_from_slice(&palette.foreground);
+
+        let file = BufWriter::new(File::create(path)?);
+        let mut encoder = ::gif::Encoder::new(file, width, height, &colors).map_err(to_io)?;
+        encoder.set_repeat(::gif::Repeat::Infinite).map_err(to_io)?;
+
+        Ok(GifWriter {
+            encoder,
+            width,
+            height,
+        })
+    }
+
+    // Append a frame of palette indices shown for delay hundredths of a second
+    pub fn write_frame(&mut self, indices: &[u8], delay: u16) -> io::Result<()> {
+        let mut frame = ::gif::Frame::from_indexed_pixels(self.width, self.height, indices, None);
+        frame.delay = delay;
+        self.encoder.write_frame(&frame).map_err(to_io)
+    }
+
+    pub fn finish(self) -> io::Result<()> {
+        self.encoder.into_inner()?.into_inner()?;
+        Ok(())
+    }
+}
+
+fn to_io(err: ::gif::EncodingError) -> io::Error {
+    match err {
+        ::gif::EncodingError::Io(err) => err,
+        err => io::Error::other(err),
+    }
+}
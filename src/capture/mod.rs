@@ -0,0 +1,101 @@
+use crate::palette::Palette;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub mod gif;
+pub mod png;
+pub mod video;
+pub mod wav;
+pub mod y4m;
+
+// RGB image of a single frame
+pub struct Image {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<u8>,
+}
+
+impl Image {
+    // Expand the framebuffer into RGB pixels, each cell becoming a scale x scale block
+    pub fn from_gfx(gfx: &[[u16; 64]; 32], palette: &Palette, scale: u32) -> Image {
+        let (width, height, indices) = indexed_pixels(gfx, scale);
+        Image::from_indexed(width, height, &indices, palette)
+    }
+
+    // Map palette indices (0 or 1) to RGB pixels
+    pub fn from_indexed(width: u32, height: u32, indices: &[u8], palette: &Palette) -> Image {
+        let mut pixels = Vec::with_capacity(indices.len() * 3);
+        for &index in indices {
+            pixels.extend_from_slice(&palette.color(u16::from(index)));
+        }
+
+        Image {
+            width,
+            height,
+            pixels,
+        }
+    }
+}
+
+// Expand the framebuffer into one palette index per pixel, each cell becoming a scale x scale block
+pub fn indexed_pixels(gfx: &[[u16; 64]; 32], scale: u32) -> (u32, u32, Vec<u8>) {
+    let scale = scale.max(1) as usize;
+    let width = 64 * scale;
+    let height = 32 * scale;
+    let mut indices = Vec::with_capacity(width * height);
+
+    for row in gfx.iter() {
+        let mut line = Vec::with_capacity(width);
+        for &cell in row.iter() {
+            let index = u8::from(cell != 0);
+            line.extend(std::iter::repeat_n(index, scale));
+        }
+        for _ in 0..scale {
+            indices.extend_from_slice(&line);
+        }
+    }
+
+    (width as u32, height as u32, indices)
+}
+
+// Build a file path like dir/cj8-20240131-154502-123.ext from the current time
+pub fn timestamped_path(dir: &Path, extension: &str) -> PathBuf {
+    dir.join(format!("cj8-{}.{}", timestamp(), extension))
+}
+
+// Format the current UTC time as YYYYMMDD-HHMMSS-mmm
+pub fn timestamp() -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let secs = now.as_secs();
+    let (year, month, day) = civil_from_days((secs / 86_400) as i64);
+    let rem = secs % 86_400;
+
+    format!(
+        "{:04}{:02}{:02}-{:02}{:02}{:02}-{:03}",
+        year,
+        month,
+        day,
+        rem / 3600,
+        (rem % 3600) / 60,
+        rem % 60,
+        now.subsec_millis()
+    )
+}
+
+// Convert days since the Unix epoch to a (year, month, day) date
+// Based on Howard Hinnant's civil_from_days algorithm
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    (year, month, day)
+}
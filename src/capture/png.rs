@@ -0,0 +1,23 @@
+use super::Image;
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::path::Path;
+
+// Write an image to disk as an 8-bit RGB PNG
+pub fn save(image: &Image, path: &Path) -> io::Result<()> {
+    let file = File::create(path)?;
+    let mut encoder = ::png::Encoder::new(BufWriter::new(file), image.width, image.height);
+    encoder.set_color(::png::ColorType::Rgb);
+    encoder.set_depth(::png::BitDepth::Eight);
+
+    let mut writer = encoder.write_header().map_err(to_io)?;
+    writer.write_image_data(&image.pixels).map_err(to_io)?;
+    writer.finish().map_err(to_io)
+}
+
+fn to_io(err: ::png::EncodingError) -> io::Error {
+    match err {
+        ::png::EncodingError::IoError(err) => err,
+        err => io::Error::other(err),
+    }
+}
@@ -0,0 +1,149 @@
+use super::{gif::GifWriter, indexed_pixels, y4m::Y4mWriter, Image};
+use crate::palette::Palette;
+use std::io;
+use std::path::Path;
+use std::time::Instant;
+
+// Frame rate used to timestamp Y4M output
+const Y4M_FPS: u32 = 60;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VideoFormat {
+    Gif,
+    Y4m,
+}
+
+impl VideoFormat {
+    // Pick the format from the file extension
+    pub fn from_path(path: &Path) -> Option<VideoFormat> {
+        let ext = path.extension()?.to_str()?.to_ascii_lowercase();
+        match ext.as_str() {
+            "gif" => Some(VideoFormat::Gif),
+            "y4m" => Some(VideoFormat::Y4m),
+            _ => None,
+        }
+    }
+}
+
+enum Writer {
+    Gif(GifWriter),
+    Y4m(Y4mWriter),
+}
+
+// Records presented frames, holding each one back until the next arrives so its on-screen time is known
+pub struct Recorder {
+    writer: Writer,
+    palette: Palette,
+    scale: u32,
+    started: Instant,
+    // Seconds since the start of the recording at the last push, and whether the caller supplies them
+    now: f64,
+    external_clock: bool,
+    pending: Option<Vec<u8>>,
+    // Time already written out, in units of the output format (centiseconds or Y4M frames)
+    written: u64,
+    frames: u64,
+}
+
+impl Recorder {
+    pub fn create(path: &Path, palette: Palette, scale: u32) -> io::Result<Recorder> {
+        let format = VideoFormat::from_path(path).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "recording path must end in .gif or .y4m",
+            )
+        })?;
+        let scale = scale.max(1);
+        let (width, height) = (64 * scale, 32 * scale);
+
+        let writer = match format {
+            VideoFormat::Gif => Writer::Gif(GifWriter::create(path, width, height, &palette)?),
+            VideoFormat::Y4m => Writer::Y4m(Y4mWriter::create(path, width, height, Y4M_FPS)?),
+        };
+
+        Ok(Recorder {
+            writer,
+            palette,
+            scale,
+            started: Instant::now(),
+            now: 0.0,
+            external_clock: false,
+            pending: None,
+            written: 0,
+            frames: 0,
+        })
+    }
+
+    // Number of frames captured so far
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    // Capture a newly presented frame, timed by the wall clock
+    pub fn push(&mut self, gfx: &[[u16; 64]; 32]) -> io::Result<()> {
+        self.now = self.started.elapsed().as_secs_f64();
+        self.capture(gfx)
+    }
+
+    // Capture a frame presented the given number of seconds into the recording, e.g. in emulated time
+    pub fn push_at(&mut self, gfx: &[[u16; 64]; 32], seconds: f64) -> io::Result<()> {
+        self.advance_to(seconds);
+        self.capture(gfx)
+    }
+
+    // Move the caller supplied clock forward, so the last frame is held until then on finish
+    pub fn advance_to(&mut self, seconds: f64) {
+        self.external_clock = true;
+        self.now = self.now.max(seconds);
+    }
+
+    fn capture(&mut self, gfx: &[[u16; 64]; 32]) -> io::Result<()> {
+        let (_, _, indices) = indexed_pixels(gfx, self.scale);
+        self.flush_pending()?;
+        self.pending = Some(indices);
+        self.frames += 1;
+        Ok(())
+    }
+
+    // Write out the last frame and close the file
+    pub fn finish(mut self) -> io::Result<()> {
+        if !self.external_clock {
+            self.now = self.started.elapsed().as_secs_f64();
+        }
+        self.flush_pending()?;
+        match self.writer {
+            Writer::Gif(writer) => writer.finish(),
+            Writer::Y4m(writer) => writer.finish(),
+        }
+    }
+
+    fn flush_pending(&mut self) -> io::Result<()> {
+        let indices = match self.pending.take() {
+            Some(indices) => indices,
+            None => return Ok(()),
+        };
+        let elapsed = self.now;
+        let scale = self.scale;
+
+        match &mut self.writer {
+            Writer::Gif(writer) => {
+                // GIF delays are in hundredths of a second and most viewers clamp anything below 2
+                let now = (elapsed * 100.0) as u64;
+                let delay = now.saturating_sub(self.written).max(2);
+                self.written += delay;
+                writer.write_frame(&indices, delay.min(u64::from(u16::MAX)) as u16)
+            }
+            Writer::Y4m(writer) => {
+                // Y4M has a fixed rate so repeat the frame for as many ticks as it was shown
+                let now = (elapsed * f64::from(Y4M_FPS)).round() as u64;
+                let repeat = now.saturating_sub(self.written).max(1);
+                self.written += repeat;
+                let image = Image::from_indexed(64 * scale, 32 * scale, &indices, &self.palette);
+                for _ in 0..repeat {
+                    writer.write_frame(&image)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
@@ -0,0 +1,59 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+// 16-bit PCM WAV writer, the header sizes are patched in on finish
+pub struct WavWriter {
+    out: BufWriter<File>,
+    samples: u32,
+}
+
+impl WavWriter {
+    pub fn create(path: &Path, sample_rate: u32) -> io::Result<WavWriter> {
+        let mut out = BufWriter::new(File::create(path)?);
+        let channels: u16 = 1;
+        let bits: u16 = 16;
+        let block_align = channels * bits / 8;
+
+        out.write_all(b"RIFF")?;
+        out.write_all(&0u32.to_le_bytes())?;
+        out.write_all(b"WAVE")?;
+        out.write_all(b"fmt ")?;
+        out.write_all(&16u32.to_le_bytes())?;
+        // PCM
+        out.write_all(&1u16.to_le_bytes())?;
+        out.write_all(&channels.to_le_bytes())?;
+        out.write_all(&sample_rate.to_le_bytes())?;
+        out.write_all(&(sample_rate * u32::from(block_align)).to_le_bytes())?;
+        out.write_all(&block_align.to_le_bytes())?;
+        out.write_all(&bits.to_le_bytes())?;
+        out.write_all(b"data")?;
+        out.write_all(&0u32.to_le_bytes())?;
+
+        Ok(WavWriter { out, samples: 0 })
+    }
+
+    // Number of samples written so far
+    pub fn samples(&self) -> u32 {
+        self.samples
+    }
+
+    // Append mono samples in the range -1.0..=1.0
+    pub fn write_samples(&mut self, samples: &[f32]) -> io::Result<()> {
+        for sample in samples {
+            let value = (sample.clamp(-1.0, 1.0) * f32::from(i16::MAX)) as i16;
+            self.out.write_all(&value.to_le_bytes())?;
+        }
+        self.samples += samples.len() as u32;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> io::Result<()> {
+        let data_len = self.samples * 2;
+        self.out.seek(SeekFrom::Start(4))?;
+        self.out.write_all(&(36 + data_len).to_le_bytes())?;
+        self.out.seek(SeekFrom::Start(40))?;
+        self.out.write_all(&data_len.to_le_bytes())?;
+        self.out.flush()
+    }
+}
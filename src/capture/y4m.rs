@@ -0,0 +1,76 @@
+use super::Image;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+// Raw YUV4MPEG2 writer with 4:2:0 chroma subsampling
+pub struct Y4mWriter {
+    out: BufWriter<File>,
+    width: usize,
+    height: usize,
+}
+
+impl Y4mWriter {
+    pub fn create(path: &Path, width: u32, height: u32, fps: u32) -> io::Result<Y4mWriter> {
+        let mut out = BufWriter::new(File::create(path)?);
+        writeln!(
+            out,
+            "YUV4MPEG2 W{} H{} F{}:1 Ip A1:1 C420jpeg",
+            width, height, fps
+        )?;
+
+        Ok(Y4mWriter {
+            out,
+            width: width as usize,
+            height: height as usize,
+        })
+    }
+
+    pub fn write_frame(&mut self, image: &Image) -> io::Result<()> {
+        let (w, h) = (self.width, self.height);
+        let (cw, ch) = (w.div_ceil(2), h.div_ceil(2));
+        let mut y_plane = Vec::with_capacity(w * h);
+        let mut u_plane = vec![0u32; cw * ch];
+        let mut v_plane = vec![0u32; cw * ch];
+        let mut counts = vec![0u32; cw * ch];
+
+        for (i, rgb) in image.pixels.chunks_exact(3).enumerate() {
+            let (y, u, v) = rgb_to_yuv(rgb[0], rgb[1], rgb[2]);
+            let c = (i / w / 2) * cw + (i % w) / 2;
+            y_plane.push(y);
+            u_plane[c] += u32::from(u);
+            v_plane[c] += u32::from(v);
+            counts[c] += 1;
+        }
+
+        self.out.write_all(b"FRAME\n")?;
+        self.out.write_all(&y_plane)?;
+        for plane in [&u_plane, &v_plane] {
+            let averaged: Vec<u8> = plane
+                .iter()
+                .zip(counts.iter())
+                .map(|(sum, n)| (sum / (*n).max(1)) as u8)
+                .collect();
+            self.out.write_all(&averaged)?;
+        }
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+// Full range BT.601 conversion as used by JPEG
+fn rgb_to_yuv(r: u8, g: u8, b: u8) -> (u8, u8, u8) {
+    let (r, g, b) = (f32::from(r), f32::from(g), f32::from(b));
+    let y = 0.299 * r + 0.587 * g + 0.114 * b;
+    let u = 128.0 - 0.168_736 * r - 0.331_264 * g + 0.5 * b;
+    let v = 128.0 + 0.5 * r - 0.418_688 * g - 0.081_312 * b;
+
+    (
+        y.round().clamp(0.0, 255.0) as u8,
+        u.round().clamp(0.0, 255.0) as u8,
+        v.round().clamp(0.0, 255.0) as u8,
+    )
+}
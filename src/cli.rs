@@ -0,0 +1,504 @@
+use crate::config::{ConfigSource, MachineOptions, Settings};
+use crate::units::tu::TerminalMode;
+use std::fmt::Display;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+pub const USAGE: &str = "\
+usage: cj-8 [run] [options] ROM      (ROM can be - for standard input, or archive.zip#file)
+       cj-8 disasm [--patch FILE] [--platform NAME] [--load-address ADDR] ROM
+       cj-8 info [--patch FILE] [--platform NAME] [--load-address ADDR] ROM
+       cj-8 bench [--frames N] [machine options] ROM
+       cj-8 test [--frames N] [--expect HASH | --movie FILE] [machine options] ROM
+       cj-8 mkpatch ORIGINAL MODIFIED PATCH
+       cj-8 help | --help
+
+Commands:
+  run       Play a ROM, the default when no command is given
+  disasm    List every instruction in a ROM
+  info      Show a ROM's size, hashes, database entry and platform guess
+  bench     Run a ROM flat out without a display and report its speed
+  test      Run a ROM without a display and check the screen it ends on
+  mkpatch   Write the changes from ORIGINAL to MODIFIED as a patch, IPS if PATCH ends in .ips
+            and BPS otherwise
+
+Machine options:
+  --platform NAME     chip8, schip, xochip or eti660, picks the quirks, memory size and load
+                      address that platform had
+  --quirks LIST       Quirks on top of the platform's, e.g. clip,no-vf-reset,jump-vx=on
+  --ipf N             Instructions per 60hz frame (default 8)
+  --seed N            Seed for CXNN so runs repeat exactly
+  --load-address ADDR Where the ROM is loaded, e.g. 0x600 (default 0x200, the platform's)
+  --entry-point ADDR  Where running starts (default the load address)
+  --config FILE       Read settings from FILE instead of the user's config.ini
+  --no-config         Ignore the config file
+  --patch FILE        Apply an IPS or BPS patch to the ROM, can be given more than once
+
+Run options:
+  --scale N           Starting window scale (default 10)
+  --palette COLORS    classic, amber, green, lcd, octo or BACKGROUND,FOREGROUND in hex
+  --fullscreen        Start fullscreen
+  --integer-scale     Only scale by whole numbers
+  --keymap FILE       Keyboard, controller and hotkey bindings
+  --key-press-only    FX0A takes a key on press instead of release
+  --tone HZ           Buzzer pitch up to 20000 (default 440)
+  --waveform WAVE     square, pulse:DUTY, triangle, sine or noise
+  --volume 0-1        Buzzer volume
+  --attack MS         Buzzer fade in
+  --release MS        Buzzer fade out
+  --record FILE       Record video to a .gif or .y4m
+  --frames N          Stop recording video after N frames
+  --record-audio FILE Record the buzzer to a .wav
+  --record-movie FILE Record keypad input to a movie
+  --play-movie FILE   Play keypad input back from a movie
+  --terminal          Draw in the terminal with half blocks
+  --braille           Draw in the terminal with braille dots
+  --no-bell           Keep the terminal bell quiet
+  --headless          Run without a window or sound, needs one of the limits below,
+                      --play-movie or --record with --frames
+  --max-frames N      Quit after N frames
+  --max-cycles N      Quit after N instructions
+  --watch             Reload the ROM from a clean machine whenever its file changes
+  --watch-keep-state  Reload the ROM into memory without resetting, keeping registers and screen
+
+Settings from --platform to --integer-scale can also go in the config file, see the README.
+Flags on the command line win over the config file's section for the ROM, which wins over its
+global settings.
+
+Exit status is 0 on success, 1 when the ROM can't be run or a check fails and 2 on bad usage.
+";
+
+// What to do, parsed from the command line
+#[derive(Debug)]
+pub enum Command {
+    Run(RunOptions),
+    // The ROM, any patches to apply to it and the platform or load address it's listed for
+    Disasm(PathBuf, Vec<PathBuf>, MachineOptions),
+    Info(PathBuf, Vec<PathBuf>, MachineOptions),
+    Bench(CheckOptions),
+    Test(CheckOptions),
+    MakePatch {
+        original: PathBuf,
+        modified: PathBuf,
+        output: PathBuf,
+    },
+    Help,
+    Version,
+}
+
+#[derive(Debug)]
+pub struct RunOptions {
+    pub rom: PathBuf,
+    pub patches: Vec<PathBuf>,
+    // Settings given on the command line, the top layer over the config file
+    pub settings: Settings,
+    pub config: ConfigSource,
+    pub record: Option<PathBuf>,
+    pub record_frames: Option<u64>,
+    pub record_audio: Option<PathBuf>,
+    pub record_movie: Option<PathBuf>,
+    pub play_movie: Option<PathBuf>,
+    pub terminal: Option<TerminalMode>,
+    pub bell: bool,
+    pub headless: bool,
+    pub max_frames: Option<u64>,
+    pub max_cycles: Option<u64>,
+    // Reload the ROM when its file changes, keeping the machine's state or not
+    pub watch: bool,
+    pub watch_keep_state: bool,
+}
+
+// Options for bench and test, which run a ROM with no frontend at all
+#[derive(Debug)]
+pub struct CheckOptions {
+    pub rom: PathBuf,
+    pub patches: Vec<PathBuf>,
+    pub machine: MachineOptions,
+    pub config: ConfigSource,
+    pub frames: u64,
+    // Display hash the test must end on
+    pub expect: Option<String>,
+    // Movie supplying keypad input, the test checks its final state
+    pub movie: Option<PathBuf>,
+}
+
+// Parse the arguments after the program name
+pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Command, String> {
+    let mut args = args.into_iter().peekable();
+    let command = match args.peek().map(String::as_str) {
+        None => return Err("no ROM given".to_string()),
+        Some("help" | "--help" | "-h") => return Ok(Command::Help),
+        Some("--version" | "-V") => return Ok(Command::Version),
+        Some(command @ ("run" | "disasm" | "info" | "bench" | "test" | "mkpatch")) => {
+            let command = command.to_string();
+            args.next();
+            command
+        }
+        // Without a command the arguments are for run, as they always were
+        Some(_) => "run".to_string(),
+    };
+
+    match command.as_str() {
+        "run" => parse_run(args),
+        "disasm" => Ok(
+            rom_only(args)?.map_or(Command::Help, |(rom, patches, machine)| {
+                Command::Disasm(rom, patches, machine)
+            }),
+        ),
+        "info" => Ok(
+            rom_only(args)?.map_or(Command::Help, |(rom, patches, machine)| {
+                Command::Info(rom, patches, machine)
+            }),
+        ),
+        "bench" => Ok(parse_check(args, 3600)?.map_or(Command::Help, Command::Bench)),
+        "mkpatch" => parse_make_patch(args),
+        _ => Ok(parse_check(args, 600)?.map_or(Command::Help, Command::Test)),
+    }
+}
+
+fn parse_run(mut args: impl Iterator<Item = String>) -> Result<Command, String> {
+    let mut positional: Vec<String> = Vec::new();
+    let mut options = RunOptions {
+        rom: PathBuf::new(),
+        patches: Vec::new(),
+        settings: Settings::default(),
+        config: ConfigSource::default(),
+        record: None,
+        record_frames: None,
+        record_audio: None,
+        record_movie: None,
+        play_movie: None,
+        terminal: None,
+        bell: true,
+        headless: false,
+        max_frames: None,
+        max_cycles: None,
+        watch: false,
+        watch_keep_state: false,
+    };
+
+    while let Some(arg) = args.next() {
+        if parse_config_flag(&arg, &mut args, &mut options.config)? {
+            continue;
+        }
+        let flag = arg.as_str();
+        match flag {
+            "--help" | "-h" => return Ok(Command::Help),
+            "--watch" => options.watch = true,
+            "--watch-keep-state" => {
+                options.watch = true;
+                options.watch_keep_state = true;
+            }
+            "--fullscreen" | "--integer-scale" => options.settings.set(&flag[2..], "on")?,
+            "--key-press-only" => options.settings.set("quirks", "no-key-wait-release")?,
+            "--patch" => options.patches.push(value(flag, args.next())?.into()),
+            "--record" => options.record = Some(value(flag, args.next())?.into()),
+            "--frames" => options.record_frames = Some(parse_value(flag, args.next())?),
+            "--record-audio" => options.record_audio = Some(value(flag, args.next())?.into()),
+            "--record-movie" => options.record_movie = Some(value(flag, args.next())?.into()),
+            "--play-movie" => options.play_movie = Some(value(flag, args.next())?.into()),
+            "--terminal" => options.terminal = options.terminal.or(Some(TerminalMode::HalfBlock)),
+            "--braille" => options.terminal = Some(TerminalMode::Braille),
+            "--no-bell" => options.bell = false,
+            "--headless" => options.headless = true,
+            "--max-frames" => options.max_frames = Some(parse_value(flag, args.next())?),
+            "--max-cycles" => options.max_cycles = Some(parse_value(flag, args.next())?),
+            // Everything that can also go in the config file
+            _ if flag
+                .strip_prefix("--")
+                .is_some_and(|key| Settings::KEYS.contains(&key)) =>
+            {
+                options
+                    .settings
+                    .set(&flag[2..], &value(flag, args.next())?)?
+            }
+            _ if flag.starts_with("--") => return Err(format!("unknown option: {}", flag)),
+            _ => positional.push(arg),
+        }
+    }
+    if options.record_movie.is_some() && options.play_movie.is_some() {
+        return Err("--record-movie and --play-movie can't be used together".to_string());
+    }
+    // Nothing ends a headless run but a limit or the end of a movie
+    if options.headless
+        && options.max_frames.is_none()
+        && options.max_cycles.is_none()
+        && options.play_movie.is_none()
+        && !(options.record.is_some() && options.record_frames.is_some())
+    {
+        return Err(
+            "--headless needs --max-frames, --max-cycles, --play-movie or --record with --frames \
+             to know when to stop"
+                .to_string(),
+        );
+    }
+
+    // The scale used to come before the ROM, that still works
+    match positional.as_slice() {
+        [rom] => options.rom = rom.into(),
+        [scale, rom] if options.settings.scale.is_none() => {
+            options.settings.set("scale", scale)?;
+            options.rom = rom.into();
+        }
+        [] => return Err("no ROM given".to_string()),
+        _ => return Err(format!("unexpected argument: {}", positional[0])),
+    }
+    if options.watch && options.rom.as_os_str() == "-" {
+        return Err("--watch needs a ROM file, not standard input".to_string());
+    }
+    Ok(Command::Run(options))
+}
+
+// --config FILE and --no-config, returns false for any other flag
+fn parse_config_flag(
+    flag: &str,
+    args: &mut impl Iterator<Item = String>,
+    config: &mut ConfigSource,
+) -> Result<bool, String> {
+    match flag {
+        "--config" => *config = ConfigSource::File(value(flag, args.next())?.into()),
+        "--no-config" => *config = ConfigSource::None,
+        _ => return Ok(false),
+    }
+    Ok(true)
+}
+
+// The ROM path and its patches, None when help was asked for
+fn rom_only(
+    mut args: impl Iterator<Item = String>,
+) -> Result<Option<(PathBuf, Vec<PathBuf>, MachineOptions)>, String> {
+    let mut rom = None;
+    let mut patches = Vec::new();
+    let mut machine = MachineOptions::default();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--help" | "-h" => return Ok(None),
+            "--patch" => patches.push(value("--patch", args.next())?.into()),
+            // Only where the ROM is loaded matters to a listing
+            flag @ ("--platform" | "--load-address") => {
+                machine.set(&flag[2..], &value(flag, args.next())?)?;
+            }
+            flag if flag.starts_with("--") => return Err(format!("unknown option: {}", flag)),
+            _ if rom.is_some() => return Err(format!("unexpected argument: {}", arg)),
+            _ => rom = Some(PathBuf::from(arg)),
+        }
+    }
+    rom.map(|rom| Some((rom, patches, machine)))
+        .ok_or_else(|| "no ROM given".to_string())
+}
+
+fn parse_make_patch(args: impl Iterator<Item = String>) -> Result<Command, String> {
+    let mut paths = Vec::new();
+    for arg in args {
+        match arg.as_str() {
+            "--help" | "-h" => return Ok(Command::Help),
+            flag if flag.starts_with("--") => return Err(format!("unknown option: {}", flag)),
+            _ => paths.push(PathBuf::from(arg)),
+        }
+    }
+    match <[PathBuf; 3]>::try_from(paths) {
+        Ok([original, modified, output]) => Ok(Command::MakePatch {
+            original,
+            modified,
+            output,
+        }),
+        Err(paths) if paths.len() > 3 => {
+            Err(format!("unexpected argument: {}", paths[3].display()))
+        }
+        Err(_) => Err(
+            "mkpatch needs the original ROM, the modified ROM and the patch to write".to_string(),
+        ),
+    }
+}
+
+fn parse_check(
+    mut args: impl Iterator<Item = String>,
+    frames: u64,
+) -> Result<Option<CheckOptions>, String> {
+    let mut rom: Option<PathBuf> = None;
+    let mut options = CheckOptions {
+        rom: PathBuf::new(),
+        patches: Vec::new(),
+        machine: MachineOptions::default(),
+        config: ConfigSource::default(),
+        frames,
+        expect: None,
+        movie: None,
+    };
+
+    while let Some(arg) = args.next() {
+        if parse_config_flag(&arg, &mut args, &mut options.config)? {
+            continue;
+        }
+        let flag = arg.as_str();
+        match flag {
+            "--help" | "-h" => return Ok(None),
+            "--platform" | "--quirks" | "--ipf" | "--seed" | "--load-address" | "--entry-point" => {
+                options
+                    .machine
+                    .set(&flag[2..], &value(flag, args.next())?)?;
+            }
+            "--frames" => options.frames = parse_value(flag, args.next())?,
+            "--patch" => options.patches.push(value(flag, args.next())?.into()),
+            "--expect" => options.expect = Some(value(flag, args.next())?.to_ascii_lowercase()),
+            "--movie" => options.movie = Some(value(flag, args.next())?.into()),
+            _ if flag.starts_with("--") => return Err(format!("unknown option: {}", flag)),
+            _ if rom.is_some() => return Err(format!("unexpected argument: {}", arg)),
+            _ => rom = Some(PathBuf::from(arg)),
+        }
+    }
+    options.rom = rom.ok_or_else(|| "no ROM given".to_string())?;
+    Ok(Some(options))
+}
+
+fn value(flag: &str, value: Option<String>) -> Result<String, String> {
+    value.ok_or_else(|| format!("{} needs a value", flag))
+}
+
+// Parse the value following a flag
+fn parse_value<T: FromStr>(flag: &str, value: Option<String>) -> Result<T, String>
+where
+    T::Err: Display,
+{
+    let value = self::value(flag, value)?;
+    value
+        .parse()
+        .map_err(|e| format!("invalid value for {}: {}: {}", flag, value, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::variant::Variant;
+
+    fn parse_line(line: &str) -> Result<Command, String> {
+        parse(line.split_whitespace().map(str::to_string))
+    }
+
+    fn run(line: &str) -> RunOptions {
+        match parse_line(line) {
+            Ok(Command::Run(options)) => options,
+            other => panic!("{}: {:?}", line, other),
+        }
+    }
+
+    fn check(line: &str) -> CheckOptions {
+        match parse_line(line) {
+            Ok(Command::Bench(options) | Command::Test(options)) => options,
+            other => panic!("{}: {:?}", line, other),
+        }
+    }
+
+    #[test]
+    fn commands_are_picked_by_the_first_argument() {
+        for (line, expected) in [
+            ("help", "Help"),
+            ("--help", "Help"),
+            ("-h", "Help"),
+            ("--version", "Version"),
+            ("pong.ch8", "Run"),
+            ("run pong.ch8", "Run"),
+            ("run --help", "Help"),
+            ("disasm pong.ch8", "Disasm"),
+            ("info pong.ch8", "Info"),
+            ("bench pong.ch8", "Bench"),
+            ("test pong.ch8", "Test"),
+            ("mkpatch a.ch8 b.ch8 fix.bps", "MakePatch"),
+        ] {
+            let command = format!("{:?}", parse_line(line).unwrap());
+            assert!(command.starts_with(expected), "{}: {}", line, command);
+        }
+    }
+
+    #[test]
+    fn scale_can_still_come_before_the_rom() {
+        let options = run("20 pong.ch8");
+        assert_eq!(options.settings.scale, Some(20));
+        assert_eq!(options.rom, PathBuf::from("pong.ch8"));
+        assert_eq!(run("--scale 4 pong.ch8").settings.scale, Some(4));
+        assert_eq!(
+            parse_line("--scale 4 20 pong.ch8").unwrap_err(),
+            "unexpected argument: 20"
+        );
+        assert_eq!(parse_line("0 pong.ch8").unwrap_err(), "invalid scale: 0");
+    }
+
+    #[test]
+    fn options_are_parsed_for_each_command() {
+        let options = run("--platform schip --ipf 15 --key-press-only --patch fix.ips pong.ch8");
+        assert_eq!(options.settings.machine.platform, Some(Variant::Schip));
+        assert_eq!(options.settings.machine.ipf, Some(15));
+        assert_eq!(options.settings.machine.quirks, ["no-key-wait-release"]);
+        assert_eq!(options.patches, [PathBuf::from("fix.ips")]);
+
+        match parse_line("disasm --patch fix.ips --load-address 0x600 game.ch8").unwrap() {
+            Command::Disasm(rom, patches, machine) => {
+                assert_eq!(rom, PathBuf::from("game.ch8"));
+                assert_eq!(patches, [PathBuf::from("fix.ips")]);
+                assert_eq!(machine.load_address, Some(0x600));
+            }
+            other => panic!("{:?}", other),
+        }
+
+        assert_eq!(check("bench pong.ch8").frames, 3600);
+        let options = check("test --frames 10 --expect ABC --no-config pong.ch8");
+        assert_eq!(options.frames, 10);
+        assert_eq!(options.expect.as_deref(), Some("abc"));
+        assert!(matches!(options.config, ConfigSource::None));
+    }
+
+    #[test]
+    fn bad_usage_is_an_error() {
+        for (line, error) in [
+            ("", "no ROM given"),
+            ("run", "no ROM given"),
+            ("info", "no ROM given"),
+            ("--bogus pong.ch8", "unknown option: --bogus"),
+            ("info --ipf 4 pong.ch8", "unknown option: --ipf"),
+            ("pong.ch8 --ipf", "--ipf needs a value"),
+            ("test pong.ch8 --expect", "--expect needs a value"),
+            ("--ipf 0 pong.ch8", "ipf must be a whole number above 0: 0"),
+            (
+                "--platform vic20 pong.ch8",
+                "invalid value for platform: vic20",
+            ),
+            (
+                "--max-frames many pong.ch8",
+                "invalid value for --max-frames: many",
+            ),
+            ("a.ch8 b.ch8 c.ch8", "unexpected argument: a.ch8"),
+            ("disasm a.ch8 b.ch8", "unexpected argument: b.ch8"),
+            ("mkpatch a.ch8 b.ch8", "mkpatch needs the original ROM"),
+            ("mkpatch a b c d", "unexpected argument: d"),
+            ("--watch -", "--watch needs a ROM file"),
+            (
+                "--record-movie a.movie --play-movie b.movie pong.ch8",
+                "--record-movie and --play-movie can't be used together",
+            ),
+        ] {
+            let result = parse_line(line);
+            assert!(
+                result.as_ref().is_err_and(|e| e.starts_with(error)),
+                "{}: {:?}",
+                line,
+                result
+            );
+        }
+    }
+
+    #[test]
+    fn headless_runs_need_something_to_stop_them() {
+        for line in ["--headless pong.ch8", "--headless --record a.gif pong.ch8"] {
+            let error = parse_line(line).unwrap_err();
+            assert!(error.starts_with("--headless needs"), "{}", line);
+        }
+        for line in [
+            "--headless --max-frames 60 pong.ch8",
+            "--headless --max-cycles 1000 pong.ch8",
+            "--headless --play-movie a.movie pong.ch8",
+            "--headless --record a.gif --frames 60 pong.ch8",
+        ] {
+            assert!(run(line).headless, "{}", line);
+        }
+    }
+}
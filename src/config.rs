@@ -0,0 +1,447 @@
+use crate::palette::Palette;
+use crate::quirks::Quirks;
+use crate::system::System;
+use crate::units::au::{Tone, Waveform};
+use crate::variant::Variant;
+use std::collections::HashMap;
+use std::env;
+use std::fmt::Display;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+// Highest buzzer pitch, below the 22050 Hz a 44.1 kHz device can play
+const MAX_TONE: f32 = 20_000.0;
+
+// Options changing how the machine itself behaves, shared by every command that runs a ROM
+#[derive(Clone, Debug, Default)]
+pub struct MachineOptions {
+    pub platform: Option<Variant>,
+    // Quirk lists in the order given, applied on top of the platform's
+    pub quirks: Vec<String>,
+    pub ipf: Option<u32>,
+    pub seed: Option<u64>,
+    // Where the ROM goes and where it starts running, the platform's load address unless set
+    pub load_address: Option<usize>,
+    pub entry_point: Option<usize>,
+}
+
+impl MachineOptions {
+    // Set one option by its name, returns false for names this doesn't know
+    pub fn set(&mut self, key: &str, value: &str) -> Result<bool, String> {
+        match key {
+            "platform" => self.platform = Some(parse(key, value)?),
+            "quirks" => {
+                // Catch typos now, the list is applied for real once the platform is known
+                Quirks::default().apply(value)?;
+                self.quirks.push(value.to_string());
+            }
+            "ipf" => match value.parse() {
+                Ok(ipf) if ipf > 0 => self.ipf = Some(ipf),
+                _ => return Err(format!("ipf must be a whole number above 0: {}", value)),
+            },
+            "seed" => self.seed = Some(parse(key, value)?),
+            "load-address" => self.load_address = Some(parse_address(key, value)?),
+            "entry-point" => self.entry_point = Some(parse_address(key, value)?),
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    // Layer other on top, its values win and its quirk lists go after ours
+    pub fn merge(&mut self, other: &MachineOptions) {
+        self.platform = other.platform.or(self.platform);
+        self.quirks.extend(other.quirks.iter().cloned());
+        self.ipf = other.ipf.or(self.ipf);
+        self.seed = other.seed.or(self.seed);
+        self.load_address = other.load_address.or(self.load_address);
+        self.entry_point = other.entry_point.or(self.entry_point);
+    }
+
+    // The platform's quirks with the quirk lists applied in order
+    pub fn quirks(&self) -> Result<Quirks, String> {
+        let mut quirks = self.platform.map(Variant::quirks).unwrap_or_default();
+        for spec in self.quirks.iter() {
+            quirks.apply(spec)?;
+        }
+        Ok(quirks)
+    }
+
+    // Where the ROM goes, the platform's load address unless one was given
+    pub fn load_address(&self) -> usize {
+        self.load_address
+            .unwrap_or(self.platform.unwrap_or(Variant::Chip8).load_address())
+    }
+
+    // Where the program starts running, the load address unless one was given
+    pub fn entry_point(&self) -> usize {
+        self.entry_point.unwrap_or_else(|| self.load_address())
+    }
+
+    pub fn configure(&self, system: &mut System) -> Result<(), String> {
+        system.quirks = self.quirks()?;
+        let platform = self.platform.unwrap_or(Variant::Chip8);
+        system.set_memory_size(platform.memory_size());
+        system.set_load_address(self.load_address(), self.entry_point())?;
+        if let Some(ipf) = self.ipf {
+            system.ipf = ipf;
+        }
+        if let Some(seed) = self.seed {
+            system.set_seed(seed);
+        }
+        Ok(())
+    }
+}
+
+// Settings from one layer: the config file, a ROM's section in it or the command line.
+// Anything left as None falls through to the layer below
+#[derive(Clone, Debug, Default)]
+pub struct Settings {
+    pub machine: MachineOptions,
+    pub scale: Option<u32>,
+    pub palette: Option<Palette>,
+    pub keymap: Option<PathBuf>,
+    pub tone: Option<f32>,
+    pub waveform: Option<Waveform>,
+    pub volume: Option<f32>,
+    // Milliseconds
+    pub attack: Option<f32>,
+    pub release: Option<f32>,
+    pub fullscreen: Option<bool>,
+    pub integer_scale: Option<bool>,
+}
+
+impl Settings {
+    // Names shared by config keys and command line flags, the flags being these with -- in front
+    pub const KEYS: [&'static str; 16] = [
+        "platform",
+        "quirks",
+        "ipf",
+        "seed",
+        "load-address",
+        "entry-point",
+        "scale",
+        "palette",
+        "keymap",
+        "tone",
+        "waveform",
+        "volume",
+        "attack",
+        "release",
+        "fullscreen",
+        "integer-scale",
+    ];
+
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        if self.machine.set(key, value)? {
+            return Ok(());
+        }
+        match key {
+            "scale" => match value.parse() {
+                Ok(scale) if scale > 0 => self.scale = Some(scale),
+                _ => return Err(format!("invalid scale: {}", value)),
+            },
+            "palette" => self.palette = Some(parse(key, value)?),
+            "keymap" => self.keymap = Some(PathBuf::from(value)),
+            "tone" => match value.parse::<f32>() {
+                Ok(tone) if tone > 0.0 && tone <= MAX_TONE => self.tone = Some(tone),
+                _ => {
+                    return Err(format!(
+                        "tone must be a pitch above 0 and up to {} Hz: {}",
+                        MAX_TONE, value
+                    ))
+                }
+            },
+            "waveform" => self.waveform = Some(parse(key, value)?),
+            "volume" => self.volume = Some(parse::<f32>(key, value)?.clamp(0.0, 1.0)),
+            "attack" => self.attack = Some(parse(key, value)?),
+            "release" => self.release = Some(parse(key, value)?),
+            "fullscreen" => self.fullscreen = Some(parse_bool(key, value)?),
+            "integer-scale" => self.integer_scale = Some(parse_bool(key, value)?),
+            _ => return Err(format!("unknown setting: {}", key)),
+        }
+        Ok(())
+    }
+
+    // Layer other on top, anything it sets wins
+    pub fn merge(&mut self, other: &Settings) {
+        self.machine.merge(&other.machine);
+        self.scale = other.scale.or(self.scale);
+        self.palette = other.palette.or(self.palette);
+        self.keymap = other.keymap.clone().or(self.keymap.take());
+        self.tone = other.tone.or(self.tone);
+        self.waveform = other.waveform.or(self.waveform);
+        self.volume = other.volume.or(self.volume);
+        self.attack = other.attack.or(self.attack);
+        self.release = other.release.or(self.release);
+        self.fullscreen = other.fullscreen.or(self.fullscreen);
+        self.integer_scale = other.integer_scale.or(self.integer_scale);
+    }
+
+    // The buzzer with anything set here in place of the defaults
+    pub fn tone(&self) -> Tone {
+        let mut tone = Tone::default();
+        if let Some(frequency) = self.tone {
+            tone.frequency = frequency;
+        }
+        if let Some(waveform) = self.waveform {
+            tone.waveform = waveform;
+        }
+        if let Some(volume) = self.volume {
+            tone.volume = volume;
+        }
+        if let Some(attack) = self.attack {
+            tone.attack = attack / 1000.0;
+        }
+        if let Some(release) = self.release {
+            tone.release = release / 1000.0;
+        }
+        tone
+    }
+}
+
+// Which config file to read
+#[derive(Clone, Debug, Default)]
+pub enum ConfigSource {
+    // The user's config.ini, if there is one
+    #[default]
+    Default,
+    File(PathBuf),
+    None,
+}
+
+impl ConfigSource {
+    pub fn load(&self) -> Result<Config, String> {
+        let path = match self {
+            ConfigSource::Default => match Config::default_path() {
+                Some(path) if path.is_file() => path,
+                _ => return Ok(Config::default()),
+            },
+            ConfigSource::File(path) => path.clone(),
+            ConfigSource::None => return Ok(Config::default()),
+        };
+        Config::load(&path).map_err(|e| format!("Failed to load config: {}", e))
+    }
+}
+
+// Global defaults plus sections for single ROMs, keyed by the ROM's SHA-1
+#[derive(Debug, Default)]
+pub struct Config {
+    pub global: Settings,
+    pub roms: HashMap<String, Settings>,
+}
+
+impl Config {
+    // cj-8/config.ini in the user's config directory
+    pub fn default_path() -> Option<PathBuf> {
+        let dir = if cfg!(windows) {
+            env::var_os("APPDATA").map(PathBuf::from)
+        } else if cfg!(target_os = "macos") {
+            env::var_os("HOME").map(|home| Path::new(&home).join("Library/Application Support"))
+        } else {
+            env::var_os("XDG_CONFIG_HOME")
+                .map(PathBuf::from)
+                .filter(|dir| dir.is_absolute())
+                .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".config")))
+        };
+        dir.map(|dir| dir.join("cj-8").join("config.ini"))
+    }
+
+    // Keymap paths in the file are taken relative to the file
+    pub fn load(path: &Path) -> io::Result<Config> {
+        let text = fs::read_to_string(path)?;
+        let mut config = Config::parse(&text).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}: {}", path.display(), e),
+            )
+        })?;
+        if let Some(dir) = path.parent() {
+            for settings in std::iter::once(&mut config.global).chain(config.roms.values_mut()) {
+                settings.keymap = settings.keymap.take().map(|keymap| dir.join(keymap));
+            }
+        }
+        Ok(config)
+    }
+
+    // INI style: key = value lines, # or ; comments, and a [SHA-1] header starting each ROM's section
+    pub fn parse(text: &str) -> Result<Config, String> {
+        let mut config = Config::default();
+        let mut section: Option<String> = None;
+
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+                continue;
+            }
+            let error = |e: String| format!("line {}: {}", number + 1, e);
+
+            if let Some(header) = line.strip_prefix('[') {
+                let hash = header
+                    .strip_suffix(']')
+                    .map(|hash| hash.trim().to_ascii_lowercase())
+                    .filter(|hash| hash.len() == 40 && hash.chars().all(|c| c.is_ascii_hexdigit()))
+                    .ok_or_else(|| error(format!("section must be a ROM's SHA-1: {}", line)))?;
+                config.roms.entry(hash.clone()).or_default();
+                section = Some(hash);
+                continue;
+            }
+
+            let (key, value) = line
+                .split_once('=')
+                .ok_or_else(|| error(format!("expected key = value: {}", line)))?;
+            let settings = match &section {
+                Some(hash) => config.roms.get_mut(hash).unwrap(),
+                None => &mut config.global,
+            };
+            settings
+                .set(&key.trim().to_ascii_lowercase(), value.trim())
+                .map_err(error)?;
+        }
+        Ok(config)
+    }
+
+    // Global defaults with the ROM's own section on top
+    pub fn settings(&self, rom_hash: &str) -> Settings {
+        let mut settings = self.global.clone();
+        if let Some(rom) = self.roms.get(rom_hash) {
+            settings.merge(rom);
+        }
+        settings
+    }
+}
+
+fn parse<T: FromStr>(key: &str, value: &str) -> Result<T, String>
+where
+    T::Err: Display,
+{
+    value
+        .parse()
+        .map_err(|e| format!("invalid value for {}: {}: {}", key, value, e))
+}
+
+// An address in hex with a 0x in front, or in decimal
+fn parse_address(key: &str, value: &str) -> Result<usize, String> {
+    let parsed = match value
+        .strip_prefix("0x")
+        .or_else(|| value.strip_prefix("0X"))
+    {
+        Some(hex) => usize::from_str_radix(hex, 16).ok(),
+        None => value.parse().ok(),
+    };
+    parsed.ok_or_else(|| format!("invalid address for {}: {}", key, value))
+}
+
+fn parse_bool(key: &str, value: &str) -> Result<bool, String> {
+    match value.to_ascii_lowercase().as_str() {
+        "on" | "true" | "yes" | "1" => Ok(true),
+        "off" | "false" | "no" | "0" => Ok(false),
+        _ => Err(format!("invalid value for {}: {}", key, value)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HASH: &str = "0123456789abcdef0123456789abcdef01234567";
+
+    #[test]
+    fn parse_reads_globals_and_rom_sections() {
+        let text = format!(
+            "# comment\n; another\n\nplatform = schip\nIPF=15\nquirks = clip\nscale = 4\n\
+             [{}]\nipf = 30\nquirks = no-clip, vf-reset\nload-address = 0x300\nentry-point = 800\n",
+            HASH.to_ascii_uppercase()
+        );
+        let config = Config::parse(&text).unwrap();
+        assert_eq!(config.global.machine.platform, Some(Variant::Schip));
+        assert_eq!(config.global.machine.ipf, Some(15));
+        assert_eq!(config.global.scale, Some(4));
+
+        // Section names are matched in lower case
+        let rom = &config.roms[HASH];
+        assert_eq!(rom.machine.ipf, Some(30));
+        assert_eq!(rom.machine.load_address, Some(0x300));
+        assert_eq!(rom.machine.entry_point, Some(800));
+        assert_eq!(rom.scale, None);
+    }
+
+    #[test]
+    fn rom_section_wins_over_globals() {
+        let text = format!(
+            "platform = schip\nipf = 15\nscale = 4\nquirks = clip,vf-reset\n\
+             [{}]\nipf = 30\nquirks = no-clip\n",
+            HASH
+        );
+        let config = Config::parse(&text).unwrap();
+
+        let settings = config.settings(HASH);
+        assert_eq!(settings.machine.platform, Some(Variant::Schip));
+        assert_eq!(settings.machine.ipf, Some(30));
+        assert_eq!(settings.scale, Some(4));
+        // Quirk lists stack, the section's going last
+        assert_eq!(settings.machine.quirks, ["clip,vf-reset", "no-clip"]);
+        let quirks = settings.machine.quirks().unwrap();
+        assert!(!quirks.clip && quirks.vf_reset);
+
+        // Other ROMs only see the globals
+        let other = config.settings("ffffffffffffffffffffffffffffffffffffffff");
+        assert_eq!(other.machine.ipf, Some(15));
+        assert_eq!(other.machine.quirks, ["clip,vf-reset"]);
+    }
+
+    #[test]
+    fn merge_keeps_what_the_top_layer_leaves_unset() {
+        let mut below = Settings::default();
+        below.set("ipf", "20").unwrap();
+        below.set("palette", "amber").unwrap();
+        below.set("fullscreen", "on").unwrap();
+        let mut above = Settings::default();
+        above.set("ipf", "9").unwrap();
+        above.set("fullscreen", "no").unwrap();
+
+        below.merge(&above);
+        assert_eq!(below.machine.ipf, Some(9));
+        assert_eq!(below.fullscreen, Some(false));
+        assert_eq!(below.palette, Some("amber".parse().unwrap()));
+    }
+
+    #[test]
+    fn parse_reports_the_line() {
+        let error = |text: &str| Config::parse(text).unwrap_err();
+        assert!(error("ipf = 8\nscale\n").starts_with("line 2: expected key = value"));
+        assert!(error("[not a hash]\n").starts_with("line 1: section must be a ROM's SHA-1"));
+        assert!(error("\nipf = 0\n").starts_with("line 2: ipf must be"));
+        assert!(error("colour = red\n").starts_with("line 1: unknown setting"));
+        assert!(error("load-address = 0xZZ\n").starts_with("line 1: invalid address"));
+        assert!(error("quirks = warp\n").starts_with("line 1: unknown quirk"));
+        for tone in ["0", "-440", "NaN", "inf", "25000", "high"] {
+            let text = format!("ipf = 8\ntone = {}\n", tone);
+            assert!(error(&text).starts_with("line 2: tone must be"), "{}", tone);
+        }
+        let config = Config::parse("tone = 880.5\n").unwrap();
+        assert_eq!(config.global.tone().frequency, 880.5);
+    }
+
+    #[test]
+    fn keymap_paths_are_relative_to_the_file() {
+        let dir = env::temp_dir().join(format!("cj-8-test-config-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("config.ini");
+        fs::write(
+            &path,
+            format!("keymap = keys.ini\n[{}]\nkeymap = /abs/rom.ini\n", HASH),
+        )
+        .unwrap();
+        let config = Config::load(&path);
+        let _ = fs::remove_dir_all(&dir);
+
+        let config = config.unwrap();
+        assert_eq!(config.global.keymap, Some(dir.join("keys.ini")));
+        assert_eq!(
+            config.roms[HASH].keymap,
+            Some(PathBuf::from("/abs/rom.ini"))
+        );
+    }
+}
@@ -0,0 +1,424 @@
+use crate::config::{Config, Settings};
+use crate::json::{self, Value};
+use crate::palette::{self, Palette};
+use crate::quirks::Quirks;
+use crate::variant::Variant;
+use std::fs;
+use std::path::PathBuf;
+
+// A copy of the community CHIP-8 database (github.com/chip-8/chip-8-database), same files and schema
+const PROGRAMS: &str = include_str!("../database/programs.json");
+const HASHES: &str = include_str!("../database/sha1-hashes.json");
+
+// What the database knows about one ROM
+#[derive(Clone, Debug, Default)]
+pub struct RomInfo {
+    pub title: String,
+    pub authors: Vec<String>,
+    pub release: Option<String>,
+    pub description: Option<String>,
+    // Platform id as the database names it, e.g. "superchip"
+    pub platform_id: Option<String>,
+    pub platform: Option<Variant>,
+    // How the ROM needs the quirks set, None if it doesn't run on any platform CJ-8 knows
+    pub quirks: Option<Quirks>,
+    // Instructions per frame
+    pub tickrate: Option<u32>,
+    pub palette: Option<Palette>,
+    // Chip-8 keys for the directions and buttons, by the database's names (up, down, left, right, a, b)
+    pub keys: Vec<(String, u8)>,
+}
+
+impl RomInfo {
+    // The ROM's settings as the lowest layer under the config file and command line
+    pub fn settings(&self) -> Settings {
+        let mut settings = Settings::default();
+        settings.machine.platform = self.platform;
+        if let (Some(platform), Some(quirks)) = (self.platform, self.quirks) {
+            // Only where the ROM differs from its platform, so a platform chosen by the user still counts
+            let preset = platform.quirks().flags();
+            let changes: Vec<String> = quirks
+                .flags()
+                .iter()
+                .zip(preset.iter())
+                .filter(|(wanted, preset)| wanted.1 != preset.1)
+                .map(|((name, on), _)| format!("{}={}", name, if *on { "on" } else { "off" }))
+                .collect();
+            if !changes.is_empty() {
+                settings.machine.quirks.push(changes.join(","));
+            }
+        }
+        settings.machine.ipf = self.tickrate.filter(|&tickrate| tickrate > 0);
+        settings.palette = self.palette;
+        settings
+    }
+}
+
+impl std::fmt::Display for RomInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.title)?;
+        if !self.authors.is_empty() {
+            write!(f, " by {}", self.authors.join(", "))?;
+        }
+        if let Some(release) = &self.release {
+            write!(f, " ({})", release)?;
+        }
+        Ok(())
+    }
+}
+
+pub struct Database {
+    programs: Value,
+    hashes: Value,
+}
+
+impl Database {
+    // The copy built into CJ-8
+    pub fn embedded() -> Database {
+        Database::parse(PROGRAMS, HASHES).expect("ERROR: Built in ROM database is invalid")
+    }
+
+    // A newer copy in the database folder next to config.ini wins over the built in one
+    pub fn load() -> Database {
+        if let Some(dir) = Database::user_dir() {
+            let programs = fs::read_to_string(dir.join("programs.json"));
+            let hashes = fs::read_to_string(dir.join("sha1-hashes.json"));
+            if let (Ok(programs), Ok(hashes)) = (programs, hashes) {
+                match Database::parse(&programs, &hashes) {
+                    Ok(database) => return database,
+                    Err(e) => eprintln!(
+                        "ERROR: Failed to load ROM database from {}: {}",
+                        dir.display(),
+                        e
+                    ),
+                }
+            }
+        }
+        Database::embedded()
+    }
+
+    pub fn user_dir() -> Option<PathBuf> {
+        Config::default_path().and_then(|path| path.parent().map(|dir| dir.join("database")))
+    }
+
+    // programs.json is an array of programs, sha1-hashes.json maps each ROM's SHA-1 to its program's index
+    pub fn parse(programs: &str, hashes: &str) -> Result<Database, String> {
+        let programs = json::parse(programs).map_err(|e| format!("programs.json: {}", e))?;
+        let hashes = json::parse(hashes).map_err(|e| format!("sha1-hashes.json: {}", e))?;
+        if programs.as_array().is_none() || hashes.as_object().is_none() {
+            return Err("unexpected layout".to_string());
+        }
+        Ok(Database { programs, hashes })
+    }
+
+    pub fn lookup(&self, sha1: &str) -> Option<RomInfo> {
+        let sha1 = sha1.to_ascii_lowercase();
+        let index = self.hashes.get(&sha1)?.as_f64()? as usize;
+        let program = self.programs.as_array()?.get(index)?;
+        let rom = program.get("roms")?.get(&sha1);
+
+        let text = |value: Option<&Value>| value.and_then(Value::as_str).map(str::to_string);
+        let mut info = RomInfo {
+            title: text(program.get("title")).unwrap_or_else(|| sha1.clone()),
+            authors: program
+                .get("authors")
+                .and_then(Value::as_array)
+                .unwrap_or_default()
+                .iter()
+                .filter_map(|author| text(Some(author)))
+                .collect(),
+            release: text(program.get("release")),
+            description: text(program.get("description")),
+            ..RomInfo::default()
+        };
+        let rom = match rom {
+            Some(rom) => rom,
+            None => return Some(info),
+        };
+
+        // The first platform listed that CJ-8 can emulate, with any quirks the ROM needs there
+        let platforms = rom
+            .get("platforms")
+            .and_then(Value::as_array)
+            .unwrap_or_default();
+        if let Some((id, platform, mut quirks)) = platforms
+            .iter()
+            .filter_map(Value::as_str)
+            .find_map(|id| platform(id).map(|(platform, quirks)| (id, platform, quirks)))
+        {
+            if let Some(overrides) = rom.get("quirkyPlatforms").and_then(|quirky| quirky.get(id)) {
+                apply_quirks(&mut quirks, overrides);
+            }
+            info.platform_id = Some(id.to_string());
+            info.platform = Some(platform);
+            info.quirks = Some(quirks);
+        }
+
+        info.tickrate = rom
+            .get("tickrate")
+            .and_then(Value::as_f64)
+            .map(|tickrate| tickrate as u32);
+        info.palette = rom
+            .get("colors")
+            .and_then(|colors| colors.get("pixels"))
+            .and_then(Value::as_array)
+            .and_then(|pixels| match pixels {
+                [background, foreground, ..] => Some(Palette {
+                    background: palette::parse_color(background.as_str()?).ok()?,
+                    foreground: palette::parse_color(foreground.as_str()?).ok()?,
+                }),
+                _ => None,
+            });
+        if let Some(keys) = rom.get("keys").and_then(Value::as_object) {
+            info.keys = keys
+                .iter()
+                .filter_map(|(name, key)| Some((name.clone(), key.as_f64()? as u8 & 0xF)))
+                .collect();
+        }
+        Some(info)
+    }
+}
+
+// The database's platform ids, with the closest CJ-8 platform and the quirks the real one had
+fn platform(id: &str) -> Option<(Variant, Quirks)> {
+    let (variant, quirks) = match id {
+        "originalChip8" | "hybridVIP" => (Variant::Chip8, Variant::Chip8.quirks()),
+        // Later CHIP-8 interpreters without the VIP's VF reset
+        "modernChip8" => (
+            Variant::Chip8,
+            Quirks {
+                vf_reset: false,
+                ..Variant::Chip8.quirks()
+            },
+        ),
+        "chip48" | "superchip1" | "superchip" => (Variant::Schip, Variant::Schip.quirks()),
+        "xochip" => (Variant::XoChip, Variant::XoChip.quirks()),
+        // CHIP-8X's colour and MegaChip's 256x192 display aren't emulated
+        _ => return None,
+    };
+    Some((variant, quirks))
+}
+
+// The database's quirk names mostly have the opposite sense to ours, vblank isn't emulated
+fn apply_quirks(quirks: &mut Quirks, overrides: &Value) {
+    for (name, value) in overrides.as_object().unwrap_or_default() {
+        let on = match value.as_bool() {
+            Some(on) => on,
+            None => continue,
+        };
+        match name.as_str() {
+            "shift" => quirks.shift_vy = !on,
+            "memoryLeaveIUnchanged" => quirks.memory_increment = !on,
+            // I goes up by X rather than X + 1, closest is still moving it
+            "memoryIncrementByX" if on => quirks.memory_increment = true,
+            "wrap" => quirks.clip = !on,
+            "jump" => quirks.jump_vx = on,
+            "logic" => quirks.vf_reset = on,
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PONG: &str = "0123456789abcdef0123456789abcdef01234567";
+    const PONG_SCHIP: &str = "89abcdef0123456789abcdef0123456789abcdef";
+    const MEGA: &str = "fedcba9876543210fedcba9876543210fedcba98";
+    const UNLISTED: &str = "00000000000000000000000000000000000000ff";
+
+    fn database() -> Database {
+        let programs = format!(
+            r##"[
+                {{
+                    "title": "Pong",
+                    "authors": ["Paul Vervalin", "Someone Else"],
+                    "release": "1990",
+                    "description": "Bats and a ball",
+                    "roms": {{
+                        "{pong}": {{
+                            "platforms": ["originalChip8"],
+                            "tickrate": 15,
+                            "colors": {{ "pixels": ["#102030", "#F0E0D0"] }},
+                            "keys": {{ "up": 1, "down": 4, "a": 26 }}
+                        }},
+                        "{schip}": {{
+                            "platforms": ["megachip8", "superchip", "xochip"],
+                            "quirkyPlatforms": {{ "superchip": {{ "shift": false, "jump": false }} }}
+                        }}
+                    }}
+                }},
+                {{
+                    "title": "Mega",
+                    "roms": {{ "{mega}": {{ "platforms": ["megachip8", "chip8x"] }} }}
+                }}
+            ]"##,
+            pong = PONG,
+            schip = PONG_SCHIP,
+            mega = MEGA,
+        );
+        let hashes = format!(
+            r#"{{ "{}": 0, "{}": 0, "{}": 1, "{}": 1 }}"#,
+            PONG, PONG_SCHIP, MEGA, UNLISTED
+        );
+        Database::parse(&programs, &hashes).unwrap()
+    }
+
+    fn quirks_after(platform_id: &str, overrides: &str) -> Quirks {
+        let (_, mut quirks) = platform(platform_id).unwrap();
+        apply_quirks(&mut quirks, &json::parse(overrides).unwrap());
+        quirks
+    }
+
+    #[test]
+    fn the_embedded_copy_parses() {
+        Database::embedded();
+    }
+
+    #[test]
+    fn parse_rejects_other_layouts() {
+        assert_eq!(
+            Database::parse("{}", "{}").err().unwrap(),
+            "unexpected layout"
+        );
+        assert_eq!(
+            Database::parse("[]", "[]").err().unwrap(),
+            "unexpected layout"
+        );
+        assert!(Database::parse("[", "{}")
+            .err()
+            .unwrap()
+            .starts_with("programs.json: "));
+        assert!(Database::parse("[]", "{")
+            .err()
+            .unwrap()
+            .starts_with("sha1-hashes.json: "));
+    }
+
+    #[test]
+    fn lookup_reads_the_program_and_rom() {
+        let info = database().lookup(&PONG.to_ascii_uppercase()).unwrap();
+        assert_eq!(info.title, "Pong");
+        assert_eq!(info.authors, ["Paul Vervalin", "Someone Else"]);
+        assert_eq!(info.release.as_deref(), Some("1990"));
+        assert_eq!(info.description.as_deref(), Some("Bats and a ball"));
+        assert_eq!(info.platform_id.as_deref(), Some("originalChip8"));
+        assert_eq!(info.platform, Some(Variant::Chip8));
+        assert_eq!(info.quirks, Some(Variant::Chip8.quirks()));
+        assert_eq!(info.tickrate, Some(15));
+        let palette = info.palette.unwrap();
+        assert_eq!(palette.background, [0x10, 0x20, 0x30]);
+        assert_eq!(palette.foreground, [0xF0, 0xE0, 0xD0]);
+        let mut keys = info.keys.clone();
+        keys.sort();
+        let expected = [("a", 0xA), ("down", 4), ("up", 1)];
+        let expected: Vec<(String, u8)> = expected
+            .iter()
+            .map(|&(name, key)| (name.to_string(), key))
+            .collect();
+        assert_eq!(keys, expected);
+        assert_eq!(
+            info.to_string(),
+            "Pong by Paul Vervalin, Someone Else (1990)"
+        );
+    }
+
+    #[test]
+    fn lookup_takes_the_first_platform_cj8_runs_with_its_quirks() {
+        let info = database().lookup(PONG_SCHIP).unwrap();
+        assert_eq!(info.platform_id.as_deref(), Some("superchip"));
+        assert_eq!(info.platform, Some(Variant::Schip));
+        let expected = Quirks {
+            shift_vy: true,
+            jump_vx: false,
+            ..Variant::Schip.quirks()
+        };
+        assert_eq!(info.quirks, Some(expected));
+        assert_eq!(info.tickrate, None);
+        assert!(info.palette.is_none());
+
+        // Only the quirks that differ from the platform are kept as settings
+        let settings = info.settings();
+        assert_eq!(settings.machine.platform, Some(Variant::Schip));
+        assert_eq!(settings.machine.quirks, ["shift-vy=on,jump-vx=off"]);
+    }
+
+    #[test]
+    fn lookup_without_a_platform_or_rom_entry() {
+        let database = database();
+        let mega = database.lookup(MEGA).unwrap();
+        assert_eq!(mega.title, "Mega");
+        assert_eq!(
+            (mega.platform_id, mega.platform, mega.quirks),
+            (None, None, None)
+        );
+        let unlisted = database.lookup(UNLISTED).unwrap();
+        assert_eq!(unlisted.title, "Mega");
+        assert_eq!(unlisted.platform, None);
+        assert!(database
+            .lookup("ffffffffffffffffffffffffffffffffffffffff")
+            .is_none());
+    }
+
+    #[test]
+    fn platform_ids_map_to_the_closest_platform() {
+        for id in &["originalChip8", "hybridVIP"] {
+            assert_eq!(
+                platform(id),
+                Some((Variant::Chip8, Variant::Chip8.quirks()))
+            );
+        }
+        let modern = Quirks {
+            vf_reset: false,
+            ..Variant::Chip8.quirks()
+        };
+        assert_eq!(platform("modernChip8"), Some((Variant::Chip8, modern)));
+        for id in &["chip48", "superchip1", "superchip"] {
+            assert_eq!(
+                platform(id),
+                Some((Variant::Schip, Variant::Schip.quirks()))
+            );
+        }
+        assert_eq!(
+            platform("xochip"),
+            Some((Variant::XoChip, Variant::XoChip.quirks()))
+        );
+        for id in &["chip8x", "megachip8", "superchip8", ""] {
+            assert_eq!(platform(id), None);
+        }
+    }
+
+    #[test]
+    fn quirk_flags_map_to_ours() {
+        // Start from the platform with the opposite setting so every flag has to change it
+        assert!(!quirks_after("originalChip8", r#"{"shift": true}"#).shift_vy);
+        assert!(quirks_after("superchip", r#"{"shift": false}"#).shift_vy);
+        assert!(
+            !quirks_after("originalChip8", r#"{"memoryLeaveIUnchanged": true}"#).memory_increment
+        );
+        assert!(quirks_after("superchip", r#"{"memoryLeaveIUnchanged": false}"#).memory_increment);
+        assert!(quirks_after("superchip", r#"{"memoryIncrementByX": true}"#).memory_increment);
+        assert!(!quirks_after("superchip", r#"{"memoryIncrementByX": false}"#).memory_increment);
+        assert!(!quirks_after("originalChip8", r#"{"wrap": true}"#).clip);
+        assert!(quirks_after("xochip", r#"{"wrap": false}"#).clip);
+        assert!(quirks_after("originalChip8", r#"{"jump": true}"#).jump_vx);
+        assert!(!quirks_after("superchip", r#"{"jump": false}"#).jump_vx);
+        assert!(quirks_after("modernChip8", r#"{"logic": true}"#).vf_reset);
+        assert!(!quirks_after("originalChip8", r#"{"logic": false}"#).vf_reset);
+    }
+
+    #[test]
+    fn other_quirk_flags_are_ignored() {
+        let preset = Variant::Chip8.quirks();
+        assert_eq!(
+            quirks_after("originalChip8", r#"{"vblank": false}"#),
+            preset
+        );
+        assert_eq!(
+            quirks_after("originalChip8", r#"{"shift": 1, "wrap": "yes"}"#),
+            preset
+        );
+        assert_eq!(quirks_after("originalChip8", "[]"), preset);
+    }
+}
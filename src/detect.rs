@@ -0,0 +1,406 @@
+use crate::config::Settings;
+use crate::variant::Variant;
+
+// Below this a guess is only shown, not used
+pub const CONFIDENCE_THRESHOLD: f64 = 0.5;
+
+// A guess at the platform a ROM was written for, from the instructions it uses
+#[derive(Clone, Debug)]
+pub struct Detection {
+    pub platform: Variant,
+    // Between 0 and 1
+    pub confidence: f64,
+    // Starts like a HIRES CHIP-8 program with its 64x64 mode, which isn't emulated
+    pub hires: bool,
+    // Quirks the code looks like it relies on, by name
+    pub quirks: Vec<(&'static str, bool)>,
+    // What gave it away, one line each
+    pub evidence: Vec<String>,
+}
+
+impl Detection {
+    // The platform and any quirks the code relies on, nothing when the guess isn't good enough
+    pub fn settings(&self) -> Settings {
+        let mut settings = Settings::default();
+        if self.confidence < CONFIDENCE_THRESHOLD {
+            return settings;
+        }
+        settings.machine.platform = Some(self.platform);
+        if !self.quirks.is_empty() {
+            let spec: Vec<String> = self
+                .quirks
+                .iter()
+                .map(|(name, on)| format!("{}={}", name, if *on { "on" } else { "off" }))
+                .collect();
+            settings.machine.quirks.push(spec.join(","));
+        }
+        settings
+    }
+}
+
+// Instructions only some platforms have, with what they do
+const SCHIP: &[(u16, u16, &str)] = &[
+    (0xFFFF, 0x00FF, "00FF switches to 128x64"),
+    (0xFFFF, 0x00FE, "00FE switches to 64x32"),
+    (0xFFFF, 0x00FB, "00FB scrolls right"),
+    (0xFFFF, 0x00FC, "00FC scrolls left"),
+    (0xFFFF, 0x00FD, "00FD exits"),
+    (0xFFF0, 0x00C0, "00CN scrolls down"),
+    (0xF00F, 0xD000, "DXY0 draws a 16x16 sprite"),
+    (0xF0FF, 0xF030, "FX30 points I at the big font"),
+    (0xF0FF, 0xF075, "FX75 saves to the RPL flags"),
+    (0xF0FF, 0xF085, "FX85 loads from the RPL flags"),
+];
+const XOCHIP: &[(u16, u16, &str)] = &[
+    (0xFFFF, 0xF000, "F000 NNNN loads a 16 bit address into I"),
+    (0xF00F, 0x5002, "5XY2 saves a range of registers"),
+    (0xF00F, 0x5003, "5XY3 loads a range of registers"),
+    (0xF0FF, 0xF001, "FN01 selects drawing planes"),
+    (0xFFFF, 0xF002, "F002 loads an audio pattern"),
+    (0xF0FF, 0xF03A, "FX3A sets the audio pitch"),
+    (0xFFF0, 0x00D0, "00DN scrolls up"),
+];
+
+// Look through the code reachable from the entry point for telltale instructions, with the
+// ROM loaded at load_address so jumps and calls land where they would when it runs
+pub fn detect(rom: &[u8], load_address: usize, entry_point: usize) -> Detection {
+    let code = reachable(rom, load_address, entry_point);
+    let opcodes: Vec<(usize, u16)> = code
+        .iter()
+        .map(|&offset| (offset, opcode(rom, offset)))
+        .collect();
+    let mut evidence = Vec::new();
+
+    let mut find = |table: &[(u16, u16, &str)]| {
+        let mut kinds = 0;
+        for &(mask, pattern, what) in table {
+            if let Some((offset, _)) = opcodes.iter().find(|(_, op)| op & mask == pattern) {
+                evidence.push(format!("{} at {:#05X}", what, load_address + offset));
+                kinds += 1;
+            }
+        }
+        kinds
+    };
+    let xochip = find(XOCHIP);
+    let schip = find(SCHIP);
+
+    // More kinds of telltale instruction make a coincidence less likely, one on its own could
+    // be data the path went through and isn't enough to switch platform
+    let confidence = |kinds: usize| -> f64 {
+        match kinds {
+            0 => 0.0,
+            1 => 0.4,
+            2 => 0.8,
+            _ => 0.95,
+        }
+    };
+    let (mut platform, mut confidence) = if xochip > 0 {
+        // XO-CHIP has every SCHIP instruction too
+        (Variant::XoChip, confidence(xochip + schip.min(1)))
+    } else if schip > 0 {
+        (Variant::Schip, confidence(schip))
+    } else {
+        (Variant::Chip8, 0.0)
+    };
+    // 4K of memory less what comes before the load address
+    let room = Variant::Chip8.memory_size().saturating_sub(load_address);
+    if rom.len() > room {
+        evidence.push(format!(
+            "{} bytes is more than the {} CHIP-8 and SCHIP have room for after {:#05X}",
+            rom.len(),
+            room,
+            load_address
+        ));
+        platform = Variant::XoChip;
+        confidence = confidence.max(0.9);
+    }
+
+    // HIRES CHIP-8 programs start by jumping over the interpreter patch to 0x260
+    let hires = rom.starts_with(&[0x12, 0x60])
+        && opcodes.iter().any(|&(_, op)| op == 0x0230)
+        && platform == Variant::Chip8;
+    if hires {
+        evidence.push("1260 then 0230 clears the 64x64 HIRES screen".to_string());
+        confidence = 0.8;
+    }
+
+    let quirks = quirk_hints(rom, load_address, &opcodes, &mut evidence);
+    if platform == Variant::Chip8 && !hires {
+        if quirks.is_empty() {
+            // No evidence either way, CHIP-8 is only the default
+            evidence.push("Nothing beyond CHIP-8 instructions".to_string());
+            confidence = 0.3;
+        } else {
+            // SCHIP shift and load/store behaviour with only CHIP-8 instructions points at a CHIP-48 era game
+            platform = Variant::Schip;
+            confidence = 0.4;
+        }
+    }
+
+    Detection {
+        platform,
+        confidence,
+        hires,
+        quirks,
+        evidence,
+    }
+}
+
+// Code patterns that only work one way round for the shift and load/store quirks
+fn quirk_hints(
+    rom: &[u8],
+    load_address: usize,
+    opcodes: &[(usize, u16)],
+    evidence: &mut Vec<String>,
+) -> Vec<(&'static str, bool)> {
+    let mut quirks = Vec::new();
+
+    // 8X06 and 8X0E with Y left as V0 are written for shifting VX in place
+    if let Some((offset, op)) = opcodes.iter().find(|(_, op)| {
+        matches!(op & 0xF00F, 0x8006 | 0x800E) && op & 0x00F0 == 0 && op & 0x0F00 != 0
+    }) {
+        evidence.push(format!(
+            "{:04X} at {:#05X} shifts with VY left as V0",
+            op,
+            load_address + offset
+        ));
+        quirks.push(("shift-vy", false));
+    }
+
+    // What comes straight after FX55 or FX65 before I is set again tells whether I is expected to move
+    let mut increments = None;
+    for &(offset, op) in opcodes {
+        if !matches!(op & 0xF0FF, 0xF055 | 0xF065) {
+            continue;
+        }
+        let next = opcode(rom, offset + 2);
+        let relies = match next & 0xF0FF {
+            // Another block straight after, I must have moved past the first
+            0xF055 | 0xF065 => Some(true),
+            // Drawing from where the registers were loaded, I must have stayed put
+            _ if next & 0xF000 == 0xD000 => Some(false),
+            _ => None,
+        };
+        if let Some(relies) = relies {
+            evidence.push(format!(
+                "{:04X} then {:04X} at {:#05X} expects I {}",
+                op,
+                next,
+                load_address + offset,
+                if relies { "to move" } else { "to stay put" }
+            ));
+            increments = Some(relies);
+            break;
+        }
+    }
+    if let Some(increments) = increments {
+        quirks.push(("memory-increment", increments));
+    }
+    quirks
+}
+
+fn opcode(rom: &[u8], offset: usize) -> u16 {
+    match rom.get(offset..offset + 2) {
+        Some(&[high, low]) => u16::from(high) << 8 | u16::from(low),
+        _ => 0,
+    }
+}
+
+// Offsets of every instruction reachable from the entry point by following jumps, calls and skips,
+// so sprite data and text aren't taken for instructions
+fn reachable(rom: &[u8], load_address: usize, entry_point: usize) -> Vec<usize> {
+    let mut seen = vec![false; rom.len()];
+    let mut pending: Vec<usize> = entry_point.checked_sub(load_address).into_iter().collect();
+    let target = |op: u16| usize::from(op & 0x0FFF).checked_sub(load_address);
+
+    while let Some(offset) = pending.pop() {
+        if offset + 1 >= rom.len() || seen[offset] {
+            continue;
+        }
+        seen[offset] = true;
+        let op = opcode(rom, offset);
+        // XO-CHIP's F000 NNNN is the only four byte instruction
+        let length = |op: u16| if op == 0xF000 { 4 } else { 2 };
+        let next = offset + length(op);
+
+        match op & 0xF000 {
+            // Returns and exit end the path, BNNN jumps somewhere only known at run time
+            _ if op == 0x00EE || op == 0x00FD => {}
+            0xB000 => {}
+            0x1000 => pending.extend(target(op)),
+            0x2000 => {
+                pending.extend(target(op));
+                pending.push(next);
+            }
+            0x3000 | 0x4000 => {
+                pending.push(next);
+                pending.push(next + length(opcode(rom, next)));
+            }
+            0x5000 | 0x9000 if op & 0x000F == 0 => {
+                pending.push(next);
+                pending.push(next + length(opcode(rom, next)));
+            }
+            0xE000 if matches!(op & 0x00FF, 0x9E | 0xA1) => {
+                pending.push(next);
+                pending.push(next + length(opcode(rom, next)));
+            }
+            _ => pending.push(next),
+        }
+    }
+    (0..rom.len()).filter(|&offset| seen[offset]).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const START: usize = 0x200;
+
+    fn guess(rom: &[u8]) -> Detection {
+        detect(rom, START, START)
+    }
+
+    // A ROM of size bytes that loops at its start
+    fn sized(size: usize) -> Vec<u8> {
+        let mut rom = vec![0; size];
+        rom[..2].copy_from_slice(&[0x12, 0x00]);
+        rom
+    }
+
+    #[test]
+    fn plain_chip8_is_only_the_default() {
+        let detection = guess(&[0x60, 0x01, 0x70, 0x01, 0x12, 0x02]);
+        assert_eq!(detection.platform, Variant::Chip8);
+        assert_eq!(detection.confidence, 0.3);
+        assert!(!detection.hires);
+        assert_eq!(detection.evidence, ["Nothing beyond CHIP-8 instructions"]);
+        assert_eq!(detection.settings().machine.platform, None);
+    }
+
+    #[test]
+    fn one_schip_instruction_is_not_enough() {
+        let detection = guess(&[0x00, 0xFF, 0x12, 0x02]);
+        assert_eq!(detection.platform, Variant::Schip);
+        assert!(detection.confidence < CONFIDENCE_THRESHOLD);
+        assert_eq!(detection.evidence, ["00FF switches to 128x64 at 0x200"]);
+        assert_eq!(detection.settings().machine.platform, None);
+    }
+
+    #[test]
+    fn several_schip_instructions_pick_schip() {
+        let detection = guess(&[0x00, 0xFF, 0xF1, 0x30, 0x12, 0x04]);
+        assert_eq!(detection.platform, Variant::Schip);
+        assert_eq!(detection.confidence, 0.8);
+        assert_eq!(
+            detection.evidence,
+            [
+                "00FF switches to 128x64 at 0x200",
+                "FX30 points I at the big font at 0x202"
+            ]
+        );
+        assert_eq!(detection.settings().machine.platform, Some(Variant::Schip));
+    }
+
+    #[test]
+    fn xochip_instructions_pick_xochip() {
+        // The address after F000 isn't an instruction of its own
+        let detection = guess(&[0xF0, 0x00, 0x00, 0xFF, 0xF2, 0x01, 0x12, 0x06]);
+        assert_eq!(detection.platform, Variant::XoChip);
+        assert_eq!(detection.confidence, 0.8);
+        assert_eq!(detection.evidence.len(), 2);
+        assert_eq!(detection.settings().machine.platform, Some(Variant::XoChip));
+
+        // A SCHIP instruction backs up a single XO-CHIP one
+        let detection = guess(&[0xF2, 0x01, 0x00, 0xFF, 0x12, 0x04]);
+        assert_eq!(detection.platform, Variant::XoChip);
+        assert_eq!(detection.confidence, 0.8);
+    }
+
+    #[test]
+    fn only_reachable_code_counts() {
+        // Jumps over data that would look like SCHIP instructions
+        let detection = guess(&[0x12, 0x06, 0x00, 0xFF, 0xF1, 0x30, 0x12, 0x06]);
+        assert_eq!(detection.platform, Variant::Chip8);
+        assert_eq!(detection.confidence, 0.3);
+
+        // Code a skip can jump over to is followed as well as the jump
+        let detection = guess(&[0x30, 0x00, 0x12, 0x08, 0x00, 0xFF, 0xF1, 0x30, 0x12, 0x08]);
+        assert_eq!(detection.platform, Variant::Schip);
+    }
+
+    #[test]
+    fn code_is_followed_from_the_entry_point() {
+        let rom = [0x12, 0x00, 0x00, 0xFF, 0xF1, 0x30, 0x12, 0x06];
+        assert_eq!(detect(&rom, START, START).platform, Variant::Chip8);
+        assert_eq!(detect(&rom, START, START + 2).platform, Variant::Schip);
+        // Jumps are relative to the load address
+        let rom = [0x00, 0xFF, 0xF1, 0x30, 0x16, 0x00];
+        assert_eq!(detect(&rom, 0x600, 0x600).confidence, 0.8);
+        // Starting outside the ROM finds nothing to go on
+        assert_eq!(detect(&rom, START, 0x100).confidence, 0.3);
+    }
+
+    #[test]
+    fn hires_chip8_is_recognised() {
+        let mut rom = vec![0; 0x64];
+        rom[..2].copy_from_slice(&[0x12, 0x60]);
+        rom[0x60..].copy_from_slice(&[0x02, 0x30, 0x12, 0x62]);
+        let detection = guess(&rom);
+        assert!(detection.hires);
+        assert_eq!(detection.platform, Variant::Chip8);
+        assert_eq!(detection.confidence, 0.8);
+        assert_eq!(
+            detection.evidence,
+            ["1260 then 0230 clears the 64x64 HIRES screen"]
+        );
+
+        // Without 0230 it's just a jump
+        rom[0x60..0x62].copy_from_slice(&[0x00, 0xE0]);
+        assert!(!guess(&rom).hires);
+    }
+
+    #[test]
+    fn quirk_hints_from_shifts_and_register_blocks() {
+        let detection = guess(&[0x81, 0x06, 0xF2, 0x55, 0xF2, 0x55, 0x12, 0x06]);
+        assert_eq!(
+            detection.quirks,
+            [("shift-vy", false), ("memory-increment", true)]
+        );
+        // Hints alone point at a CHIP-48 game but aren't enough to switch platform
+        assert_eq!(detection.platform, Variant::Schip);
+        assert!(detection.confidence < CONFIDENCE_THRESHOLD);
+
+        let detection = guess(&[0xF2, 0x65, 0xD0, 0x15, 0x12, 0x04]);
+        assert_eq!(detection.quirks, [("memory-increment", false)]);
+
+        // Shifting V0 or naming VY says nothing
+        assert!(guess(&[0x80, 0x06, 0x81, 0x2E, 0x12, 0x04])
+            .quirks
+            .is_empty());
+
+        // With enough evidence for the platform the hints come along as quirks
+        let detection = guess(&[0x00, 0xFF, 0xF1, 0x30, 0x81, 0x0E, 0x12, 0x06]);
+        let settings = detection.settings();
+        assert_eq!(settings.machine.platform, Some(Variant::Schip));
+        assert_eq!(settings.machine.quirks, ["shift-vy=off"]);
+    }
+
+    #[test]
+    fn oversized_roms_need_xochip() {
+        assert_eq!(guess(&sized(4096 - START)).platform, Variant::Chip8);
+        let detection = guess(&sized(4096 - START + 1));
+        assert_eq!(detection.platform, Variant::XoChip);
+        assert_eq!(detection.confidence, 0.9);
+        assert_eq!(
+            detection.evidence,
+            ["3585 bytes is more than the 3584 CHIP-8 and SCHIP have room for after 0x200"]
+        );
+
+        // The room depends on where the ROM is loaded
+        let rom = sized(4096 - 0x600 + 1);
+        assert_eq!(detect(&rom, START, START).platform, Variant::Chip8);
+        let mut rom = rom;
+        rom[..2].copy_from_slice(&[0x16, 0x00]);
+        assert_eq!(detect(&rom, 0x600, 0x600).platform, Variant::XoChip);
+    }
+}
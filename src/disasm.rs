@@ -0,0 +1,66 @@
+// Mnemonics follow Cowgod's Chip-8 technical reference
+pub fn disassemble(opcode: u16) -> String {
+    let x = (opcode & 0x0F00) >> 8;
+    let y = (opcode & 0x00F0) >> 4;
+    let n = opcode & 0x000F;
+    let nn = opcode & 0x00FF;
+    let nnn = opcode & 0x0FFF;
+
+    match (opcode & 0xF000, n, nn) {
+        (0x0000, _, 0xE0) if nnn == 0x0E0 => "CLS".to_string(),
+        (0x0000, _, 0xEE) if nnn == 0x0EE => "RET".to_string(),
+        (0x0000, _, _) => format!("SYS {:#05X}", nnn),
+        (0x1000, _, _) => format!("JP {:#05X}", nnn),
+        (0x2000, _, _) => format!("CALL {:#05X}", nnn),
+        (0x3000, _, _) => format!("SE V{:X}, {:#04X}", x, nn),
+        (0x4000, _, _) => format!("SNE V{:X}, {:#04X}", x, nn),
+        (0x5000, 0x0, _) => format!("SE V{:X}, V{:X}", x, y),
+        (0x6000, _, _) => format!("LD V{:X}, {:#04X}", x, nn),
+        (0x7000, _, _) => format!("ADD V{:X}, {:#04X}", x, nn),
+        (0x8000, 0x0, _) => format!("LD V{:X}, V{:X}", x, y),
+        (0x8000, 0x1, _) => format!("OR V{:X}, V{:X}", x, y),
+        (0x8000, 0x2, _) => format!("AND V{:X}, V{:X}", x, y),
+        (0x8000, 0x3, _) => format!("XOR V{:X}, V{:X}", x, y),
+        (0x8000, 0x4, _) => format!("ADD V{:X}, V{:X}", x, y),
+        (0x8000, 0x5, _) => format!("SUB V{:X}, V{:X}", x, y),
+        (0x8000, 0x6, _) => format!("SHR V{:X}, V{:X}", x, y),
+        (0x8000, 0x7, _) => format!("SUBN V{:X}, V{:X}", x, y),
+        (0x8000, 0xE, _) => format!("SHL V{:X}, V{:X}", x, y),
+        (0x9000, 0x0, _) => format!("SNE V{:X}, V{:X}", x, y),
+        (0xA000, _, _) => format!("LD I, {:#05X}", nnn),
+        (0xB000, _, _) => format!("JP V0, {:#05X}", nnn),
+        (0xC000, _, _) => format!("RND V{:X}, {:#04X}", x, nn),
+        (0xD000, _, _) => format!("DRW V{:X}, V{:X}, {}", x, y, n),
+        (0xE000, _, 0x9E) => format!("SKP V{:X}", x),
+        (0xE000, _, 0xA1) => format!("SKNP V{:X}", x),
+        (0xF000, _, 0x07) => format!("LD V{:X}, DT", x),
+        (0xF000, _, 0x0A) => format!("LD V{:X}, K", x),
+        (0xF000, _, 0x15) => format!("LD DT, V{:X}", x),
+        (0xF000, _, 0x18) => format!("LD ST, V{:X}", x),
+        (0xF000, _, 0x1E) => format!("ADD I, V{:X}", x),
+        (0xF000, _, 0x29) => format!("LD F, V{:X}", x),
+        (0xF000, _, 0x33) => format!("LD B, V{:X}", x),
+        (0xF000, _, 0x55) => format!("LD [I], V{:X}", x),
+        (0xF000, _, 0x65) => format!("LD V{:X}, [I]", x),
+        _ => format!("DW {:#06X}", opcode),
+    }
+}
+
+// One line per instruction as address, raw bytes and mnemonic, starting at the given load address
+pub fn listing(rom: &[u8], base: u16) -> Vec<String> {
+    rom.chunks(2)
+        .enumerate()
+        .map(|(i, bytes)| {
+            let address = base as usize + i * 2;
+            match *bytes {
+                [high, low] => {
+                    let opcode = u16::from(high) << 8 | u16::from(low);
+                    format!("{:#05X}  {:04X}  {}", address, opcode, disassemble(opcode))
+                }
+                // A trailing odd byte can't be an instruction
+                [byte] => format!("{:#05X}  {:02X}    DB {:#04X}", address, byte, byte),
+                _ => unreachable!(),
+            }
+        })
+        .collect()
+}
@@ -0,0 +1,270 @@
+pub mod terminal;
+pub mod units;
+pub mod watch;
+
+use crate::movie::Movie;
+use crate::system::{BuzzerEvent, System, TIMER_HZ};
+use crate::units::cu;
+use std::thread;
+use std::time::{Duration, Instant};
+use watch::Watch;
+
+// Emulator controls a frontend can ask for, anything only the frontend cares about it handles itself
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Control {
+    Quit,
+    Pause,
+    // Run a single frame and stay paused
+    FrameAdvance,
+    // Restart the ROM keeping memory outside it
+    SoftReset,
+    // Restart from a cleared machine
+    HardReset,
+    SlowMotion,
+    SpeedDown,
+    SpeedUp,
+}
+
+// Everything the run loop needs from the host: a display, a buzzer, a keypad and a clock
+pub trait Frontend {
+    // Controls requested since the last call, called first every time round the loop
+    fn poll_controls(&mut self) -> Vec<Control>;
+
+    // Keys held for the next frame
+    fn poll_keypad(&mut self) -> [bool; 16];
+
+    // Whether frames should run as fast as possible right now
+    fn fast_forward(&self) -> bool {
+        false
+    }
+
+    // Show a newly drawn frame
+    fn present(&mut self, gfx: &[[u16; 64]; 32]);
+
+    // Called every time round the loop, e.g. to redraw an overlay that changed
+    fn refresh(&mut self) {}
+
+    // Buzzer changes up to the given emulated time, in seconds since power on
+    fn set_buzzer(&mut self, events: &[BuzzerEvent], until: f64);
+
+    // A frame of the given number of instructions finished at the given emulated time
+    fn frame_executed(&mut self, _instructions: u32, _time: f64) {}
+
+    // Short notice for the user, e.g. after a reset
+    fn message(&mut self, _text: &str) {}
+
+    fn set_paused(&mut self, _paused: bool) {}
+
+    // Lasting indicator such as fast forward or slow motion
+    fn set_status(&mut self, _status: Option<&'static str>) {}
+
+    // Clock the loop paces itself by, a frontend without real time can keep its own
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep_until(&mut self, deadline: Instant) {
+        let now = Instant::now();
+        if deadline > now {
+            thread::sleep(deadline - now);
+        }
+    }
+
+    // Called once the loop ends, before the frontend is dropped
+    fn finish(&mut self) {}
+}
+
+// How a run is set up and what it leaves behind
+#[derive(Default)]
+pub struct Session {
+    // ROM reloaded on resets
+    pub rom: Vec<u8>,
+    pub max_frames: Option<u64>,
+    pub max_cycles: Option<u64>,
+    // Movie being recorded, keys are appended every frame
+    pub recording: Option<Movie>,
+    // Movie being played back, the run stops when it runs out
+    pub playback: Option<Movie>,
+    // Reloads the ROM when its file changes
+    pub watch: Option<Watch>,
+    // Frames and instructions run so far
+    pub frames: u64,
+    pub cycles: u64,
+}
+
+// Run the system on a frontend until it asks to quit or a limit is reached
+pub fn run(system: &mut System, frontend: &mut dyn Frontend, session: &mut Session) {
+    let mut paused = false;
+    let mut slow_motion = false;
+    let mut next_frame = frontend.now();
+
+    'frames: loop {
+        // Movies only hold keypad input, so anything changing how frames run is off while one is in use
+        let movie_active = session.recording.is_some() || session.playback.is_some();
+        let mut advance = false;
+
+        if let Some(result) = session.watch.as_mut().and_then(Watch::poll) {
+            hot_reload(system, frontend, session, result, movie_active);
+        }
+
+        for control in frontend.poll_controls() {
+            match control {
+                Control::Quit => break 'frames,
+                Control::Pause => {
+                    paused = !paused;
+                    frontend.set_paused(paused);
+                }
+                Control::FrameAdvance => {
+                    advance = paused;
+                    paused = true;
+                    frontend.set_paused(true);
+                }
+                Control::SoftReset | Control::HardReset if movie_active => {
+                    frontend.message("No resets during movies");
+                }
+                Control::SoftReset => {
+                    system.reset();
+                    reload(system, frontend, &session.rom, "Reset");
+                }
+                Control::HardReset => {
+                    system.hard_reset();
+                    reload(system, frontend, &session.rom, "Hard reset");
+                }
+                Control::SlowMotion => slow_motion = !slow_motion,
+                Control::SpeedDown | Control::SpeedUp if movie_active => {
+                    frontend.message("No speed changes during movies");
+                }
+                Control::SpeedDown | Control::SpeedUp => {
+                    if control == Control::SpeedUp {
+                        system.ipf += 1;
+                    } else {
+                        system.ipf = system.ipf.saturating_sub(1).max(1);
+                    }
+                    frontend.message(&format!("Speed {} IPF", system.ipf));
+                }
+            }
+        }
+        let host_keys = frontend.poll_keypad();
+        let fast_forward = frontend.fast_forward();
+        frontend.set_status(if fast_forward {
+            Some("FAST FORWARD")
+        } else if slow_motion {
+            Some("SLOW MOTION")
+        } else {
+            None
+        });
+
+        if paused && !advance {
+            frontend.refresh();
+            wait_for_frame(frontend, &mut next_frame, 1.0);
+            continue;
+        }
+
+        let keys = match session.playback.as_ref() {
+            Some(playback) => match playback.keys(session.frames as usize) {
+                Some(keys) => keys,
+                None => break,
+            },
+            None => host_keys,
+        };
+        if let Some(movie) = session.recording.as_mut() {
+            movie.push(&keys);
+        }
+
+        // Program Cycles for one 60hz frame
+        system.run_frame(&keys);
+        frontend.set_buzzer(&system.take_buzzer_events(), system.emulated_time());
+        frontend.frame_executed(system.ipf, system.emulated_time());
+        session.frames += 1;
+        session.cycles += u64::from(system.ipf);
+
+        // Check drawflag
+        if system.draw_flag {
+            frontend.present(&system.gfx);
+            system.falsify_df();
+        }
+        frontend.refresh();
+
+        // Quit once the run limits are reached
+        if session
+            .max_frames
+            .is_some_and(|limit| session.frames >= limit)
+            || session
+                .max_cycles
+                .is_some_and(|limit| session.cycles >= limit)
+        {
+            break;
+        }
+
+        if fast_forward {
+            next_frame = frontend.now();
+        } else {
+            wait_for_frame(
+                frontend,
+                &mut next_frame,
+                if slow_motion { 0.5 } else { 1.0 },
+            );
+        }
+    }
+
+    frontend.finish();
+}
+
+// Load the ROM again after a reset
+fn reload(system: &mut System, frontend: &mut dyn Frontend, rom: &[u8], done: &str) {
+    match system.init(rom) {
+        Ok(()) => frontend.message(done),
+        Err(e) => frontend.message(&e.to_string()),
+    }
+}
+
+// Put a changed ROM in place of the running one, a ROM that doesn't load leaves the old one running
+fn hot_reload(
+    system: &mut System,
+    frontend: &mut dyn Frontend,
+    session: &mut Session,
+    result: Result<cu::CU, String>,
+    movie_active: bool,
+) {
+    let rom = match result {
+        Ok(cartridge) => cartridge.buffer,
+        Err(e) => {
+            frontend.message(&format!("Reload failed: {}", e));
+            return;
+        }
+    };
+    if rom == session.rom {
+        return;
+    }
+    if movie_active {
+        frontend.message("ROM changed, no reloads during movies");
+        return;
+    }
+    // Check before a reset clears the machine, so a ROM that doesn't fit can't leave it empty
+    if let Err(e) = cu::check_fits(&rom, system.memory_size() - system.load_address()) {
+        frontend.message(&format!("Reload failed: {}", e));
+        return;
+    }
+
+    let keep_state = session.watch.as_ref().is_some_and(|watch| watch.keep_state);
+    session.rom = rom;
+    if keep_state {
+        reload(system, frontend, &session.rom, "ROM reloaded, state kept");
+    } else {
+        system.hard_reset();
+        reload(system, frontend, &session.rom, "ROM reloaded");
+    }
+}
+
+// Sleep until the next 60hz frame is due at the given speed, skipping ahead rather than rushing if we fell far behind
+fn wait_for_frame(frontend: &mut dyn Frontend, next_frame: &mut Instant, speed: f64) {
+    let frame = (Duration::from_secs(1) / TIMER_HZ).div_f64(speed);
+    *next_frame += frame;
+
+    let now = frontend.now();
+    if *next_frame > now {
+        frontend.sleep_until(*next_frame);
+    } else if now - *next_frame > frame * 5 {
+        *next_frame = now;
+    }
+}
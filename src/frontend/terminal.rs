@@ -0,0 +1,65 @@
+#[cfg(unix)]
+use super::{Control, Frontend};
+#[cfg(unix)]
+use crate::system::BuzzerEvent;
+#[cfg(unix)]
+use crate::units::tu::TU;
+#[cfg(unix)]
+use std::io;
+
+// Frontend on the terminal unit, a raw mode tty with the bell as buzzer
+#[cfg(unix)]
+pub struct TerminalFrontend {
+    pub terminal_unit: TU,
+    keys: [bool; 16],
+    // Drawing failed, the run stops and the error is reported once the terminal is restored
+    error: Option<io::Error>,
+}
+
+#[cfg(unix)]
+impl TerminalFrontend {
+    pub fn new(terminal_unit: TU) -> TerminalFrontend {
+        TerminalFrontend {
+            terminal_unit,
+            keys: [false; 16],
+            error: None,
+        }
+    }
+
+    pub fn take_error(&mut self) -> Option<io::Error> {
+        self.error.take()
+    }
+}
+
+#[cfg(unix)]
+impl Frontend for TerminalFrontend {
+    fn poll_controls(&mut self) -> Vec<Control> {
+        if self.error.is_some() {
+            return vec![Control::Quit];
+        }
+        match self.terminal_unit.process_input() {
+            Ok(keys) => {
+                self.keys = keys;
+                Vec::new()
+            }
+            Err(()) => vec![Control::Quit],
+        }
+    }
+
+    fn poll_keypad(&mut self) -> [bool; 16] {
+        self.keys
+    }
+
+    fn present(&mut self, gfx: &[[u16; 64]; 32]) {
+        if let Err(e) = self.terminal_unit.draw(gfx) {
+            self.error = Some(e);
+        }
+    }
+
+    // The bell rings on every switch on, however short
+    fn set_buzzer(&mut self, events: &[BuzzerEvent], _until: f64) {
+        for event in events {
+            self.terminal_unit.set_buzzer(event.on);
+        }
+    }
+}
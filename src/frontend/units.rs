@@ -0,0 +1,330 @@
+use super::{Control, Frontend};
+use crate::capture::{png, timestamped_path, Image};
+use crate::system::BuzzerEvent;
+use crate::units::au::{Tone, AU};
+use crate::units::gu::GU;
+use crate::units::ku::{Hotkey, KU};
+use sdl2::Sdl;
+use std::path::Path;
+use std::time::Instant;
+
+// Frontend over the graphical, keyboard and audio units, a window when they were built on SDL
+// and a headless run when they were built with their headless constructors
+pub struct UnitFrontend {
+    pub graphical_unit: GU,
+    pub keyboard_unit: KU,
+    pub audio_unit: AU,
+    keys: [bool; 16],
+    // Quit once a recording holds this many frames
+    pub record_limit: Option<u64>,
+    // Headless runs keep their own clock and never sleep
+    clock: Option<Instant>,
+    // The SDL context the units were built on, None when they weren't
+    _sdl: Option<Sdl>,
+}
+
+impl UnitFrontend {
+    pub fn new(graphical_unit: GU, keyboard_unit: KU, audio_unit: AU) -> UnitFrontend {
+        UnitFrontend {
+            graphical_unit,
+            keyboard_unit,
+            audio_unit,
+            keys: [false; 16],
+            record_limit: None,
+            clock: None,
+            _sdl: None,
+        }
+    }
+
+    // A window scale times the size of the Chip-8 display, with its keyboard, controllers and sound
+    pub fn sdl(title: &str, scale: u32, tone: Tone) -> UnitFrontend {
+        let context = sdl2::init().unwrap();
+        let video_subsystem = context.video().unwrap();
+        let audio_subsystem = context.audio().unwrap();
+        let controller_subsystem = match context.game_controller() {
+            Ok(subsystem) => Some(subsystem),
+            Err(e) => {
+                eprintln!("ERROR: Game controllers unavailable: {}", e);
+                None
+            }
+        };
+
+        let mut frontend = UnitFrontend::new(
+            GU::new(&video_subsystem, title, 64 * scale, 32 * scale),
+            KU::new(context.event_pump().unwrap(), controller_subsystem),
+            AU::new(&audio_subsystem, tone),
+        );
+        frontend._sdl = Some(context);
+        frontend
+    }
+
+    // Run as fast as possible, timing recordings by emulated time instead of the wall clock
+    pub fn headless(graphical_unit: GU, keyboard_unit: KU, audio_unit: AU) -> UnitFrontend {
+        let mut frontend = UnitFrontend::new(graphical_unit, keyboard_unit, audio_unit);
+        frontend.graphical_unit.clock = Some(0.0);
+        frontend.clock = Some(Instant::now());
+        frontend
+    }
+
+    pub fn is_headless(&self) -> bool {
+        self.clock.is_some()
+    }
+
+    // Save the last presented frame as a PNG
+    pub fn save_screenshot(&mut self, scale: u32) {
+        let image = Image::from_gfx(
+            &self.graphical_unit.frame,
+            &self.graphical_unit.palette,
+            scale,
+        );
+        let path = timestamped_path(Path::new("."), "png");
+        match png::save(&image, &path) {
+            Ok(()) => {
+                println!("Screenshot saved to {}", path.display());
+                self.graphical_unit.osd.message("Screenshot saved");
+            }
+            Err(e) => {
+                eprintln!("ERROR: Failed to save screenshot: {}", e);
+                self.graphical_unit.osd.message("Screenshot failed");
+            }
+        }
+    }
+
+    // Record video to the given path with sound to the given .wav, or one next to the video
+    pub fn start_recording(&mut self, path: &Path, scale: u32, audio_path: Option<&Path>) {
+        match self.graphical_unit.start_recording(path, scale) {
+            Ok(()) => {
+                println!("Recording to {}", path.display());
+                self.graphical_unit.osd.message("Recording started");
+            }
+            Err(e) => {
+                eprintln!("ERROR: Failed to start recording: {}", e);
+                self.graphical_unit.osd.message("Recording failed");
+                return;
+            }
+        }
+        match audio_path {
+            Some(audio_path) => self.start_audio_recording(audio_path),
+            None => self.start_audio_recording(&path.with_extension("wav")),
+        }
+    }
+
+    pub fn start_audio_recording(&mut self, path: &Path) {
+        match self.audio_unit.start_recording(path) {
+            Ok(()) => println!("Recording audio to {}", path.display()),
+            Err(e) => eprintln!("ERROR: Failed to start audio recording: {}", e),
+        }
+    }
+
+    pub fn stop_recording(&mut self) {
+        match self.graphical_unit.stop_recording() {
+            Ok(Some(frames)) => {
+                println!("Recording finished with {} frames", frames);
+                self.graphical_unit.osd.message("Recording saved");
+            }
+            Ok(None) => {}
+            Err(e) => {
+                eprintln!("ERROR: Failed to finish recording: {}", e);
+                self.graphical_unit.osd.message("Recording failed");
+            }
+        }
+        match self.audio_unit.stop_recording() {
+            Ok(Some(samples)) => println!(
+                "Audio recording finished with {} samples at {} Hz",
+                samples, self.audio_unit.sample_rate
+            ),
+            Ok(None) => {}
+            Err(e) => eprintln!("ERROR: Failed to finish audio recording: {}", e),
+        }
+    }
+
+    // Hotkeys this frontend handles itself, the rest are passed on to the run loop
+    fn handle_hotkey(&mut self, hotkey: Hotkey) -> Option<Control> {
+        match hotkey {
+            Hotkey::Screenshot => {
+                let scale = self.graphical_unit.scale();
+                self.save_screenshot(scale);
+            }
+            Hotkey::ScreenshotNative => self.save_screenshot(1),
+            Hotkey::ToggleRecording => {
+                if self.graphical_unit.recorder.is_some() {
+                    self.stop_recording();
+                } else {
+                    let path = timestamped_path(Path::new("."), "gif");
+                    let scale = self.graphical_unit.scale();
+                    self.start_recording(&path, scale, None);
+                }
+            }
+            Hotkey::ToggleFullscreen => self.graphical_unit.toggle_fullscreen(),
+            Hotkey::ToggleOsd => self.graphical_unit.osd.toggle(),
+            Hotkey::ToggleMute => {
+                if self.audio_unit.toggle_mute() {
+                    self.graphical_unit.osd.message("Muted");
+                } else {
+                    self.graphical_unit.osd.message("Unmuted");
+                }
+            }
+            Hotkey::VolumeDown | Hotkey::VolumeUp => {
+                let step = if hotkey == Hotkey::VolumeUp {
+                    0.05
+                } else {
+                    -0.05
+                };
+                let volume = self.audio_unit.volume() + step;
+                self.audio_unit.set_volume(volume);
+                let volume = self.audio_unit.volume();
+                self.graphical_unit
+                    .osd
+                    .message(&format!("Volume {:.0}%", volume * 100.0));
+            }
+            Hotkey::Pause => return Some(Control::Pause),
+            Hotkey::FrameAdvance => return Some(Control::FrameAdvance),
+            Hotkey::SoftReset => return Some(Control::SoftReset),
+            Hotkey::HardReset => return Some(Control::HardReset),
+            // Held rather than pressed, see fast_forward
+            Hotkey::FastForward => {}
+            Hotkey::SlowMotion => return Some(Control::SlowMotion),
+            Hotkey::SpeedDown => return Some(Control::SpeedDown),
+            Hotkey::SpeedUp => return Some(Control::SpeedUp),
+        }
+        None
+    }
+}
+
+impl Frontend for UnitFrontend {
+    fn poll_controls(&mut self) -> Vec<Control> {
+        self.keys = match self.keyboard_unit.process_input() {
+            Ok(keys) => keys,
+            Err(()) => return vec![Control::Quit],
+        };
+
+        let mut controls: Vec<Control> = self
+            .keyboard_unit
+            .take_hotkeys()
+            .into_iter()
+            .filter_map(|hotkey| self.handle_hotkey(hotkey))
+            .collect();
+
+        // Quit once the requested number of frames has been recorded
+        if let (Some(limit), Some(frames)) =
+            (self.record_limit, self.graphical_unit.recorded_frames())
+        {
+            if frames >= limit {
+                controls.push(Control::Quit);
+            }
+        }
+        controls
+    }
+
+    fn poll_keypad(&mut self) -> [bool; 16] {
+        self.keys
+    }
+
+    fn fast_forward(&self) -> bool {
+        self.keyboard_unit.hotkey_held(Hotkey::FastForward)
+    }
+
+    fn present(&mut self, gfx: &[[u16; 64]; 32]) {
+        self.graphical_unit.osd.update();
+        self.graphical_unit.draw(gfx);
+        self.graphical_unit.osd.take_dirty();
+    }
+
+    fn refresh(&mut self) {
+        self.graphical_unit.osd.update();
+        if self.keyboard_unit.take_window_changed() | self.graphical_unit.osd.take_dirty() {
+            let frame = self.graphical_unit.frame;
+            self.graphical_unit.redraw(&frame);
+        }
+    }
+
+    fn set_buzzer(&mut self, events: &[BuzzerEvent], until: f64) {
+        self.audio_unit.render(events, until);
+    }
+
+    fn frame_executed(&mut self, instructions: u32, time: f64) {
+        self.graphical_unit
+            .osd
+            .instructions_executed(u64::from(instructions));
+        if self.is_headless() {
+            self.graphical_unit.clock = Some(time);
+        }
+    }
+
+    fn message(&mut self, text: &str) {
+        self.graphical_unit.osd.message(text);
+    }
+
+    fn set_paused(&mut self, paused: bool) {
+        self.graphical_unit.osd.set_paused(paused);
+    }
+
+    fn set_status(&mut self, status: Option<&'static str>) {
+        self.graphical_unit.osd.set_status(status);
+    }
+
+    fn now(&self) -> Instant {
+        self.clock.unwrap_or_else(Instant::now)
+    }
+
+    fn sleep_until(&mut self, deadline: Instant) {
+        match self.clock.as_mut() {
+            Some(clock) => *clock = (*clock).max(deadline),
+            None => {
+                let now = Instant::now();
+                if deadline > now {
+                    std::thread::sleep(deadline - now);
+                }
+            }
+        }
+    }
+
+    fn finish(&mut self) {
+        self.stop_recording();
+        if self.is_headless() {
+            println!("Buzzer on for {:.3}s", self.audio_unit.beep_time());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frontend() -> UnitFrontend {
+        UnitFrontend::headless(
+            GU::headless(1),
+            KU::headless(),
+            AU::headless(Tone::default()),
+        )
+    }
+
+    #[test]
+    fn recordings_write_sound_next_to_the_video() {
+        let dir = std::env::temp_dir().join(format!("cj-8-test-sidecar-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let video = dir.join("run.gif");
+
+        let mut frontend = frontend();
+        frontend.start_recording(&video, 1, None);
+        frontend.stop_recording();
+        assert!(video.exists());
+        assert!(dir.join("run.wav").exists());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn recordings_with_an_audio_path_leave_no_sidecar() {
+        let dir = std::env::temp_dir().join(format!("cj-8-test-audio-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let video = dir.join("run.gif");
+        let audio = dir.join("sound.wav");
+
+        let mut frontend = frontend();
+        frontend.start_recording(&video, 1, Some(&audio));
+        frontend.stop_recording();
+        assert!(audio.exists());
+        assert!(!dir.join("run.wav").exists());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,76 @@
+use crate::units::cu::{self, CU};
+use std::fs;
+use std::path::PathBuf;
+use std::time::{Duration, Instant, SystemTime};
+
+// How often the ROM file is looked at
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+// Modification time and size, enough to notice an assembler writing a new build
+type Stamp = (Option<SystemTime>, u64);
+
+// Watches the ROM file during a run so a new build can be loaded without restarting
+pub struct Watch {
+    path: PathBuf,
+    patches: Vec<PathBuf>,
+    // Copy the new ROM over the old one and carry on, rather than restarting from a cleared machine
+    pub keep_state: bool,
+    stamp: Option<Stamp>,
+    // A change seen on the last look, loaded once the file stops changing
+    pending: Option<Stamp>,
+    next_poll: Instant,
+}
+
+impl Watch {
+    // The ROM path as given on the command line, with the patches applied on every reload
+    pub fn new(path: PathBuf, patches: Vec<PathBuf>, keep_state: bool) -> Watch {
+        let mut watch = Watch {
+            path,
+            patches,
+            keep_state,
+            stamp: None,
+            pending: None,
+            next_poll: Instant::now() + POLL_INTERVAL,
+        };
+        watch.stamp = watch.stamp();
+        watch
+    }
+
+    fn stamp(&self) -> Option<Stamp> {
+        // Watch the archive itself for archive.zip#entry
+        let (file, _) = cu::split_entry(&self.path);
+        let metadata = fs::metadata(file).ok()?;
+        Some((metadata.modified().ok(), metadata.len()))
+    }
+
+    // The ROM as it is now once it has changed and settled, None while nothing has changed
+    pub fn poll(&mut self) -> Option<Result<CU, String>> {
+        let now = Instant::now();
+        if now < self.next_poll {
+            return None;
+        }
+        self.next_poll = now + POLL_INTERVAL;
+
+        // A file that vanished is usually being rewritten, wait for it to come back
+        let stamp = self.stamp()?;
+        if Some(stamp) == self.stamp {
+            self.pending = None;
+            return None;
+        }
+        // Wait a look for the build to finish writing
+        if self.pending != Some(stamp) {
+            self.pending = Some(stamp);
+            return None;
+        }
+        self.pending = None;
+        self.stamp = Some(stamp);
+
+        let result = CU::new(&self.path).and_then(|mut cartridge| {
+            for patch in self.patches.iter() {
+                cartridge.patch(patch)?;
+            }
+            Ok(cartridge)
+        });
+        Some(result.map_err(|e| e.to_string()))
+    }
+}
@@ -1,5 +1,22 @@
+extern crate libc;
 extern crate rand;
 extern crate sdl2;
 
+pub mod capture;
+pub mod cli;
+pub mod config;
+pub mod database;
+pub mod detect;
+pub mod disasm;
+pub mod frontend;
+pub mod hash;
+pub mod json;
+pub mod movie;
+pub mod octo;
+pub mod palette;
+pub mod patch;
+pub mod quirks;
+pub mod romfile;
 pub mod system;
 pub mod units;
+pub mod variant;
@@ -0,0 +1,247 @@
+use crate::quirks::Quirks;
+use crate::variant::Variant;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+
+const HEADER: &str = "CJ-8 movie 1";
+
+// Keypad input for every frame of a run, with everything else needed to replay it exactly
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Movie {
+    // SHA-1 of the ROM the movie was recorded with
+    pub rom_hash: String,
+    pub seed: u64,
+    pub ipf: u32,
+    pub quirks: Quirks,
+    // The memory size and where the ROM went, None in movies from before they were recorded
+    pub platform: Option<Variant>,
+    pub load_address: Option<usize>,
+    pub entry_point: Option<usize>,
+    // One bit per Chip-8 key for each frame, bit 0 is key 0
+    pub frames: Vec<u16>,
+    // State hash at the end of the recording, checked at the end of playback
+    pub final_state: Option<String>,
+}
+
+impl Movie {
+    pub fn new(rom_hash: &str, seed: u64, ipf: u32, quirks: Quirks) -> Movie {
+        Movie {
+            rom_hash: rom_hash.to_string(),
+            seed,
+            ipf,
+            quirks,
+            platform: None,
+            load_address: None,
+            entry_point: None,
+            frames: Vec::new(),
+            final_state: None,
+        }
+    }
+
+    // Record the keys held for the next frame
+    pub fn push(&mut self, keys: &[bool; 16]) {
+        let bits = keys
+            .iter()
+            .enumerate()
+            .fold(0u16, |bits, (i, &down)| bits | (u16::from(down) << i));
+        self.frames.push(bits);
+    }
+
+    // Keys held during the given frame, None once the movie has run out
+    pub fn keys(&self, frame: usize) -> Option<[bool; 16]> {
+        let bits = *self.frames.get(frame)?;
+        let mut keys = [false; 16];
+        for (i, key) in keys.iter_mut().enumerate() {
+            *key = bits & (1 << i) != 0;
+        }
+        Some(keys)
+    }
+
+    // Plain text, the header fields then one line per run of identical frames as HEX or HEX*COUNT
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let mut out = io::BufWriter::new(fs::File::create(path)?);
+        writeln!(out, "{}", HEADER)?;
+        writeln!(out, "rom {}", self.rom_hash)?;
+        writeln!(out, "seed {}", self.seed)?;
+        writeln!(out, "ipf {}", self.ipf)?;
+        writeln!(out, "quirks {}", self.quirks)?;
+        if let Some(platform) = self.platform {
+            writeln!(out, "platform {}", platform)?;
+        }
+        if let Some(load_address) = self.load_address {
+            writeln!(out, "load {:#05x}", load_address)?;
+        }
+        if let Some(entry_point) = self.entry_point {
+            writeln!(out, "entry {:#05x}", entry_point)?;
+        }
+        writeln!(out, "frames {}", self.frames.len())?;
+
+        let mut frames = self.frames.iter().peekable();
+        while let Some(&bits) = frames.next() {
+            let mut count = 1;
+            while frames.next_if(|&&next| next == bits).is_some() {
+                count += 1;
+            }
+            if count == 1 {
+                writeln!(out, "{:04x}", bits)?;
+            } else {
+                writeln!(out, "{:04x}*{}", bits, count)?;
+            }
+        }
+
+        if let Some(state) = &self.final_state {
+            writeln!(out, "end {}", state)?;
+        }
+        out.flush()
+    }
+
+    pub fn load(path: &Path) -> io::Result<Movie> {
+        let text = fs::read_to_string(path)?;
+        Movie::parse(&text).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}: {}", path.display(), e),
+            )
+        })
+    }
+
+    pub fn parse(text: &str) -> Result<Movie, String> {
+        let mut lines = text.lines().enumerate();
+        match lines.next() {
+            Some((_, line)) if line.trim() == HEADER => {}
+            _ => return Err("not a CJ-8 movie".to_string()),
+        }
+
+        let mut movie = Movie::new("", 0, 0, Quirks::default());
+        let mut expected_frames: Option<usize> = None;
+        for (number, line) in lines {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let invalid = || format!("line {}: invalid entry: {}", number + 1, line);
+            let address = |text: &str| {
+                text.trim()
+                    .strip_prefix("0x")
+                    .and_then(|hex| usize::from_str_radix(hex, 16).ok())
+                    .ok_or_else(invalid)
+            };
+
+            match line.split_once(' ') {
+                Some(("rom", hash)) => movie.rom_hash = hash.trim().to_string(),
+                Some(("seed", seed)) => movie.seed = seed.trim().parse().map_err(|_| invalid())?,
+                Some(("ipf", ipf)) => movie.ipf = ipf.trim().parse().map_err(|_| invalid())?,
+                Some(("quirks", quirks)) => movie
+                    .quirks
+                    .apply(quirks)
+                    .map_err(|e| format!("line {}: {}", number + 1, e))?,
+                Some(("platform", platform)) => {
+                    movie.platform = Some(platform.trim().parse().map_err(|_| invalid())?)
+                }
+                Some(("load", load_address)) => movie.load_address = Some(address(load_address)?),
+                Some(("entry", entry_point)) => movie.entry_point = Some(address(entry_point)?),
+                Some(("frames", frames)) => {
+                    expected_frames = Some(frames.trim().parse().map_err(|_| invalid())?)
+                }
+                Some(("end", state)) => movie.final_state = Some(state.trim().to_string()),
+                Some(_) => return Err(invalid()),
+                None => {
+                    let (bits, count) = line.split_once('*').unwrap_or((line, "1"));
+                    let bits = u16::from_str_radix(bits, 16).map_err(|_| invalid())?;
+                    let count: usize = count.parse().map_err(|_| invalid())?;
+                    movie.frames.extend(std::iter::repeat_n(bits, count));
+                }
+            }
+        }
+
+        if expected_frames.is_some_and(|frames| frames != movie.frames.len()) {
+            return Err("movie is truncated".to_string());
+        }
+        Ok(movie)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys(down: &[usize]) -> [bool; 16] {
+        let mut keys = [false; 16];
+        for &key in down {
+            keys[key] = true;
+        }
+        keys
+    }
+
+    fn round_trip(movie: &Movie, name: &str) -> (String, Movie) {
+        let path =
+            std::env::temp_dir().join(format!("cj-8-test-{}-{}.movie", name, std::process::id()));
+        movie.save(&path).unwrap();
+        let text = fs::read_to_string(&path).unwrap();
+        let _ = fs::remove_file(&path);
+        let parsed = Movie::parse(&text).unwrap();
+        (text, parsed)
+    }
+
+    #[test]
+    fn runs_of_identical_frames_are_saved_once() {
+        let quirks = Quirks {
+            clip: true,
+            ..Quirks::default()
+        };
+        let mut movie = Movie::new("abc123", 42, 15, quirks);
+        movie.platform = Some(Variant::XoChip);
+        movie.load_address = Some(0x300);
+        movie.entry_point = Some(0x310);
+        for _ in 0..3 {
+            movie.push(&keys(&[]));
+        }
+        movie.push(&keys(&[0, 15]));
+        for _ in 0..2 {
+            movie.push(&keys(&[5]));
+        }
+        movie.final_state = Some("f00d".to_string());
+
+        let (text, parsed) = round_trip(&movie, "runs");
+        let frames: Vec<&str> = text
+            .lines()
+            .filter(|line| !line.contains(' ') && *line != HEADER)
+            .collect();
+        assert_eq!(frames, ["0000*3", "8001", "0020*2"]);
+        assert!(text.contains("\nplatform xochip\nload 0x300\nentry 0x310\n"));
+        assert_eq!(parsed, movie);
+        assert_eq!(parsed.keys(3), Some(keys(&[0, 15])));
+        assert_eq!(parsed.keys(6), None);
+    }
+
+    #[test]
+    fn empty_movie_round_trips() {
+        let movie = Movie::new("abc123", 0, 8, Quirks::default());
+        assert_eq!(round_trip(&movie, "empty").1, movie);
+    }
+
+    #[test]
+    fn parse_expands_counts() {
+        let movie = Movie::parse("CJ-8 movie 1\nrom x\nframes 4\n0001*3\n\n0002\n").unwrap();
+        assert_eq!(movie.frames, [1, 1, 1, 2]);
+        assert_eq!(movie.final_state, None);
+    }
+
+    #[test]
+    fn parse_rejects_bad_movies() {
+        assert!(Movie::parse("not a movie\n").is_err());
+        assert_eq!(
+            Movie::parse("CJ-8 movie 1\nframes 3\n0001*2\n"),
+            Err("movie is truncated".to_string())
+        );
+        assert_eq!(
+            Movie::parse("CJ-8 movie 1\n0001*x\n"),
+            Err("line 2: invalid entry: 0001*x".to_string())
+        );
+        assert!(Movie::parse("CJ-8 movie 1\nspeed 3\n").is_err());
+        assert!(Movie::parse("CJ-8 movie 1\nquirks warp=on\n").is_err());
+        assert!(Movie::parse("CJ-8 movie 1\nplatform vic20\n").is_err());
+        assert!(Movie::parse("CJ-8 movie 1\nload 512\n").is_err());
+    }
+}
@@ -0,0 +1,1272 @@
+use std::collections::HashMap;
+use std::f64::consts;
+
+// Octo programs are assembled for 0x200, the first two bytes are kept for a jump to main
+const START: usize = 0x200;
+// XO-CHIP's 64K is the most an Octo program can fill
+const END: usize = 0x10000;
+// Macros that keep expanding into themselves are stopped after this many expansions
+const MAX_EXPANSIONS: usize = 100_000;
+
+// Words that mean something on their own and can't name a label, constant or macro
+const KEYWORDS: &str =
+    "; return clear bcd save load sprite jump jump0 native delay buzzer pitch i \
+    if then begin else end loop again while key -key random hex bighex long hires lores \
+    scroll-down scroll-up scroll-left scroll-right exit saveflags loadflags plane audio \
+    := += -= =- |= &= ^= >>= <<= == != < > <= >= - { } ( )";
+const UNARY: &[&str] = &[
+    "-", "~", "!", "sin", "cos", "tan", "exp", "log", "abs", "sqrt", "sign", "ceil", "floor",
+];
+
+#[derive(Clone, Debug, PartialEq)]
+enum Text {
+    Word(String),
+    // "..." with its escapes resolved
+    Quoted(String),
+}
+
+#[derive(Clone, Debug)]
+struct Token {
+    text: Text,
+    line: usize,
+}
+
+// A number, or a label that hasn't been reached yet and is filled in at the end
+#[derive(Clone, Debug)]
+enum Value {
+    Known(i64),
+    Forward(String),
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Operand {
+    Register(u8),
+    Byte(u8),
+}
+
+// Where an address goes once it's known
+#[derive(Clone, Copy, Debug)]
+enum Patch {
+    // The low 12 bits of an instruction
+    Address12,
+    // Two bytes, high first
+    Address16,
+    // A byte holding a nibble then the top 4 bits of a 12 bit address, for :unpack
+    High(u8),
+    // The top 8 bits of a 16 bit address, for :unpack long
+    LongHigh,
+    Low,
+}
+
+struct Fixup {
+    at: usize,
+    patch: Patch,
+    name: String,
+    line: usize,
+}
+
+// Open if ... begin, else and loop blocks, holding the jumps still to be pointed somewhere
+enum Block {
+    If(usize),
+    Else(usize),
+    Loop { start: usize, exits: Vec<usize> },
+}
+
+struct Macro {
+    params: Vec<String>,
+    body: Vec<Token>,
+    calls: usize,
+}
+
+// Bodies to expand for each character of a string, by the alphabet they cover
+#[derive(Default)]
+struct StringMode {
+    alphabets: Vec<(String, Vec<Token>)>,
+    calls: usize,
+}
+
+// Assemble Octo source into ROM bytes for 0x200, the way Octo does when it runs a program
+pub fn assemble(source: &str) -> Result<Vec<u8>, String> {
+    let mut tokens = tokenize(source)?;
+    tokens.reverse();
+    let mut assembler = Assembler {
+        tokens,
+        line: 1,
+        rom: Vec::new(),
+        here: START,
+        labels: HashMap::new(),
+        constants: HashMap::new(),
+        aliases: HashMap::new(),
+        macros: HashMap::new(),
+        stringmodes: HashMap::new(),
+        fixups: Vec::new(),
+        blocks: Vec::new(),
+        jump_to_main: true,
+        expansions: 0,
+    };
+    assembler
+        .program()
+        .map_err(|e| format!("line {}: {}", assembler.line, e))
+}
+
+// Octo source rather than some other text: it has a main label, which every Octo program needs
+pub fn is_source(data: &[u8]) -> bool {
+    let tokens = match std::str::from_utf8(data).ok().map(tokenize) {
+        Some(Ok(tokens)) => tokens,
+        _ => return false,
+    };
+    tokens.windows(2).any(|pair| {
+        pair[0].text == Text::Word(":".to_string())
+            && pair[1].text == Text::Word("main".to_string())
+    })
+}
+
+// Whitespace separated words, "strings" and # comments to the end of the line
+fn tokenize(source: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = source.chars().peekable();
+    let mut line = 1;
+    while let Some(&c) = chars.peek() {
+        if c == '\n' {
+            line += 1;
+            chars.next();
+        } else if c.is_whitespace() {
+            chars.next();
+        } else if c == '#' {
+            while chars.next_if(|&c| c != '\n').is_some() {}
+        } else if c == '"' {
+            let start = line;
+            chars.next();
+            let mut text = String::new();
+            loop {
+                let c = match chars.next() {
+                    None => return Err(format!("line {}: string is never closed", start)),
+                    Some('"') => break,
+                    Some('\\') => match chars.next() {
+                        Some('n') => '\n',
+                        Some('t') => '\t',
+                        Some('r') => '\r',
+                        Some('0') => '\0',
+                        Some(c @ ('\\' | '"')) => c,
+                        _ => return Err(format!("line {}: unknown escape in string", line)),
+                    },
+                    Some(c) => c,
+                };
+                if c == '\n' {
+                    line += 1;
+                }
+                text.push(c);
+            }
+            tokens.push(Token {
+                text: Text::Quoted(text),
+                line: start,
+            });
+        } else {
+            let mut word = String::new();
+            while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+                word.push(c);
+            }
+            tokens.push(Token {
+                text: Text::Word(word),
+                line,
+            });
+        }
+    }
+    Ok(tokens)
+}
+
+// Decimal, 0x hex or 0b binary, optionally negative. Fractions only mean something to :calc
+fn number(word: &str) -> Option<f64> {
+    let (negative, digits) = match word.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, word),
+    };
+    let radix = |prefix: &str| {
+        digits
+            .strip_prefix(prefix)
+            .or_else(|| digits.strip_prefix(&prefix.to_ascii_uppercase()))
+    };
+    let value = if let Some(hex) = radix("0x") {
+        if hex.is_empty() || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+            return None;
+        }
+        i64::from_str_radix(hex, 16).ok()? as f64
+    } else if let Some(binary) = radix("0b") {
+        if binary.is_empty() || !binary.chars().all(|c| c == '0' || c == '1') {
+            return None;
+        }
+        i64::from_str_radix(binary, 2).ok()? as f64
+    } else if digits.starts_with(|c: char| c.is_ascii_digit())
+        && digits.chars().all(|c| c.is_ascii_digit() || c == '.')
+    {
+        digits.parse().ok()?
+    } else {
+        return None;
+    };
+    Some(if negative { -value } else { value })
+}
+
+// v0 to vF, not counting aliases
+fn register_name(word: &str) -> Option<u8> {
+    let mut chars = word.chars();
+    match (chars.next(), chars.next(), chars.next()) {
+        (Some('v' | 'V'), Some(digit), None) => digit.to_digit(16).map(|digit| digit as u8),
+        _ => None,
+    }
+}
+
+fn is_name(word: &str) -> bool {
+    !word.is_empty()
+        && !word.starts_with(':')
+        && number(word).is_none()
+        && register_name(word).is_none()
+        && !KEYWORDS.split_whitespace().any(|keyword| keyword == word)
+}
+
+fn unary(op: &str, value: f64) -> f64 {
+    match op {
+        "-" => -value,
+        "~" => !(value as i64) as f64,
+        "!" => f64::from(value == 0.0),
+        "sin" => value.sin(),
+        "cos" => value.cos(),
+        "tan" => value.tan(),
+        "exp" => value.exp(),
+        "log" => value.ln(),
+        "abs" => value.abs(),
+        "sqrt" => value.sqrt(),
+        "sign" if value == 0.0 => 0.0,
+        "sign" => value.signum(),
+        "ceil" => value.ceil(),
+        _ => value.floor(),
+    }
+}
+
+fn binary(op: &str, a: f64, b: f64) -> Result<f64, String> {
+    let (x, y) = (a as i64, b as i64);
+    Ok(match op {
+        "+" => a + b,
+        "-" => a - b,
+        "*" => a * b,
+        "/" => a / b,
+        "%" => a % b,
+        "&" => (x & y) as f64,
+        "|" => (x | y) as f64,
+        "^" => (x ^ y) as f64,
+        "<<" => x.checked_shl(y as u32).unwrap_or(0) as f64,
+        ">>" => x.checked_shr(y as u32).unwrap_or(0) as f64,
+        "pow" => a.powf(b),
+        "min" => a.min(b),
+        "max" => a.max(b),
+        "<" => f64::from(a < b),
+        "<=" => f64::from(a <= b),
+        "==" => f64::from(a == b),
+        "!=" => f64::from(a != b),
+        ">=" => f64::from(a >= b),
+        ">" => f64::from(a > b),
+        _ => return Err(format!("unknown operator {}", op)),
+    })
+}
+
+struct Assembler {
+    // Still to read, the next token last so macros can push their expansion on the end
+    tokens: Vec<Token>,
+    // Of the token being assembled, for errors
+    line: usize,
+    // Everything from 0x200 on
+    rom: Vec<u8>,
+    here: usize,
+    labels: HashMap<String, usize>,
+    constants: HashMap<String, f64>,
+    aliases: HashMap<String, u8>,
+    macros: HashMap<String, Macro>,
+    stringmodes: HashMap<String, StringMode>,
+    fixups: Vec<Fixup>,
+    blocks: Vec<Block>,
+    // 0x200 still holds room for the jump to main
+    jump_to_main: bool,
+    expansions: usize,
+}
+
+impl Assembler {
+    fn program(&mut self) -> Result<Vec<u8>, String> {
+        self.op(0x0000)?;
+        while let Some(token) = self.tokens.pop() {
+            self.line = token.line;
+            self.statement(token.text)?;
+        }
+        if !self.blocks.is_empty() {
+            return Err("an if ... begin or loop is never closed".to_string());
+        }
+
+        let main = *self
+            .labels
+            .get("main")
+            .ok_or("the program has no main label")?;
+        if self.jump_to_main {
+            self.set(START, 0x10);
+            self.patch(START, Patch::Address12, main as i64)?;
+        }
+        for fixup in std::mem::take(&mut self.fixups) {
+            self.line = fixup.line;
+            let address = *self
+                .labels
+                .get(&fixup.name)
+                .ok_or_else(|| format!("{} is never defined", fixup.name))?;
+            self.patch(fixup.at, fixup.patch, address as i64)?;
+        }
+        Ok(std::mem::take(&mut self.rom))
+    }
+
+    fn next(&mut self) -> Result<Token, String> {
+        let token = self
+            .tokens
+            .pop()
+            .ok_or("the program ends in the middle of a statement")?;
+        self.line = token.line;
+        Ok(token)
+    }
+
+    fn word(&mut self) -> Result<String, String> {
+        match self.next()?.text {
+            Text::Word(word) => Ok(word),
+            Text::Quoted(text) => Err(format!("unexpected string \"{}\"", text)),
+        }
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        match self.next()?.text {
+            Text::Quoted(text) => Ok(text),
+            Text::Word(word) => Err(format!("expected a string, got {}", word)),
+        }
+    }
+
+    fn peek_word(&self) -> Option<&str> {
+        match self.tokens.last() {
+            Some(Token {
+                text: Text::Word(word),
+                ..
+            }) => Some(word),
+            _ => None,
+        }
+    }
+
+    fn expect(&mut self, expected: &str) -> Result<(), String> {
+        let word = self.word()?;
+        if word == expected {
+            Ok(())
+        } else {
+            Err(format!("expected {}, got {}", expected, word))
+        }
+    }
+
+    fn name(&mut self) -> Result<String, String> {
+        let word = self.word()?;
+        if is_name(&word) {
+            Ok(word)
+        } else {
+            Err(format!("{} can't be used as a name", word))
+        }
+    }
+
+    fn register_index(&self, word: &str) -> Option<u8> {
+        self.aliases
+            .get(word)
+            .copied()
+            .or_else(|| register_name(word))
+    }
+
+    fn register(&mut self) -> Result<u8, String> {
+        let word = self.word()?;
+        self.register_index(&word)
+            .ok_or_else(|| format!("expected a register, got {}", word))
+    }
+
+    fn peek_register(&self) -> bool {
+        self.peek_word()
+            .is_some_and(|word| self.register_index(word).is_some())
+    }
+
+    fn emit(&mut self, byte: u8) -> Result<(), String> {
+        if self.here >= END {
+            return Err("the program doesn't fit in 64K".to_string());
+        }
+        self.set(self.here, byte);
+        self.here += 1;
+        Ok(())
+    }
+
+    fn op(&mut self, op: u16) -> Result<(), String> {
+        self.emit((op >> 8) as u8)?;
+        self.emit(op as u8)
+    }
+
+    fn set(&mut self, address: usize, byte: u8) {
+        let at = address - START;
+        if self.rom.len() <= at {
+            self.rom.resize(at + 1, 0);
+        }
+        self.rom[at] = byte;
+    }
+
+    fn patch(&mut self, at: usize, patch: Patch, address: i64) -> Result<(), String> {
+        let limit = match patch {
+            Patch::Address16 | Patch::LongHigh | Patch::Low => 0xFFFF,
+            Patch::Address12 | Patch::High(_) => 0xFFF,
+        };
+        if !(0..=limit).contains(&address) {
+            return Err(format!("address {:#X} is out of range", address));
+        }
+        let address = address as usize;
+        match patch {
+            Patch::Address12 => {
+                let high = self.rom[at - START] & 0xF0 | (address >> 8) as u8;
+                self.set(at, high);
+                self.set(at + 1, address as u8);
+            }
+            Patch::Address16 => {
+                self.set(at, (address >> 8) as u8);
+                self.set(at + 1, address as u8);
+            }
+            Patch::High(nibble) => self.set(at, nibble << 4 | (address >> 8) as u8),
+            Patch::LongHigh => self.set(at, (address >> 8) as u8),
+            Patch::Low => self.set(at, address as u8),
+        }
+        Ok(())
+    }
+
+    // Fill an address in now, or once its label turns up
+    fn place(&mut self, at: usize, patch: Patch, value: Value) -> Result<(), String> {
+        match value {
+            Value::Known(address) => self.patch(at, patch, address),
+            Value::Forward(name) => {
+                self.fixups.push(Fixup {
+                    at,
+                    patch,
+                    name,
+                    line: self.line,
+                });
+                Ok(())
+            }
+        }
+    }
+
+    fn lookup(&self, name: &str) -> Option<f64> {
+        number(name)
+            .or_else(|| self.constants.get(name).copied())
+            .or_else(|| self.labels.get(name).map(|&address| address as f64))
+            .or_else(|| (name == "HERE").then_some(self.here as f64))
+    }
+
+    // A number, a name, or a { calculation }
+    fn value(&mut self) -> Result<Value, String> {
+        if self.peek_word() == Some("{") {
+            return Ok(Value::Known(self.calculation()?.floor() as i64));
+        }
+        let word = self.word()?;
+        if let Some(value) = self.lookup(&word) {
+            return Ok(Value::Known(value.floor() as i64));
+        }
+        if !is_name(&word) || self.register_index(&word).is_some() {
+            return Err(format!("expected a number or a name, got {}", word));
+        }
+        Ok(Value::Forward(word))
+    }
+
+    fn known(&mut self) -> Result<i64, String> {
+        match self.value()? {
+            Value::Known(value) => Ok(value),
+            Value::Forward(name) => Err(format!("{} isn't defined", name)),
+        }
+    }
+
+    fn byte_value(&mut self) -> Result<u8, String> {
+        match self.known()? {
+            value @ -128..=255 => Ok(value as u8),
+            value => Err(format!("{} doesn't fit in a byte", value)),
+        }
+    }
+
+    fn nibble_value(&mut self) -> Result<u16, String> {
+        match self.known()? {
+            value @ 0..=15 => Ok(value as u16),
+            value => Err(format!("{} doesn't fit in 4 bits", value)),
+        }
+    }
+
+    fn operand(&mut self) -> Result<Operand, String> {
+        if self.peek_register() {
+            Ok(Operand::Register(self.register()?))
+        } else {
+            Ok(Operand::Byte(self.byte_value()?))
+        }
+    }
+
+    // { expression }, evaluated right to left with no precedence, as Octo does
+    fn calculation(&mut self) -> Result<f64, String> {
+        self.expect("{")?;
+        self.expression("}")
+    }
+
+    fn expression(&mut self, close: &str) -> Result<f64, String> {
+        let left = self.term()?;
+        let op = self.word()?;
+        if op == close {
+            return Ok(left);
+        }
+        let right = self.expression(close)?;
+        binary(&op, left, right)
+    }
+
+    fn term(&mut self) -> Result<f64, String> {
+        let word = self.word()?;
+        match word.as_str() {
+            "(" => self.expression(")"),
+            "strlen" => Ok(self.string()?.chars().count() as f64),
+            // A byte assembled so far
+            "@" => {
+                let address = self.term()? as i64;
+                Ok(usize::try_from(address - START as i64)
+                    .ok()
+                    .and_then(|at| self.rom.get(at))
+                    .map_or(0.0, |&byte| f64::from(byte)))
+            }
+            "PI" => Ok(consts::PI),
+            "E" => Ok(consts::E),
+            _ if UNARY.contains(&word.as_str()) => Ok(unary(&word, self.term()?)),
+            _ => self
+                .lookup(&word)
+                .ok_or_else(|| format!("{} isn't defined", word)),
+        }
+    }
+
+    fn statement(&mut self, text: Text) -> Result<(), String> {
+        let word = match text {
+            Text::Word(word) => word,
+            Text::Quoted(text) => return Err(format!("unexpected string \"{}\"", text)),
+        };
+        // Bare numbers are data
+        if let Some(value) = number(&word) {
+            return match value as i64 {
+                value @ -128..=255 => self.emit(value as u8),
+                value => Err(format!("{} doesn't fit in a byte", value)),
+            };
+        }
+
+        match word.as_str() {
+            ":" => {
+                let name = self.name()?;
+                if name == "main"
+                    && self.jump_to_main
+                    && self.rom.len() == 2
+                    && self.labels.is_empty()
+                {
+                    // Nothing comes before main, so it starts the program without the jump
+                    self.rom.clear();
+                    self.here = START;
+                    self.jump_to_main = false;
+                }
+                self.define(name, self.here)
+            }
+            // Labels the byte after the next instruction's first, for code that changes itself
+            ":next" => {
+                let name = self.name()?;
+                self.define(name, self.here + 1)
+            }
+            ":unpack" => self.unpack(),
+            ":breakpoint" | ":proto" => self.word().map(drop),
+            ":monitor" => {
+                self.next()?;
+                self.next().map(drop)
+            }
+            ":alias" => {
+                let name = self.name()?;
+                let register = self.register()?;
+                self.aliases.insert(name, register);
+                Ok(())
+            }
+            ":const" => {
+                let name = self.name()?;
+                let value = self.known()?;
+                if self.labels.contains_key(&name) || self.constants.contains_key(&name) {
+                    return Err(format!("{} is already defined", name));
+                }
+                self.constants.insert(name, value as f64);
+                Ok(())
+            }
+            // Unlike :const, a calculated constant can be worked out again
+            ":calc" => {
+                let name = self.name()?;
+                if self.labels.contains_key(&name) {
+                    return Err(format!("{} is already defined", name));
+                }
+                let value = self.calculation()?;
+                self.constants.insert(name, value);
+                Ok(())
+            }
+            ":byte" => {
+                let value = self.byte_value()?;
+                self.emit(value)
+            }
+            ":pointer" => {
+                let value = self.value()?;
+                let at = self.here;
+                self.op(0x0000)?;
+                self.place(at, Patch::Address16, value)
+            }
+            ":call" => self.address_op(0x2000),
+            ":org" => {
+                let address = self.known()?;
+                if !(START as i64..END as i64).contains(&address) {
+                    return Err(format!("can't assemble at {:#X}", address));
+                }
+                self.here = address as usize;
+                Ok(())
+            }
+            ":assert" => {
+                let message = match self.tokens.last() {
+                    Some(Token {
+                        text: Text::Quoted(_),
+                        ..
+                    }) => Some(self.string()?),
+                    _ => None,
+                };
+                if self.calculation()? != 0.0 {
+                    return Ok(());
+                }
+                Err(match message {
+                    Some(message) => format!("assertion failed: {}", message),
+                    None => "assertion failed".to_string(),
+                })
+            }
+            ":macro" => self.define_macro(),
+            ":stringmode" => self.define_stringmode(),
+            _ if word.starts_with(':') => Err(format!("unknown directive {}", word)),
+            _ if self.macros.contains_key(&word) => self.expand_macro(&word),
+            _ if self.stringmodes.contains_key(&word) => self.expand_stringmode(&word),
+
+            "return" | ";" => self.op(0x00EE),
+            "clear" => self.op(0x00E0),
+            "hires" => self.op(0x00FF),
+            "lores" => self.op(0x00FE),
+            "scroll-right" => self.op(0x00FB),
+            "scroll-left" => self.op(0x00FC),
+            "exit" => self.op(0x00FD),
+            "audio" => self.op(0xF002),
+            "scroll-down" => {
+                let n = self.nibble_value()?;
+                self.op(0x00C0 | n)
+            }
+            "scroll-up" => {
+                let n = self.nibble_value()?;
+                self.op(0x00D0 | n)
+            }
+            "plane" => {
+                let n = self.nibble_value()?;
+                self.op(0xF001 | n << 8)
+            }
+            "bcd" => self.register_op(0xF033),
+            "saveflags" => self.register_op(0xF075),
+            "loadflags" => self.register_op(0xF085),
+            "save" => self.save_load(0xF055, 0x5002),
+            "load" => self.save_load(0xF065, 0x5003),
+            "sprite" => {
+                let x = u16::from(self.register()?);
+                let y = u16::from(self.register()?);
+                let n = self.nibble_value()?;
+                self.op(0xD000 | x << 8 | y << 4 | n)
+            }
+            "jump" => self.address_op(0x1000),
+            "jump0" => self.address_op(0xB000),
+            "native" => self.address_op(0x0000),
+            "delay" | "buzzer" | "pitch" => {
+                self.expect(":=")?;
+                self.register_op(match word.as_str() {
+                    "delay" => 0xF015,
+                    "buzzer" => 0xF018,
+                    _ => 0xF03A,
+                })
+            }
+            "i" => self.i_statement(),
+            "if" => self.if_statement(),
+            "else" => match self.blocks.pop() {
+                Some(Block::If(jump)) => {
+                    let end = self.here;
+                    self.op(0x1000)?;
+                    self.patch(jump, Patch::Address12, self.here as i64)?;
+                    self.blocks.push(Block::Else(end));
+                    Ok(())
+                }
+                _ => Err("else without if ... begin".to_string()),
+            },
+            "end" => match self.blocks.pop() {
+                Some(Block::If(jump) | Block::Else(jump)) => {
+                    self.patch(jump, Patch::Address12, self.here as i64)
+                }
+                _ => Err("end without if ... begin".to_string()),
+            },
+            "loop" => {
+                self.blocks.push(Block::Loop {
+                    start: self.here,
+                    exits: Vec::new(),
+                });
+                Ok(())
+            }
+            "while" => {
+                let (_, skip_if_true) = self.condition()?;
+                self.op(skip_if_true)?;
+                let exit = self.here;
+                self.op(0x1000)?;
+                match self
+                    .blocks
+                    .iter_mut()
+                    .rev()
+                    .find(|block| matches!(block, Block::Loop { .. }))
+                {
+                    Some(Block::Loop { exits, .. }) => {
+                        exits.push(exit);
+                        Ok(())
+                    }
+                    _ => Err("while outside a loop".to_string()),
+                }
+            }
+            "again" => match self.blocks.pop() {
+                Some(Block::Loop { start, exits }) => {
+                    let at = self.here;
+                    self.op(0x1000)?;
+                    self.patch(at, Patch::Address12, start as i64)?;
+                    for exit in exits {
+                        self.patch(exit, Patch::Address12, self.here as i64)?;
+                    }
+                    Ok(())
+                }
+                _ => Err("again without loop".to_string()),
+            },
+            _ => match self.register_index(&word) {
+                Some(x) => self.register_statement(x),
+                // Anything else names a subroutine to call, defined now or further down
+                None if is_name(&word) => {
+                    let value = match self.labels.get(&word) {
+                        Some(&address) => Value::Known(address as i64),
+                        None => Value::Forward(word),
+                    };
+                    let at = self.here;
+                    self.op(0x2000)?;
+                    self.place(at, Patch::Address12, value)
+                }
+                None => Err(format!("unexpected {}", word)),
+            },
+        }
+    }
+
+    fn define(&mut self, name: String, address: usize) -> Result<(), String> {
+        if self.labels.contains_key(&name) || self.constants.contains_key(&name) {
+            return Err(format!("{} is already defined", name));
+        }
+        self.labels.insert(name, address);
+        Ok(())
+    }
+
+    fn address_op(&mut self, op: u16) -> Result<(), String> {
+        let value = self.value()?;
+        let at = self.here;
+        self.op(op)?;
+        self.place(at, Patch::Address12, value)
+    }
+
+    fn register_op(&mut self, op: u16) -> Result<(), String> {
+        let x = u16::from(self.register()?);
+        self.op(op | x << 8)
+    }
+
+    // save vX, or XO-CHIP's save vX - vY
+    fn save_load(&mut self, op: u16, range: u16) -> Result<(), String> {
+        let x = u16::from(self.register()?);
+        if self.peek_word() != Some("-") {
+            return self.op(op | x << 8);
+        }
+        self.next()?;
+        let y = u16::from(self.register()?);
+        self.op(range | x << 8 | y << 4)
+    }
+
+    fn register_statement(&mut self, x: u8) -> Result<(), String> {
+        let x = u16::from(x) << 8;
+        let op = self.word()?;
+        let operand = match (op.as_str(), self.peek_word()) {
+            (":=", Some("random")) => {
+                self.next()?;
+                let mask = self.byte_value()?;
+                return self.op(0xC000 | x | u16::from(mask));
+            }
+            (":=", Some("key")) => {
+                self.next()?;
+                return self.op(0xF00A | x);
+            }
+            (":=", Some("delay")) => {
+                self.next()?;
+                return self.op(0xF007 | x);
+            }
+            _ => self.operand()?,
+        };
+        match (op.as_str(), operand) {
+            (":=", Operand::Byte(n)) => self.op(0x6000 | x | u16::from(n)),
+            ("+=", Operand::Byte(n)) => self.op(0x7000 | x | u16::from(n)),
+            ("-=", Operand::Byte(n)) => self.op(0x7000 | x | u16::from(n.wrapping_neg())),
+            (_, Operand::Register(y)) => {
+                let logic = match op.as_str() {
+                    ":=" => 0x0,
+                    "|=" => 0x1,
+                    "&=" => 0x2,
+                    "^=" => 0x3,
+                    "+=" => 0x4,
+                    "-=" => 0x5,
+                    ">>=" => 0x6,
+                    "=-" => 0x7,
+                    "<<=" => 0xE,
+                    _ => return Err(format!("unknown operator {}", op)),
+                };
+                self.op(0x8000 | x | u16::from(y) << 4 | logic)
+            }
+            _ => Err(format!("{} needs a register on the right", op)),
+        }
+    }
+
+    fn i_statement(&mut self) -> Result<(), String> {
+        let op = self.word()?;
+        match (op.as_str(), self.peek_word()) {
+            ("+=", _) => self.register_op(0xF01E),
+            (":=", Some("hex")) => {
+                self.next()?;
+                self.register_op(0xF029)
+            }
+            (":=", Some("bighex")) => {
+                self.next()?;
+                self.register_op(0xF030)
+            }
+            (":=", Some("long")) => {
+                self.next()?;
+                let value = self.value()?;
+                self.op(0xF000)?;
+                let at = self.here;
+                self.op(0x0000)?;
+                self.place(at, Patch::Address16, value)
+            }
+            (":=", _) => self.address_op(0xA000),
+            _ => Err(format!("unknown operator {} for i", op)),
+        }
+    }
+
+    // :unpack N label puts N and the label's 12 bits into v0 and v1, or :unpack long label
+    // its 16 bits. :alias unpack-hi and unpack-lo pick other registers
+    fn unpack(&mut self) -> Result<(), String> {
+        let long = self.peek_word() == Some("long");
+        let high = if long {
+            self.next()?;
+            Patch::LongHigh
+        } else {
+            Patch::High(self.nibble_value()? as u8)
+        };
+        let value = self.value()?;
+        let register = |name: &str, default: u16| {
+            self.aliases
+                .get(name)
+                .map_or(default, |&register| u16::from(register))
+        };
+        let (hi, lo) = (register("unpack-hi", 0), register("unpack-lo", 1));
+        let at = self.here;
+        self.op(0x6000 | hi << 8)?;
+        self.op(0x6000 | lo << 8)?;
+        self.place(at + 1, high, value.clone())?;
+        self.place(at + 3, Patch::Low, value)
+    }
+
+    // The skips for a condition, when it's false and when it's true. The comparisons Chip-8 has
+    // no skip for are worked out in VF first
+    fn condition(&mut self) -> Result<(u16, u16), String> {
+        let register = self.register()?;
+        let x = u16::from(register) << 8;
+        let op = self.word()?;
+        match op.as_str() {
+            "key" => return Ok((0xE0A1 | x, 0xE09E | x)),
+            "-key" => return Ok((0xE09E | x, 0xE0A1 | x)),
+            _ => {}
+        }
+        let right = self.operand()?;
+        let vf_is =
+            |flag: u16| -> Result<(u16, u16), String> { Ok((0x4F00 | flag, 0x3F00 | flag)) };
+        match (op.as_str(), right) {
+            ("==", Operand::Register(y)) => {
+                let y = u16::from(y) << 4;
+                Ok((0x9000 | x | y, 0x5000 | x | y))
+            }
+            ("!=", Operand::Register(y)) => {
+                let y = u16::from(y) << 4;
+                Ok((0x5000 | x | y, 0x9000 | x | y))
+            }
+            ("==", Operand::Byte(n)) => Ok((0x4000 | x | u16::from(n), 0x3000 | x | u16::from(n))),
+            ("!=", Operand::Byte(n)) => Ok((0x3000 | x | u16::from(n), 0x4000 | x | u16::from(n))),
+            ("<" | ">=", _) => {
+                self.at_least(Operand::Register(register), right)?;
+                vf_is(u16::from(op == ">="))
+            }
+            (">" | "<=", _) => {
+                self.at_least(right, Operand::Register(register))?;
+                vf_is(u16::from(op == "<="))
+            }
+            _ => Err(format!("unknown comparison {}", op)),
+        }
+    }
+
+    // Leave VF set to 1 when a >= b and 0 otherwise, by subtracting and keeping the borrow flag
+    fn at_least(&mut self, a: Operand, b: Operand) -> Result<(), String> {
+        match (a, b) {
+            (Operand::Register(a), Operand::Byte(b)) => {
+                self.op(0x6F00 | u16::from(b))?;
+                self.op(0x8F07 | u16::from(a) << 4)
+            }
+            (a, Operand::Register(b)) => {
+                match a {
+                    Operand::Register(a) => self.op(0x8F00 | u16::from(a) << 4)?,
+                    Operand::Byte(a) => self.op(0x6F00 | u16::from(a))?,
+                }
+                self.op(0x8F05 | u16::from(b) << 4)
+            }
+            _ => Err("can't compare two numbers".to_string()),
+        }
+    }
+
+    fn if_statement(&mut self) -> Result<(), String> {
+        let (skip_if_false, skip_if_true) = self.condition()?;
+        match self.word()?.as_str() {
+            "then" => self.op(skip_if_false),
+            "begin" => {
+                self.op(skip_if_true)?;
+                self.blocks.push(Block::If(self.here));
+                self.op(0x1000)
+            }
+            other => Err(format!("expected then or begin, got {}", other)),
+        }
+    }
+
+    // Tokens up to the } matching a { just read
+    fn body(&mut self) -> Result<Vec<Token>, String> {
+        let mut depth = 0;
+        let mut body = Vec::new();
+        loop {
+            let token = self.next()?;
+            match &token.text {
+                Text::Word(word) if word == "{" => depth += 1,
+                Text::Word(word) if word == "}" => {
+                    if depth == 0 {
+                        return Ok(body);
+                    }
+                    depth -= 1;
+                }
+                _ => {}
+            }
+            body.push(token);
+        }
+    }
+
+    fn define_macro(&mut self) -> Result<(), String> {
+        let name = self.name()?;
+        let mut params = Vec::new();
+        loop {
+            let word = self.word()?;
+            if word == "{" {
+                break;
+            }
+            params.push(word);
+        }
+        let body = self.body()?;
+        self.macros.insert(
+            name,
+            Macro {
+                params,
+                body,
+                calls: 0,
+            },
+        );
+        Ok(())
+    }
+
+    fn define_stringmode(&mut self) -> Result<(), String> {
+        let name = self.name()?;
+        let alphabet = self.string()?;
+        self.expect("{")?;
+        let body = self.body()?;
+        self.stringmodes
+            .entry(name)
+            .or_default()
+            .alphabets
+            .push((alphabet, body));
+        Ok(())
+    }
+
+    // The body with names swapped for what they stand for, read next
+    fn substitute(
+        &mut self,
+        body: &[Token],
+        names: &HashMap<String, Text>,
+        expansion: &mut Vec<Token>,
+    ) {
+        expansion.extend(body.iter().map(|token| Token {
+            text: match &token.text {
+                Text::Word(word) => names.get(word).unwrap_or(&token.text).clone(),
+                text => text.clone(),
+            },
+            line: self.line,
+        }));
+    }
+
+    fn expanded(&mut self, expansion: Vec<Token>) -> Result<(), String> {
+        self.expansions += 1;
+        if self.expansions > MAX_EXPANSIONS {
+            return Err("macros keep expanding into each other".to_string());
+        }
+        self.tokens.extend(expansion.into_iter().rev());
+        Ok(())
+    }
+
+    fn expand_macro(&mut self, name: &str) -> Result<(), String> {
+        let definition = &self.macros[name];
+        let (params, body, calls) = (
+            definition.params.clone(),
+            definition.body.clone(),
+            definition.calls,
+        );
+        let mut names = HashMap::new();
+        names.insert("CALLS".to_string(), Text::Word(calls.to_string()));
+        for param in params {
+            names.insert(param, self.next()?.text);
+        }
+        self.macros.get_mut(name).unwrap().calls += 1;
+
+        let mut expansion = Vec::new();
+        self.substitute(&body, &names, &mut expansion);
+        self.expanded(expansion)
+    }
+
+    // The body once per character, with CHAR as its code, INDEX as its place in the string
+    // and VALUE as its place in the alphabet
+    fn expand_stringmode(&mut self, name: &str) -> Result<(), String> {
+        let text = self.string()?;
+        let mode = &self.stringmodes[name];
+        let calls = mode.calls;
+        let mut bodies = Vec::new();
+        for (index, c) in text.chars().enumerate() {
+            let (value, body) = mode
+                .alphabets
+                .iter()
+                .find_map(|(alphabet, body)| {
+                    let value = alphabet.chars().position(|letter| letter == c)?;
+                    Some((value, body.clone()))
+                })
+                .ok_or_else(|| format!("{:?} isn't in the alphabet of {}", c, name))?;
+            bodies.push((c as u32 as usize, index, value, body));
+        }
+        self.stringmodes.get_mut(name).unwrap().calls += 1;
+
+        let mut expansion = Vec::new();
+        for (code, index, value, body) in bodies {
+            let names: HashMap<String, Text> = [
+                ("CHAR", code),
+                ("INDEX", index),
+                ("VALUE", value),
+                ("CALLS", calls),
+            ]
+            .into_iter()
+            .map(|(name, value)| (name.to_string(), Text::Word(value.to_string())))
+            .collect();
+            self.substitute(&body, &names, &mut expansion);
+        }
+        self.expanded(expansion)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn main_first_needs_no_jump() {
+        let source = "
+            : main
+                clear
+                v0 := 5
+                v1 += 2
+                i := dot
+                sprite v0 v1 5
+                loop again
+            : dot
+                0xF0 0b10010000
+        ";
+        assert_eq!(
+            assemble(source).unwrap(),
+            [0x00, 0xE0, 0x60, 0x05, 0x71, 0x02, 0xA2, 0x0C, 0xD0, 0x15, 0x12, 0x0A, 0xF0, 0x90]
+        );
+    }
+
+    #[test]
+    fn data_before_main_is_jumped_over() {
+        let source = ": dot 0x80 # a single pixel\n: main i := dot jump main";
+        assert_eq!(
+            assemble(source).unwrap(),
+            [0x12, 0x03, 0x80, 0xA2, 0x02, 0x12, 0x03]
+        );
+    }
+
+    #[test]
+    fn conditions_and_loops() {
+        let source = "
+            : main
+                if v0 == 3 then v1 := 1
+                if v0 != v2 then v1 := 2
+                if v3 key then clear
+                if v4 -key begin
+                    v5 := 1
+                else
+                    v5 := 2
+                end
+                loop
+                    v6 += 1
+                    while v6 != 10
+                again
+        ";
+        assert_eq!(
+            assemble(source).unwrap(),
+            [
+                0x40, 0x03, 0x61, 0x01, 0x50, 0x20, 0x61, 0x02, 0xE3, 0xA1, 0x00, 0xE0, 0xE4, 0xA1,
+                0x12, 0x14, 0x65, 0x01, 0x12, 0x16, 0x65, 0x02, 0x76, 0x01, 0x46, 0x0A, 0x12, 0x1E,
+                0x12, 0x16
+            ]
+        );
+    }
+
+    #[test]
+    fn comparisons_go_through_vf() {
+        let source = ": main if v1 < 5 then v2 := 0 if v1 > v2 then v2 := 0 if v3 <= 7 then return";
+        assert_eq!(
+            assemble(source).unwrap(),
+            [
+                0x6F, 0x05, 0x8F, 0x17, 0x4F, 0x00, 0x62, 0x00, 0x8F, 0x20, 0x8F, 0x15, 0x4F, 0x00,
+                0x62, 0x00, 0x6F, 0x07, 0x8F, 0x35, 0x4F, 0x01, 0x00, 0xEE
+            ]
+        );
+    }
+
+    #[test]
+    fn registers_and_i() {
+        let source = "
+            : main
+                v1 := v2 v1 |= v2 v1 &= v2 v1 ^= v2 v1 += v2 v1 -= v2
+                v1 >>= v2 v1 =- v2 v1 <<= v2 v1 -= 1
+                va := random 0x3F vb := key vc := delay
+                delay := v1 buzzer := v2 i += v3 i := hex v4 i := bighex v5
+                bcd v6 save v7 load v8 save v1 - v3 load v2 - v4
+                saveflags v9 loadflags va
+                hires lores scroll-down 4 scroll-up 2 scroll-left scroll-right
+                plane 3 audio pitch := v1 exit jump0 0x300 :call 0x400
+        ";
+        let rom = assemble(source).unwrap();
+        let ops: Vec<u16> = rom
+            .chunks(2)
+            .map(|pair| u16::from_be_bytes([pair[0], pair[1]]))
+            .collect();
+        assert_eq!(
+            ops,
+            [
+                0x8120, 0x8121, 0x8122, 0x8123, 0x8124, 0x8125, 0x8126, 0x8127, 0x812E, 0x71FF,
+                0xCA3F, 0xFB0A, 0xFC07, 0xF115, 0xF218, 0xF31E, 0xF429, 0xF530, 0xF633, 0xF755,
+                0xF865, 0x5132, 0x5243, 0xF975, 0xFA85, 0x00FF, 0x00FE, 0x00C4, 0x00D2, 0x00FC,
+                0x00FB, 0xF301, 0xF002, 0xF13A, 0x00FD, 0xB300, 0x2400
+            ]
+        );
+    }
+
+    #[test]
+    fn directives_macros_and_calculations() {
+        let source = "
+            :const SPEED 3
+            :calc DOUBLE { SPEED * 2 }
+            :alias x v4
+            :macro bump register amount { register += amount :byte CALLS }
+            : main
+                x := DOUBLE
+                bump x 1
+                bump v5 SPEED
+                :byte { 2 * 3 + 1 }
+                :unpack 0xA data
+                :next target v0 := 0
+                i := long data
+                :assert \"next labels the byte after\" { target == 0x20E }
+                :org 0x220
+            : data
+                1 -1
+        ";
+        let mut expected = vec![
+            0x64, 0x06, 0x74, 0x01, 0x00, 0x75, 0x03, 0x01, 0x08, 0x60, 0xA2, 0x61, 0x20, 0x60,
+            0x00, 0xF0, 0x00, 0x02, 0x20,
+        ];
+        expected.resize(0x20, 0);
+        expected.extend_from_slice(&[0x01, 0xFF]);
+        assert_eq!(assemble(source).unwrap(), expected);
+    }
+
+    #[test]
+    fn stringmodes_expand_each_character() {
+        let source = "
+            :stringmode text \"ABC\" { :byte { VALUE + 1 } :byte INDEX }
+            :stringmode text \" \" { :byte CHAR }
+            : main text \"CA B\"
+        ";
+        assert_eq!(assemble(source).unwrap(), [3, 0, 1, 1, 32, 2, 3]);
+    }
+
+    #[test]
+    fn forward_calls_are_filled_in() {
+        let source = ": main draw draw : draw return";
+        assert_eq!(
+            assemble(source).unwrap(),
+            [0x22, 0x04, 0x22, 0x04, 0x00, 0xEE]
+        );
+    }
+
+    #[test]
+    fn errors_name_the_line() {
+        let error = |source: &str| assemble(source).unwrap_err();
+        assert_eq!(
+            error("clear\nreturn"),
+            "line 2: the program has no main label"
+        );
+        assert_eq!(error(": main\n\ndraw"), "line 3: draw is never defined");
+        assert_eq!(
+            error(": main\nv0 := 256"),
+            "line 2: 256 doesn't fit in a byte"
+        );
+        assert_eq!(
+            error(": main loop"),
+            "line 1: an if ... begin or loop is never closed"
+        );
+        assert_eq!(error(": main else"), "line 1: else without if ... begin");
+        assert_eq!(error(": main : main"), "line 1: main is already defined");
+        assert_eq!(
+            error(": main\n:assert { 1 == 2 }"),
+            "line 2: assertion failed"
+        );
+        assert_eq!(error(": main :foo"), "line 1: unknown directive :foo");
+        assert_eq!(error("\n: main \"text"), "line 2: string is never closed");
+        assert_eq!(
+            error(":macro forever { forever }\n: main forever"),
+            "line 2: macros keep expanding into each other"
+        );
+    }
+
+    #[test]
+    fn source_is_told_by_its_main_label() {
+        assert!(is_source(b"# game\n: main\n  clear\n"));
+        assert!(!is_source(b"00e0 a20a"));
+        assert!(!is_source(b"main: CLS"));
+    }
+}
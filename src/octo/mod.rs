@@ -0,0 +1,295 @@
+use crate::config::Settings;
+use crate::json::{self, Value};
+use crate::palette::{self, Palette};
+use crate::variant::Variant;
+
+pub mod assembler;
+
+// Octo shares programs as "cartridge" GIFs. The low two bits of every pixel's palette index,
+// frame after frame, spell out a 4 byte big endian length and then that much UTF-8 JSON:
+// {"program": "<Octo source>", "options": {"tickrate": 20, "shiftQuirks": false, ...}}
+#[derive(Debug)]
+pub struct Cartridge {
+    // The program as Octo source text
+    pub program: String,
+    pub tickrate: Option<u32>,
+    pub palette: Option<Palette>,
+    // Guessed from the memory size the program was written for
+    pub platform: Option<Variant>,
+    // Every quirk Octo has an option for that CJ-8 emulates, by name
+    pub quirks: Vec<(&'static str, bool)>,
+}
+
+impl Cartridge {
+    // The cartridge's options as the layer above the ROM database
+    pub fn settings(&self) -> Settings {
+        let mut settings = Settings::default();
+        settings.machine.platform = self.platform;
+        if !self.quirks.is_empty() {
+            let spec: Vec<String> = self
+                .quirks
+                .iter()
+                .map(|(name, on)| format!("{}={}", name, if *on { "on" } else { "off" }))
+                .collect();
+            settings.machine.quirks.push(spec.join(","));
+        }
+        settings.machine.ipf = self.tickrate;
+        settings.palette = self.palette;
+        settings
+    }
+
+    // The options on one line, e.g. "schip, 20 IPF, clip=on, colors 000000,ffffff"
+    pub fn describe(&self) -> String {
+        let mut options = Vec::new();
+        if let Some(platform) = self.platform {
+            options.push(platform.to_string());
+        }
+        if let Some(tickrate) = self.tickrate {
+            options.push(format!("{} IPF", tickrate));
+        }
+        options.extend(
+            self.quirks
+                .iter()
+                .map(|(name, on)| format!("{}={}", name, if *on { "on" } else { "off" })),
+        );
+        if let Some(palette) = self.palette {
+            let hex = |[r, g, b]: [u8; 3]| format!("{:02x}{:02x}{:02x}", r, g, b);
+            options.push(format!(
+                "colors {},{}",
+                hex(palette.background),
+                hex(palette.foreground)
+            ));
+        }
+        options.join(", ")
+    }
+}
+
+pub fn is_gif(data: &[u8]) -> bool {
+    data.starts_with(b"GIF87a") || data.starts_with(b"GIF89a")
+}
+
+pub fn decode(data: &[u8]) -> Result<Cartridge, String> {
+    let payload = payload(data)?;
+    // Any other GIF decodes to noise
+    let value = String::from_utf8(payload)
+        .ok()
+        .and_then(|text| json::parse(&text).ok())
+        .ok_or("not an Octo cartridge, the image holds no program")?;
+    let program = value
+        .get("program")
+        .and_then(Value::as_str)
+        .ok_or("not an Octo cartridge, the image holds no program")?
+        .to_string();
+    let options = value.get("options");
+    let option = |name: &str| options.and_then(|options| options.get(name));
+
+    // Octo's quirk options mostly have the opposite sense to ours, vBlank and vfOrder aren't emulated
+    let mut quirks = Vec::new();
+    for (option_name, quirk, inverted) in [
+        ("shiftQuirks", "shift-vy", true),
+        ("loadStoreQuirks", "memory-increment", true),
+        ("clipQuirks", "clip", false),
+        ("jumpQuirks", "jump-vx", false),
+        ("logicQuirks", "vf-reset", false),
+    ] {
+        if let Some(on) = option(option_name).and_then(Value::as_bool) {
+            quirks.push((quirk, on != inverted));
+        }
+    }
+
+    let color = |name: &str| {
+        option(name)
+            .and_then(Value::as_str)
+            .and_then(|color| palette::parse_color(color).ok())
+    };
+    let palette = match (color("backgroundColor"), color("fillColor")) {
+        (Some(background), Some(foreground)) => Some(Palette {
+            background,
+            foreground,
+        }),
+        _ => None,
+    };
+
+    // Octo's presets set maxSize to 3216 (VIP), 3583 (SCHIP), 3584 (Octo) or 65024 (XO-CHIP)
+    let platform = option("maxSize")
+        .and_then(Value::as_f64)
+        .map(|size| match size as usize {
+            size if size > Variant::Chip8.max_rom_size() => Variant::XoChip,
+            3583 => Variant::Schip,
+            _ => Variant::Chip8,
+        });
+
+    Ok(Cartridge {
+        program,
+        tickrate: option("tickrate")
+            .and_then(Value::as_f64)
+            .map(|tickrate| tickrate as u32)
+            .filter(|&tickrate| tickrate > 0),
+        palette,
+        platform,
+        quirks,
+    })
+}
+
+// The bytes hidden in the image, four pixels to a byte with the high bits first
+fn payload(data: &[u8]) -> Result<Vec<u8>, String> {
+    let mut options = gif::DecodeOptions::new();
+    options.set_color_output(gif::ColorOutput::Indexed);
+    let mut decoder = options
+        .read_info(data)
+        .map_err(|e| format!("invalid GIF: {}", e))?;
+
+    let mut bytes = Vec::new();
+    let mut byte = 0u8;
+    let mut pixels = 0;
+    let mut size = None;
+    while let Some(frame) = decoder
+        .read_next_frame()
+        .map_err(|e| format!("invalid GIF: {}", e))?
+    {
+        for &index in frame.buffer.iter() {
+            byte = byte << 2 | (index & 3);
+            pixels += 1;
+            if pixels % 4 != 0 {
+                continue;
+            }
+            bytes.push(byte);
+            if size.is_none() && bytes.len() == 4 {
+                let length = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize;
+                size = Some(length);
+                bytes.clear();
+            }
+            if size == Some(bytes.len()) {
+                return Ok(bytes);
+            }
+        }
+    }
+    Err("not an Octo cartridge, the image ends before its payload".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::borrow::Cow;
+
+    // Room for 1020 bytes of payload
+    const WIDTH: u16 = 64;
+
+    // A GIF whose frames hold the pixels in order, each palette index with other bits set above the
+    // low two the way Octo's colors do
+    fn gif(frames: &[Vec<u8>]) -> Vec<u8> {
+        let palette: Vec<u8> = (0..=255).flat_map(|i| [i, i, i]).collect();
+        let mut data = Vec::new();
+        {
+            let mut encoder = gif::Encoder::new(&mut data, WIDTH, WIDTH, &palette).unwrap();
+            for pixels in frames {
+                let mut buffer: Vec<u8> = pixels.iter().map(|pixel| 0x40 | pixel).collect();
+                buffer.resize(usize::from(WIDTH) * usize::from(WIDTH), 0x40);
+                let frame = gif::Frame {
+                    width: WIDTH,
+                    height: WIDTH,
+                    buffer: Cow::Owned(buffer),
+                    ..gif::Frame::default()
+                };
+                encoder.write_frame(&frame).unwrap();
+            }
+        }
+        data
+    }
+
+    // The length and payload as two bit pixels, high bits first
+    fn pixels(payload: &[u8]) -> Vec<u8> {
+        let mut bytes = (payload.len() as u32).to_be_bytes().to_vec();
+        bytes.extend_from_slice(payload);
+        bytes
+            .iter()
+            .flat_map(|byte| [byte >> 6, byte >> 4 & 3, byte >> 2 & 3, byte & 3])
+            .collect()
+    }
+
+    fn cartridge(payload: &[u8]) -> Vec<u8> {
+        gif(&[pixels(payload)])
+    }
+
+    #[test]
+    fn payload_comes_back_out_of_the_image() {
+        let bytes: Vec<u8> = (0..=255).cycle().take(1000).collect();
+        let data = cartridge(&bytes);
+        assert!(is_gif(&data));
+        assert_eq!(payload(&data), Ok(bytes.clone()));
+
+        // Too much for one frame, carried on into the next
+        let bytes: Vec<u8> = (0..=255).rev().cycle().take(1500).collect();
+        let mut first = pixels(&bytes);
+        let second = first.split_off(usize::from(WIDTH) * usize::from(WIDTH));
+        assert_eq!(payload(&gif(&[first, second])), Ok(bytes));
+    }
+
+    #[test]
+    fn cartridge_options_are_decoded() {
+        let json = r##"{"program": ": main\n  loop again", "options": {
+            "tickrate": 30, "fillColor": "#FFCC00", "backgroundColor": "#996600",
+            "shiftQuirks": true, "loadStoreQuirks": false, "clipQuirks": true,
+            "jumpQuirks": false, "logicQuirks": true, "vBlankQuirks": true, "maxSize": 3583
+        }}"##;
+        let cartridge = decode(&cartridge(json.as_bytes())).unwrap();
+        assert_eq!(cartridge.program, ": main\n  loop again");
+        assert_eq!(cartridge.tickrate, Some(30));
+        let palette = cartridge.palette.unwrap();
+        assert_eq!(palette.background, [0x99, 0x66, 0x00]);
+        assert_eq!(palette.foreground, [0xFF, 0xCC, 0x00]);
+        assert_eq!(cartridge.platform, Some(Variant::Schip));
+        assert_eq!(
+            cartridge.quirks,
+            [
+                ("shift-vy", false),
+                ("memory-increment", true),
+                ("clip", true),
+                ("jump-vx", false),
+                ("vf-reset", true)
+            ]
+        );
+        assert_eq!(
+            cartridge.describe(),
+            "schip, 30 IPF, shift-vy=off, memory-increment=on, clip=on, jump-vx=off, vf-reset=on, colors 996600,ffcc00"
+        );
+    }
+
+    #[test]
+    fn max_size_picks_the_platform() {
+        for (size, platform) in [
+            (3216, Variant::Chip8),
+            (3583, Variant::Schip),
+            (3584, Variant::Chip8),
+            (65024, Variant::XoChip),
+        ] {
+            let json = format!(r#"{{"program": "", "options": {{"maxSize": {}}}}}"#, size);
+            let cartridge = decode(&cartridge(json.as_bytes())).unwrap();
+            assert_eq!(cartridge.platform, Some(platform));
+            assert!(cartridge.quirks.is_empty());
+        }
+    }
+
+    #[test]
+    fn other_images_are_not_cartridges() {
+        assert_eq!(
+            decode(&gif(&[vec![]])).err().unwrap(),
+            "not an Octo cartridge, the image holds no program"
+        );
+        assert_eq!(
+            decode(&cartridge(b"{\"options\": {}}")).err().unwrap(),
+            "not an Octo cartridge, the image holds no program"
+        );
+        // Claims more than the image has room for
+        let mut short = pixels(b"{}");
+        short[..16].copy_from_slice(&pixels(&[0; 4096])[..16]);
+        assert_eq!(
+            decode(&gif(&[short])).err().unwrap(),
+            "not an Octo cartridge, the image ends before its payload"
+        );
+        assert!(decode(b"GIF89a")
+            .err()
+            .unwrap()
+            .starts_with("invalid GIF: "));
+    }
+}
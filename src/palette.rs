@@ -0,0 +1,77 @@
+use std::str::FromStr;
+
+// Colors used to turn the monochrome framebuffer into RGB pixels
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Palette {
+    pub background: [u8; 3],
+    pub foreground: [u8; 3],
+}
+
+impl Default for Palette {
+    fn default() -> Self {
+        Palette {
+            background: [0, 0, 0],
+            foreground: [255, 255, 255],
+        }
+    }
+}
+
+impl Palette {
+    // Look up the RGB color of a framebuffer cell
+    pub fn color(&self, pixel: u16) -> [u8; 3] {
+        if pixel == 0 {
+            self.background
+        } else {
+            self.foreground
+        }
+    }
+}
+
+impl FromStr for Palette {
+    type Err = String;
+
+    // A preset name or two hex colors as "BACKGROUND,FOREGROUND", e.g. "000000,33ff33"
+    fn from_str(s: &str) -> Result<Palette, String> {
+        let preset = match s.to_ascii_lowercase().as_str() {
+            "default" | "classic" => Some(Palette::default()),
+            "amber" => Some(Palette {
+                background: [0x00, 0x00, 0x00],
+                foreground: [0xff, 0xb0, 0x00],
+            }),
+            "green" => Some(Palette {
+                background: [0x00, 0x00, 0x00],
+                foreground: [0x33, 0xff, 0x33],
+            }),
+            "lcd" => Some(Palette {
+                background: [0x9b, 0xbc, 0x0f],
+                foreground: [0x0f, 0x38, 0x0f],
+            }),
+            "octo" => Some(Palette {
+                background: [0x99, 0x66, 0x00],
+                foreground: [0xff, 0xcc, 0x00],
+            }),
+            _ => None,
+        };
+        if let Some(palette) = preset {
+            return Ok(palette);
+        }
+
+        let (background, foreground) = s
+            .split_once(',')
+            .ok_or_else(|| format!("unknown palette: {}", s))?;
+        Ok(Palette {
+            background: parse_color(background)?,
+            foreground: parse_color(foreground)?,
+        })
+    }
+}
+
+// RRGGBB with an optional leading #
+pub fn parse_color(s: &str) -> Result<[u8; 3], String> {
+    let hex = s.trim().trim_start_matches('#');
+    let value = u32::from_str_radix(hex, 16)
+        .ok()
+        .filter(|_| hex.len() == 6)
+        .ok_or_else(|| format!("invalid color: {}", s))?;
+    Ok([(value >> 16) as u8, (value >> 8) as u8, value as u8])
+}
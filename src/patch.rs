@@ -0,0 +1,409 @@
+use crate::hash;
+use std::fmt;
+
+// IPS records can't start at this offset, it reads as the end marker
+const IPS_EOF: usize = 0x454F46;
+const IPS_MAX_SIZE: usize = 0xFFFFFF;
+// Equal bytes between two changes that are cheaper to repeat than to start a new IPS record for
+const IPS_MERGE_GAP: usize = 5;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PatchFormat {
+    Ips,
+    // Beat patches carry CRC-32s of the ROM they're for, the result and themselves
+    Bps,
+}
+
+impl fmt::Display for PatchFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PatchFormat::Ips => write!(f, "IPS"),
+            PatchFormat::Bps => write!(f, "BPS"),
+        }
+    }
+}
+
+// Patch a ROM, the format is told from the patch's header
+pub fn apply(rom: &[u8], patch: &[u8]) -> Result<(Vec<u8>, PatchFormat), String> {
+    if patch.starts_with(b"PATCH") {
+        Ok((apply_ips(rom, patch)?, PatchFormat::Ips))
+    } else if patch.starts_with(b"BPS1") {
+        Ok((apply_bps(rom, patch)?, PatchFormat::Bps))
+    } else {
+        Err("not an IPS or BPS patch".to_string())
+    }
+}
+
+pub fn create(source: &[u8], target: &[u8], format: PatchFormat) -> Result<Vec<u8>, String> {
+    match format {
+        PatchFormat::Ips => create_ips(source, target),
+        PatchFormat::Bps => Ok(create_bps(source, target)),
+    }
+}
+
+fn apply_ips(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, String> {
+    let truncated = || "IPS patch ends early".to_string();
+    let number = |at: usize, size: usize| {
+        patch
+            .get(at..at + size)
+            .map(|bytes| bytes.iter().fold(0, |n, &b| n << 8 | usize::from(b)))
+            .ok_or_else(truncated)
+    };
+
+    let mut out = rom.to_vec();
+    let mut at = 5;
+    loop {
+        if patch.get(at..at + 3) == Some(b"EOF") {
+            at += 3;
+            break;
+        }
+        let offset = number(at, 3)?;
+        let size = number(at + 3, 2)?;
+        at += 5;
+        // A zero size is a run of one repeated byte
+        let data = if size == 0 {
+            let count = number(at, 2)?;
+            let value = *patch.get(at + 2).ok_or_else(truncated)?;
+            at += 3;
+            vec![value; count]
+        } else {
+            let data = patch.get(at..at + size).ok_or_else(truncated)?.to_vec();
+            at += size;
+            data
+        };
+        if out.len() < offset + data.len() {
+            out.resize(offset + data.len(), 0);
+        }
+        out[offset..offset + data.len()].copy_from_slice(&data);
+    }
+    // Lunar IPS puts the size to cut the result down to after the end marker
+    if patch.len() == at + 3 {
+        out.truncate(number(at, 3)?);
+    }
+    Ok(out)
+}
+
+fn create_ips(source: &[u8], target: &[u8]) -> Result<Vec<u8>, String> {
+    if target.len() > IPS_MAX_SIZE {
+        return Err("ROMs over 16MB can't be described by an IPS patch".to_string());
+    }
+    let mut patch = b"PATCH".to_vec();
+    let differs = |i: usize| source.get(i) != Some(&target[i]);
+
+    let mut i = 0;
+    while i < target.len() {
+        if !differs(i) {
+            i += 1;
+            continue;
+        }
+        // Back up a byte rather than start a record where it would read as the end marker
+        let start = if i == IPS_EOF { i - 1 } else { i };
+        let mut end = i + 1;
+        while end < target.len() && end - start < 0xFFFF {
+            // Bytes past the end of the source always count as changed
+            if (end..target.len().min(end + IPS_MERGE_GAP)).any(differs) {
+                end += 1;
+            } else {
+                break;
+            }
+        }
+        patch.extend_from_slice(&(start as u32).to_be_bytes()[1..]);
+        patch.extend_from_slice(&((end - start) as u16).to_be_bytes());
+        patch.extend_from_slice(&target[start..end]);
+        i = end;
+    }
+    patch.extend_from_slice(b"EOF");
+    if target.len() < source.len() {
+        patch.extend_from_slice(&(target.len() as u32).to_be_bytes()[1..]);
+    }
+    Ok(patch)
+}
+
+fn apply_bps(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, String> {
+    if patch.len() < 4 + 12 {
+        return Err("BPS patch ends early".to_string());
+    }
+    let footer = patch.len() - 12;
+    let crc = |at: usize| u32::from_le_bytes(patch[at..at + 4].try_into().unwrap());
+    if hash::crc32(&patch[..footer + 8]) != crc(footer + 8) {
+        return Err("BPS patch is corrupt, its checksum doesn't match".to_string());
+    }
+    if hash::crc32(rom) != crc(footer) {
+        return Err(
+            "BPS patch is for a different ROM, the ROM's checksum doesn't match".to_string(),
+        );
+    }
+
+    let mut reader = BpsReader {
+        patch,
+        at: 4,
+        end: footer,
+    };
+    let source_size = reader.number()?;
+    let target_size = reader.number()?;
+    let metadata = reader.number()?;
+    reader.at = reader.at.saturating_add(metadata);
+    if source_size != rom.len() {
+        return Err(format!(
+            "BPS patch is for a {} byte ROM, this one is {} bytes",
+            source_size,
+            rom.len()
+        ));
+    }
+
+    let mut out = Vec::with_capacity(target_size);
+    let mut source_at = 0usize;
+    let mut target_at = 0usize;
+    while reader.at < footer {
+        let command = reader.number()?;
+        let length = (command >> 2) + 1;
+        if out.len().saturating_add(length) > target_size {
+            return Err("BPS patch writes past the end of the ROM".to_string());
+        }
+        let invalid = || "BPS patch reads outside the ROM".to_string();
+        match command & 3 {
+            // Source read: the bytes are unchanged from the same place in the original
+            0 => {
+                let at = out.len();
+                out.extend_from_slice(rom.get(at..at + length).ok_or_else(invalid)?);
+            }
+            // Target read: new bytes straight from the patch
+            1 => {
+                let data = reader.bytes(length)?;
+                out.extend_from_slice(data);
+            }
+            // Source copy: from anywhere in the original
+            2 => {
+                source_at = reader.offset(source_at)?;
+                out.extend_from_slice(
+                    rom.get(source_at..source_at.saturating_add(length))
+                        .ok_or_else(invalid)?,
+                );
+                source_at += length;
+            }
+            // Target copy: from earlier in the output, a byte at a time as it can overlap
+            _ => {
+                target_at = reader.offset(target_at)?;
+                if target_at >= out.len() {
+                    return Err(invalid());
+                }
+                for _ in 0..length {
+                    out.push(out[target_at]);
+                    target_at += 1;
+                }
+            }
+        }
+    }
+    if out.len() != target_size {
+        return Err("BPS patch ends early".to_string());
+    }
+    if hash::crc32(&out) != crc(footer + 4) {
+        return Err("patched ROM's checksum doesn't match the BPS patch".to_string());
+    }
+    Ok(out)
+}
+
+struct BpsReader<'a> {
+    patch: &'a [u8],
+    at: usize,
+    end: usize,
+}
+
+impl BpsReader<'_> {
+    fn bytes(&mut self, length: usize) -> Result<&[u8], String> {
+        if self.at.saturating_add(length) > self.end {
+            return Err("BPS patch ends early".to_string());
+        }
+        self.at += length;
+        Ok(&self.patch[self.at - length..self.at])
+    }
+
+    // Seven bits a byte, low first, with the top bit marking the last byte
+    fn number(&mut self) -> Result<usize, String> {
+        let mut number = 0usize;
+        let mut shift = 1usize;
+        loop {
+            let byte = self.bytes(1)?[0];
+            number = usize::from(byte & 0x7F)
+                .checked_mul(shift)
+                .and_then(|n| n.checked_add(number))
+                .ok_or("BPS patch has a number too big to read")?;
+            if byte & 0x80 != 0 {
+                return Ok(number);
+            }
+            shift = shift
+                .checked_shl(7)
+                .ok_or("BPS patch has a number too big to read")?;
+            number += shift;
+        }
+    }
+
+    // A copy offset, stored relative to where the last copy left off with the sign in the low bit
+    fn offset(&mut self, from: usize) -> Result<usize, String> {
+        let number = self.number()?;
+        let distance = number >> 1;
+        let at = if number & 1 != 0 {
+            from.checked_sub(distance)
+        } else {
+            from.checked_add(distance)
+        };
+        at.ok_or_else(|| "BPS patch reads outside the ROM".to_string())
+    }
+}
+
+// Only source and target reads, which is all a ROM hack of a few K needs to stay small
+fn create_bps(source: &[u8], target: &[u8]) -> Vec<u8> {
+    let mut patch = b"BPS1".to_vec();
+    write_number(&mut patch, source.len());
+    write_number(&mut patch, target.len());
+    write_number(&mut patch, 0);
+
+    let same = |i: usize| source.get(i) == Some(&target[i]);
+    let mut i = 0;
+    while i < target.len() {
+        let unchanged = same(i);
+        let mut end = i + 1;
+        while end < target.len() && same(end) == unchanged {
+            end += 1;
+        }
+        if unchanged {
+            write_number(&mut patch, (end - i - 1) << 2);
+        } else {
+            write_number(&mut patch, (end - i - 1) << 2 | 1);
+            patch.extend_from_slice(&target[i..end]);
+        }
+        i = end;
+    }
+
+    patch.extend_from_slice(&hash::crc32(source).to_le_bytes());
+    patch.extend_from_slice(&hash::crc32(target).to_le_bytes());
+    let crc = hash::crc32(&patch);
+    patch.extend_from_slice(&crc.to_le_bytes());
+    patch
+}
+
+fn write_number(patch: &mut Vec<u8>, mut number: usize) {
+    loop {
+        let byte = (number & 0x7F) as u8;
+        number >>= 7;
+        if number == 0 {
+            patch.push(byte | 0x80);
+            return;
+        }
+        patch.push(byte);
+        number -= 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rom(size: usize) -> Vec<u8> {
+        (0..size).map(|i| (i * 7 + 3) as u8).collect()
+    }
+
+    fn round_trip(source: &[u8], target: &[u8], format: PatchFormat) -> Vec<u8> {
+        let patch = create(source, target, format).unwrap();
+        let (out, applied) = apply(source, &patch).unwrap();
+        assert_eq!(applied, format);
+        assert_eq!(out, target);
+        patch
+    }
+
+    #[test]
+    fn patches_round_trip() {
+        let source = rom(300);
+        let mut changed = source.clone();
+        changed[0] ^= 0xFF;
+        changed[10] = 0;
+        changed[12] = 0;
+        changed[299] = 1;
+        let mut grown = changed.clone();
+        grown.extend_from_slice(&[9; 50]);
+        let shrunk = changed[..200].to_vec();
+
+        for format in [PatchFormat::Ips, PatchFormat::Bps] {
+            round_trip(&source, &source, format);
+            round_trip(&source, &changed, format);
+            round_trip(&source, &grown, format);
+            round_trip(&source, &shrunk, format);
+            round_trip(&[], &source, format);
+        }
+    }
+
+    #[test]
+    fn ips_shrinking_puts_the_size_after_the_end_marker() {
+        let source = rom(16);
+        let patch = round_trip(&source, &source[..10], PatchFormat::Ips);
+        assert!(patch.ends_with(b"EOF\x00\x00\x0A"));
+        // Without the size the ROM is only patched, not cut down
+        let (out, _) = apply(&source, &patch[..patch.len() - 3]).unwrap();
+        assert_eq!(out, source);
+    }
+
+    #[test]
+    fn ips_records_never_start_at_the_end_marker_offset() {
+        let source = vec![0; IPS_EOF + 4];
+        let mut target = source.clone();
+        target[IPS_EOF] = 1;
+        let patch = round_trip(&source, &target, PatchFormat::Ips);
+        assert_eq!(&patch[5..10], &[0x45, 0x4F, 0x45, 0x00, 0x02]);
+    }
+
+    #[test]
+    fn bps_numbers_read_back() {
+        for number in [0, 1, 0x7F, 0x80, 0x407F, 0x4080, 0xFFFFFF, usize::MAX >> 8] {
+            let mut data = Vec::new();
+            write_number(&mut data, number);
+            let mut reader = BpsReader {
+                patch: &data,
+                at: 0,
+                end: data.len(),
+            };
+            assert_eq!(reader.number(), Ok(number));
+            assert_eq!(reader.at, data.len());
+        }
+        // The last byte is the one with the top bit set
+        let mut data = Vec::new();
+        write_number(&mut data, 0x80);
+        assert_eq!(data, [0x00, 0x80]);
+    }
+
+    #[test]
+    fn bps_patches_for_another_rom_are_refused() {
+        let source = rom(64);
+        let mut target = source.clone();
+        target[5] = 0;
+        let patch = create(&source, &target, PatchFormat::Bps).unwrap();
+        let mut other = source.clone();
+        other[20] ^= 1;
+        let error = apply(&other, &patch).unwrap_err();
+        assert!(error.contains("different ROM"), "{}", error);
+
+        let mut corrupt = patch.clone();
+        corrupt[6] ^= 1;
+        let error = apply(&source, &corrupt).unwrap_err();
+        assert!(error.contains("corrupt"), "{}", error);
+    }
+
+    #[test]
+    fn truncated_patches_are_refused() {
+        let source = rom(64);
+        let mut target = source.clone();
+        target[5..9].copy_from_slice(&[1, 2, 3, 4]);
+
+        let ips = create(&source, &target, PatchFormat::Ips).unwrap();
+        // Cut inside the record, before the end marker
+        for cut in [6, 9, 12] {
+            assert_eq!(
+                apply(&source, &ips[..cut]),
+                Err("IPS patch ends early".to_string())
+            );
+        }
+
+        let bps = create(&source, &target, PatchFormat::Bps).unwrap();
+        assert!(apply(&source, &bps[..10]).is_err());
+        assert!(apply(&source, &bps[..4]).is_err());
+    }
+}
@@ -0,0 +1,147 @@
+use std::fmt;
+
+// Behaviours that differ between Chip-8 interpreters, the defaults are what CJ-8 has always done
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Quirks {
+    // 8XY1, 8XY2 and 8XY3 reset VF to 0 like the COSMAC VIP
+    pub vf_reset: bool,
+    // FX55 and FX65 leave I pointing past the last register, off leaves I unchanged
+    pub memory_increment: bool,
+    // 8XY6 and 8XYE shift VY into VX, off shifts VX in place
+    pub shift_vy: bool,
+    // BNNN jumps to XNN plus VX like SCHIP, off jumps to NNN plus V0
+    pub jump_vx: bool,
+    // Sprites are cut off at the screen edges, off wraps every pixel around
+    pub clip: bool,
+    // FX0A waits for a key to be pressed and released, off stores the key as soon as it goes down
+    pub key_wait_release: bool,
+}
+
+impl Default for Quirks {
+    fn default() -> Self {
+        Quirks {
+            vf_reset: false,
+            memory_increment: false,
+            shift_vy: false,
+            jump_vx: false,
+            clip: false,
+            key_wait_release: true,
+        }
+    }
+}
+
+impl Quirks {
+    // Every quirk by the name used on the command line and in files
+    pub fn flags(&self) -> Vec<(&'static str, bool)> {
+        vec![
+            ("vf-reset", self.vf_reset),
+            ("memory-increment", self.memory_increment),
+            ("shift-vy", self.shift_vy),
+            ("jump-vx", self.jump_vx),
+            ("clip", self.clip),
+            ("key-wait-release", self.key_wait_release),
+        ]
+    }
+
+    fn flag_mut(&mut self, name: &str) -> Option<&mut bool> {
+        match name {
+            "vf-reset" => Some(&mut self.vf_reset),
+            "memory-increment" => Some(&mut self.memory_increment),
+            "shift-vy" => Some(&mut self.shift_vy),
+            "jump-vx" => Some(&mut self.jump_vx),
+            "clip" => Some(&mut self.clip),
+            "key-wait-release" => Some(&mut self.key_wait_release),
+            _ => None,
+        }
+    }
+
+    // Apply a comma separated list like "key-wait-release=off", a bare name switches a quirk on
+    // and a no- prefix switches it off
+    pub fn apply(&mut self, spec: &str) -> Result<(), String> {
+        for item in spec
+            .split(',')
+            .map(str::trim)
+            .filter(|item| !item.is_empty())
+        {
+            let (name, on) = match item.split_once('=') {
+                Some((name, value)) => match value.trim() {
+                    "on" | "true" | "1" => (name.trim(), true),
+                    "off" | "false" | "0" => (name.trim(), false),
+                    _ => return Err(format!("invalid value for quirk {}: {}", name, value)),
+                },
+                None => match item.strip_prefix("no-") {
+                    Some(name) => (name, false),
+                    None => (item, true),
+                },
+            };
+            let flag = self
+                .flag_mut(name)
+                .ok_or_else(|| format!("unknown quirk: {}", name))?;
+            *flag = on;
+        }
+        Ok(())
+    }
+}
+
+// Lists every quirk as name=on|off, the same form apply reads back
+impl fmt::Display for Quirks {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let flags: Vec<String> = self
+            .flags()
+            .iter()
+            .map(|(name, on)| format!("{}={}", name, if *on { "on" } else { "off" }))
+            .collect();
+        write!(f, "{}", flags.join(","))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn apply_reads_every_form() {
+        let mut quirks = Quirks::default();
+        quirks
+            .apply("clip, vf-reset=on,no-key-wait-release, shift-vy=1,jump-vx=true")
+            .unwrap();
+        assert_eq!(
+            quirks,
+            Quirks {
+                vf_reset: true,
+                memory_increment: false,
+                shift_vy: true,
+                jump_vx: true,
+                clip: true,
+                key_wait_release: false,
+            }
+        );
+
+        quirks.apply("clip=off,shift-vy=0,jump-vx=false,,").unwrap();
+        assert!(!quirks.clip && !quirks.shift_vy && !quirks.jump_vx);
+        assert!(quirks.vf_reset);
+    }
+
+    #[test]
+    fn apply_rejects_unknown_names_and_values() {
+        let mut quirks = Quirks::default();
+        assert_eq!(quirks.apply("warp"), Err("unknown quirk: warp".to_string()));
+        assert_eq!(
+            quirks.apply("clip=maybe"),
+            Err("invalid value for quirk clip: maybe".to_string())
+        );
+        assert_eq!(quirks, Quirks::default());
+    }
+
+    #[test]
+    fn display_reads_back() {
+        let quirks = Quirks {
+            memory_increment: true,
+            key_wait_release: false,
+            ..Quirks::default()
+        };
+        let mut parsed = Quirks::default();
+        parsed.apply(&quirks.to_string()).unwrap();
+        assert_eq!(parsed, quirks);
+    }
+}
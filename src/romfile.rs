@@ -0,0 +1,552 @@
+use crate::hash;
+use crate::octo::{self, assembler, Cartridge};
+use flate2::read::{DeflateDecoder, MultiGzDecoder};
+use std::fmt;
+use std::io::Read;
+
+// Anything unpacking to more than this isn't a ROM, and stops a small archive filling memory
+const MAX_UNPACKED: u64 = 1 << 20;
+// Archives inside archives are followed this deep
+const MAX_DEPTH: usize = 3;
+
+// How a ROM was stored, outermost first
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Format {
+    Zip(String),
+    Gzip,
+    Hex,
+    // 0x12 0x34 ... as Octo and C sources write bytes
+    OctoHex,
+    Base64,
+    OctoCartridge,
+    // A program written in Octo's language, assembled on load
+    OctoSource,
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Format::Zip(entry) => write!(f, "zip entry {}", entry),
+            Format::Gzip => write!(f, "gzip"),
+            Format::Hex => write!(f, "hex text"),
+            Format::OctoHex => write!(f, "Octo hex text"),
+            Format::Base64 => write!(f, "base64 text"),
+            Format::OctoCartridge => write!(f, "Octo cartridge"),
+            Format::OctoSource => write!(f, "Octo source"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum UnpackError {
+    // The archive holds more than one file and none was named, the names are listed
+    Ambiguous(Vec<String>),
+    NoEntry(String),
+    Empty,
+    Invalid(String),
+    // An Octo cartridge whose program doesn't assemble, with the options it was saved with
+    Octo(Box<Cartridge>, String),
+}
+
+impl fmt::Display for UnpackError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            UnpackError::Ambiguous(entries) => {
+                write!(f, "archive holds several files: {}", entries.join(", "))
+            }
+            UnpackError::NoEntry(entry) => write!(f, "archive has no file named {}", entry),
+            UnpackError::Empty => write!(f, "archive holds no files"),
+            UnpackError::Invalid(reason) => write!(f, "{}", reason),
+            UnpackError::Octo(_, reason) => {
+                write!(
+                    f,
+                    "the Octo cartridge's program doesn't assemble: {}",
+                    reason
+                )
+            }
+        }
+    }
+}
+
+pub struct Unpacked {
+    pub rom: Vec<u8>,
+    // How the ROM was stored, outermost first, empty for a plain binary
+    pub formats: Vec<Format>,
+    // The options that came with an Octo cartridge
+    pub cartridge: Option<Cartridge>,
+}
+
+// Unpack archives and decode text until plain ROM bytes are left, going by the contents alone.
+// entry picks the file inside a zip holding more than one
+pub fn unpack(data: Vec<u8>, entry: Option<&str>) -> Result<Unpacked, UnpackError> {
+    let mut data = data;
+    let mut formats = Vec::new();
+    let mut entry = entry;
+    let mut cartridge = None;
+
+    for _ in 0..MAX_DEPTH {
+        if data.starts_with(b"PK\x03\x04") || data.starts_with(b"PK\x05\x06") {
+            let (name, unpacked) = unzip(&data, entry.take())?;
+            formats.push(Format::Zip(name));
+            data = unpacked;
+        } else if data.starts_with(&[0x1F, 0x8B]) {
+            data = limited_read(MultiGzDecoder::new(&data[..]), "gzip")?;
+            formats.push(Format::Gzip);
+        } else if octo::is_gif(&data) {
+            let decoded = octo::decode(&data).map_err(UnpackError::Invalid)?;
+            formats.push(Format::OctoCartridge);
+            data = decoded.program.clone().into_bytes();
+            cartridge = Some(decoded);
+            // The program is text, nothing more to unpack
+            break;
+        } else {
+            break;
+        }
+    }
+    if let Some(entry) = entry {
+        return Err(UnpackError::Invalid(format!(
+            "{} was asked for but the ROM isn't in a zip archive",
+            entry
+        )));
+    }
+
+    if let Some((decoded, format)) = decode_text(&data) {
+        formats.push(format);
+        data = decoded;
+    } else if cartridge.is_some() || assembler::is_source(&data) {
+        // Cartridges hold the program as source, the same as a .8o file
+        let assembled = String::from_utf8(data)
+            .map_err(|_| "Octo source isn't valid UTF-8".to_string())
+            .and_then(|source| assembler::assemble(&source));
+        data = match assembled {
+            Ok(rom) => rom,
+            Err(reason) => {
+                return Err(match cartridge {
+                    Some(cartridge) => UnpackError::Octo(Box::new(cartridge), reason),
+                    None => {
+                        UnpackError::Invalid(format!("Octo source doesn't assemble: {}", reason))
+                    }
+                })
+            }
+        };
+        formats.push(Format::OctoSource);
+    }
+    Ok(Unpacked {
+        rom: data,
+        formats,
+        cartridge,
+    })
+}
+
+fn limited_read<R: Read>(reader: R, what: &str) -> Result<Vec<u8>, UnpackError> {
+    let mut data = Vec::new();
+    reader
+        .take(MAX_UNPACKED + 1)
+        .read_to_end(&mut data)
+        .map_err(|e| UnpackError::Invalid(format!("corrupt {} data: {}", what, e)))?;
+    if data.len() as u64 > MAX_UNPACKED {
+        return Err(UnpackError::Invalid(format!(
+            "{} data unpacks to more than {} bytes",
+            what, MAX_UNPACKED
+        )));
+    }
+    Ok(data)
+}
+
+fn u16_at(data: &[u8], offset: usize) -> Option<usize> {
+    let bytes = data.get(offset..offset + 2)?;
+    Some(usize::from(u16::from_le_bytes([bytes[0], bytes[1]])))
+}
+
+fn u32_at(data: &[u8], offset: usize) -> Option<u32> {
+    let bytes = data.get(offset..offset + 4)?;
+    Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+// A file in a zip's central directory
+struct ZipEntry {
+    name: String,
+    method: usize,
+    flags: usize,
+    crc: u32,
+    compressed: usize,
+    header: usize,
+}
+
+// Pull one file out of a zip, the only one unless an entry is named
+fn unzip(data: &[u8], entry: Option<&str>) -> Result<(String, Vec<u8>), UnpackError> {
+    let corrupt = || UnpackError::Invalid("corrupt zip archive".to_string());
+
+    // The end of central directory record sits in the last 64K, after the archive comment
+    let search_from = data.len().saturating_sub(22 + 0xFFFF);
+    let end = (search_from..data.len().saturating_sub(21))
+        .rev()
+        .find(|&offset| data[offset..].starts_with(b"PK\x05\x06"))
+        .ok_or_else(corrupt)?;
+    let count = u16_at(data, end + 10).ok_or_else(corrupt)?;
+    let mut offset = u32_at(data, end + 16).ok_or_else(corrupt)? as usize;
+
+    let mut entries = Vec::new();
+    for _ in 0..count {
+        if !data
+            .get(offset..)
+            .is_some_and(|rest| rest.starts_with(b"PK\x01\x02"))
+        {
+            return Err(corrupt());
+        }
+        let field = |at: usize| u16_at(data, offset + at).ok_or_else(corrupt);
+        let name_length = field(28)?;
+        let name = data
+            .get(offset + 46..offset + 46 + name_length)
+            .ok_or_else(corrupt)?;
+        entries.push(ZipEntry {
+            name: String::from_utf8_lossy(name).into_owned(),
+            flags: field(8)?,
+            method: field(10)?,
+            crc: u32_at(data, offset + 16).ok_or_else(corrupt)?,
+            compressed: u32_at(data, offset + 20).ok_or_else(corrupt)? as usize,
+            header: u32_at(data, offset + 42).ok_or_else(corrupt)? as usize,
+        });
+        offset += 46 + name_length + field(30)? + field(32)?;
+    }
+
+    // Folders and empty files are never the ROM
+    entries.retain(|entry| !entry.name.ends_with('/') && entry.compressed > 0);
+    let chosen = match entry {
+        Some(name) => entries
+            .iter()
+            .find(|entry| entry.name == name)
+            .ok_or_else(|| UnpackError::NoEntry(name.to_string()))?,
+        None => match entries.as_slice() {
+            [] => return Err(UnpackError::Empty),
+            [only] => only,
+            _ => {
+                return Err(UnpackError::Ambiguous(
+                    entries.iter().map(|entry| entry.name.clone()).collect(),
+                ))
+            }
+        },
+    };
+
+    if chosen.flags & 1 != 0 {
+        return Err(UnpackError::Invalid(format!(
+            "{} is encrypted",
+            chosen.name
+        )));
+    }
+    let header = chosen.header;
+    let start = header
+        + 30
+        + u16_at(data, header + 26).ok_or_else(corrupt)?
+        + u16_at(data, header + 28).ok_or_else(corrupt)?;
+    let compressed = data
+        .get(start..start + chosen.compressed)
+        .ok_or_else(corrupt)?;
+    let unpacked = match chosen.method {
+        0 => compressed.to_vec(),
+        8 => limited_read(DeflateDecoder::new(compressed), "zip")?,
+        method => {
+            return Err(UnpackError::Invalid(format!(
+                "{} uses compression method {}, only stored and deflate are supported",
+                chosen.name, method
+            )))
+        }
+    };
+    if hash::crc32(&unpacked) != chosen.crc {
+        return Err(UnpackError::Invalid(format!(
+            "{} fails its CRC-32 check",
+            chosen.name
+        )));
+    }
+    Ok((chosen.name.clone(), unpacked))
+}
+
+// A ROM written out as hex or base64 text. Anything that isn't printable ASCII, or doesn't decode,
+// is left alone as a binary ROM
+fn decode_text(data: &[u8]) -> Option<(Vec<u8>, Format)> {
+    if !data
+        .iter()
+        .all(|byte| byte.is_ascii_graphic() || byte.is_ascii_whitespace())
+    {
+        return None;
+    }
+    let text = std::str::from_utf8(data).ok()?;
+    // Octo and many hex dumps use # for comments
+    let text: String = text
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or_default())
+        .collect::<Vec<_>>()
+        .join("\n");
+    let tokens: Vec<&str> = text
+        .split(|c: char| c.is_ascii_whitespace() || c == ',')
+        .filter(|token| !token.is_empty())
+        .collect();
+    if tokens.is_empty() {
+        return None;
+    }
+
+    // Every byte written as 0xNN
+    if tokens
+        .iter()
+        .all(|token| token.starts_with("0x") || token.starts_with("0X"))
+    {
+        let bytes: Option<Vec<u8>> = tokens
+            .iter()
+            .map(|token| match &token[2..] {
+                digits if (1..=2).contains(&digits.len()) => u8::from_str_radix(digits, 16).ok(),
+                _ => None,
+            })
+            .collect();
+        return bytes.map(|bytes| (bytes, Format::OctoHex));
+    }
+
+    // Plain hex digits, spaced out or not
+    let digits: String = tokens.concat();
+    if digits.len().is_multiple_of(2) && digits.chars().all(|c| c.is_ascii_hexdigit()) {
+        let bytes = (0..digits.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&digits[i..i + 2], 16).unwrap())
+            .collect();
+        return Some((bytes, Format::Hex));
+    }
+
+    // A printable binary ROM can be letters and digits too, so base64 has to look the part: padded
+    // to a multiple of 4, or wrapped into lines of the same length the way encoders write it out
+    let wrapped = match tokens.split_last() {
+        Some((last, lines)) if !lines.is_empty() => {
+            let width = lines[0].len();
+            width >= 16
+                && width.is_multiple_of(4)
+                && lines.iter().all(|line| line.len() == width)
+                && last.len() <= width
+        }
+        _ => false,
+    };
+    base64(&digits, wrapped).map(|bytes| (bytes, Format::Base64))
+}
+
+// Standard or URL safe alphabet. Padding can only be left off wrapped text
+fn base64(text: &str, wrapped: bool) -> Option<Vec<u8>> {
+    let padded = text.len();
+    let text = text.trim_end_matches('=');
+    let padding = padded - text.len();
+    if padding > 2
+        || text.len() % 4 == 1
+        || (!padded.is_multiple_of(4) && (padding > 0 || !wrapped))
+    {
+        return None;
+    }
+    let mut bytes = Vec::with_capacity(text.len() * 3 / 4);
+    let mut bits = 0u32;
+    let mut count = 0;
+    for c in text.bytes() {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' | b'-' => 62,
+            b'/' | b'_' => 63,
+            _ => return None,
+        };
+        bits = bits << 6 | u32::from(value);
+        count += 6;
+        if count >= 8 {
+            count -= 8;
+            bytes.push((bits >> count) as u8);
+        }
+    }
+    Some(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::{DeflateEncoder, GzEncoder};
+    use flate2::Compression;
+    use std::io::Write;
+
+    // A few instructions with bytes that are neither text nor an archive's magic
+    const ROM: &[u8] = &[
+        0x00, 0xE0, 0xA2, 0x0A, 0x60, 0x0C, 0xD0, 0x15, 0x12, 0x08, 0xF0, 0x90,
+    ];
+
+    fn deflate(data: &[u8]) -> Vec<u8> {
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    // A zip archive of (name, contents, deflated) files
+    fn zip(files: &[(&str, &[u8], bool)]) -> Vec<u8> {
+        let mut data = Vec::new();
+        let mut directory = Vec::new();
+        for &(name, contents, deflated) in files {
+            let stored = if deflated {
+                deflate(contents)
+            } else {
+                contents.to_vec()
+            };
+            let method: u16 = if deflated { 8 } else { 0 };
+            let mut fields = Vec::new();
+            fields.extend_from_slice(&method.to_le_bytes());
+            fields.extend_from_slice(&[0; 4]);
+            fields.extend_from_slice(&hash::crc32(contents).to_le_bytes());
+            fields.extend_from_slice(&(stored.len() as u32).to_le_bytes());
+            fields.extend_from_slice(&(contents.len() as u32).to_le_bytes());
+            fields.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            fields.extend_from_slice(&[0; 2]);
+
+            directory.extend_from_slice(b"PK\x01\x02\x14\x00\x14\x00\x00\x00");
+            directory.extend_from_slice(&fields);
+            directory.extend_from_slice(&[0; 10]);
+            directory.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            directory.extend_from_slice(name.as_bytes());
+
+            data.extend_from_slice(b"PK\x03\x04\x14\x00\x00\x00");
+            data.extend_from_slice(&fields);
+            data.extend_from_slice(name.as_bytes());
+            data.extend_from_slice(&stored);
+        }
+        let offset = data.len() as u32;
+        data.extend_from_slice(&directory);
+        data.extend_from_slice(b"PK\x05\x06\x00\x00\x00\x00");
+        data.extend_from_slice(&(files.len() as u16).to_le_bytes());
+        data.extend_from_slice(&(files.len() as u16).to_le_bytes());
+        data.extend_from_slice(&(directory.len() as u32).to_le_bytes());
+        data.extend_from_slice(&offset.to_le_bytes());
+        data.extend_from_slice(&[0; 2]);
+        data
+    }
+
+    fn unpacked(data: Vec<u8>, entry: Option<&str>) -> (Vec<u8>, Vec<Format>) {
+        let unpacked = unpack(data, entry).unwrap();
+        (unpacked.rom, unpacked.formats)
+    }
+
+    #[test]
+    fn binary_rom_is_left_alone() {
+        assert_eq!(unpacked(ROM.to_vec(), None), (ROM.to_vec(), vec![]));
+        // Printable bytes that decode as nothing are a ROM too
+        let text = b"not a hex dump!".to_vec();
+        assert_eq!(unpacked(text.clone(), None), (text, vec![]));
+    }
+
+    #[test]
+    fn zip_entries_are_unpacked() {
+        for deflated in [false, true] {
+            let archive = zip(&[("game.ch8", ROM, deflated)]);
+            assert_eq!(
+                unpacked(archive, None),
+                (ROM.to_vec(), vec![Format::Zip("game.ch8".to_string())])
+            );
+        }
+    }
+
+    #[test]
+    fn zip_with_several_files_needs_an_entry() {
+        let archive = zip(&[
+            ("readme.txt", b"\x01\x02", false),
+            ("folder/", b"", false),
+            ("game.ch8", ROM, true),
+        ]);
+        match unpack(archive.clone(), None) {
+            Err(UnpackError::Ambiguous(names)) => assert_eq!(names, ["readme.txt", "game.ch8"]),
+            _ => panic!("expected the entries to be listed"),
+        }
+        assert_eq!(unpacked(archive.clone(), Some("game.ch8")).0, ROM);
+        assert!(matches!(
+            unpack(archive, Some("other.ch8")),
+            Err(UnpackError::NoEntry(_))
+        ));
+        assert!(matches!(
+            unpack(zip(&[("folder/", b"", false)]), None),
+            Err(UnpackError::Empty)
+        ));
+    }
+
+    #[test]
+    fn zip_crc_is_checked() {
+        let mut archive = zip(&[("game.ch8", ROM, false)]);
+        // The stored bytes come straight after the 30 byte header and the name
+        archive[30 + "game.ch8".len()] ^= 0xFF;
+        assert!(matches!(
+            unpack(archive, None),
+            Err(UnpackError::Invalid(_))
+        ));
+    }
+
+    #[test]
+    fn entry_needs_a_zip() {
+        assert!(matches!(
+            unpack(ROM.to_vec(), Some("game.ch8")),
+            Err(UnpackError::Invalid(_))
+        ));
+    }
+
+    #[test]
+    fn gzip_and_nested_archives_are_unpacked() {
+        assert_eq!(
+            unpacked(gzip(ROM), None),
+            (ROM.to_vec(), vec![Format::Gzip])
+        );
+        let archive = zip(&[("game.ch8.gz", &gzip(ROM), false)]);
+        assert_eq!(
+            unpacked(archive, None),
+            (
+                ROM.to_vec(),
+                vec![Format::Zip("game.ch8.gz".to_string()), Format::Gzip]
+            )
+        );
+    }
+
+    #[test]
+    fn text_roms_are_decoded() {
+        let hex = b"00e0 a20a 600c\nd015 # draw\n1208 F090\n".to_vec();
+        assert_eq!(unpacked(hex, None), (ROM.to_vec(), vec![Format::Hex]));
+
+        let octo =
+            b"# sprite test\n0x00 0xE0 0xA2 0x0A 0x60 0x0C\n0xD0 0x15 0x12 0x08, 0xF0, 0x90\n";
+        assert_eq!(
+            unpacked(octo.to_vec(), None),
+            (ROM.to_vec(), vec![Format::OctoHex])
+        );
+
+        assert_eq!(
+            unpacked(b"AOCiCmAM0BUSCPCQ\n".to_vec(), None),
+            (ROM.to_vec(), vec![Format::Base64])
+        );
+        // URL safe and padded
+        assert_eq!(unpacked(b"_-8=".to_vec(), None).0, [0xFF, 0xEF]);
+        // Wrapped lines don't need padding
+        let wrapped = b"AOCiCmAM0BUSCPCQAOCiCmAM0BUSCPCQ\nAOCiCmAM0BUSCPA\n".to_vec();
+        assert_eq!(unpacked(wrapped, None).0.len(), 35);
+    }
+
+    #[test]
+    fn printable_binary_roms_are_not_taken_for_base64() {
+        // 6158 6259 705A, sets V1 and V2 and adds to V0, happens to be all letters
+        let rom = b"aXbYpZ".to_vec();
+        assert_eq!(unpacked(rom.clone(), None), (rom, vec![]));
+        // Padding in the wrong place or too much of it
+        for text in [&b"aXbYp="[..], b"aXbYpZ=", b"aXb=pZ==", b"aX==="] {
+            assert_eq!(unpacked(text.to_vec(), None), (text.to_vec(), vec![]));
+        }
+        // Lines too short to be an encoder's wrapping
+        let lines = b"aXbY\naXb\n".to_vec();
+        assert_eq!(unpacked(lines.clone(), None), (lines, vec![]));
+    }
+
+    #[test]
+    fn text_inside_an_archive_is_decoded() {
+        assert_eq!(
+            unpacked(gzip(b"00e0a20a600cd0151208f090"), None),
+            (ROM.to_vec(), vec![Format::Gzip, Format::Hex])
+        );
+    }
+}
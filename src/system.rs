@@ -1,5 +1,24 @@
-use crate::units::{au::AU, ku::KU};
-use rand::Rng;
+use crate::capture::Image;
+use crate::hash;
+use crate::palette::Palette;
+use crate::quirks::Quirks;
+use crate::units::cu::{self, CartridgeError};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+// Rate the delay and sound timers count down at
+pub const TIMER_HZ: u32 = 60;
+// Instructions run per 60hz frame unless told otherwise, roughly 500hz
+pub const DEFAULT_IPF: u32 = 8;
+// ROMs are loaded at 0x200 and start running there unless the platform or ROM says otherwise
+pub const ROM_START: usize = 0x200;
+pub const MAX_ROM_SIZE: usize = 4096 - ROM_START;
+
+// The buzzer switching on or off, stamped in emulated seconds since the system started
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BuzzerEvent {
+    pub time: f64,
+    pub on: bool,
+}
 
 pub struct System {
     opcode: u16,
@@ -10,27 +29,67 @@ pub struct System {
     sound_timer: u8,
     stack: [u16; 16],
     sp: usize,
-    memory: [u8; 4096],
+    memory: Vec<u8>,
+    // Where init copies the ROM to and where the PC starts after a reset
+    load_address: usize,
+    entry_point: u16,
     pub gfx: [[u16; 64]; 32],
 
     pub draw_flag: bool,
+    // Print the loading steps, each opcode and the timers to stdout
+    pub trace: bool,
+    // Instructions per frame
+    pub ipf: u32,
+    pub quirks: Quirks,
+    // Keypad as it was last frame, plus presses FX0A has already taken, to spot fresh presses
+    previous_keys: [bool; 16],
+    // Key FX0A saw pressed and is waiting to be released
+    waiting_key: Option<u8>,
+    frame: u64,
+    cycle_in_frame: u32,
+    // Emulated time the buzzer stops at while it is sounding
+    sound_until: Option<f64>,
+    buzzer_events: Vec<BuzzerEvent>,
+    // CXNN draws from a seeded generator so runs can be replayed exactly
+    seed: u64,
+    rng: StdRng,
     font_set: [u8; 80],
 }
 
+impl Default for System {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl System {
     pub fn new() -> System {
+        let seed = rand::thread_rng().gen();
         System {
             opcode: 0x000,
             v: [0x0; 16],
             i: 0,
-            pc: 0x200,
-            delay_timer: 60,
-            sound_timer: 60,
+            pc: ROM_START as u16,
+            delay_timer: 0,
+            sound_timer: 0,
             stack: [0x000; 16],
             sp: 0,
-            memory: [0x0; 4096],
+            memory: vec![0x0; 4096],
+            load_address: ROM_START,
+            entry_point: ROM_START as u16,
             gfx: [[0x000; 64]; 32],
             draw_flag: false,
+            trace: true,
+            ipf: DEFAULT_IPF,
+            quirks: Quirks::default(),
+            previous_keys: [false; 16],
+            waiting_key: None,
+            frame: 0,
+            cycle_in_frame: 0,
+            sound_until: None,
+            buzzer_events: Vec::new(),
+            seed,
+            rng: StdRng::seed_from_u64(seed),
             font_set: [
                 0xF0, 0x90, 0x90, 0x90, 0xF0, 0x20, 0x60, 0x20, 0x20, 0x70, 0xF0, 0x10, 0xF0, 0x80,
                 0xF0, 0xF0, 0x10, 0xF0, 0x10, 0xF0, 0x90, 0x90, 0xF0, 0x10, 0x10, 0xF0, 0x80, 0xF0,
@@ -42,18 +101,132 @@ impl System {
         }
     }
 
-    pub fn init(&mut self, buffer: Vec<u8>) {
+    // Load the font and the ROM, a ROM too big for memory is refused rather than cut short
+    pub fn init(&mut self, rom: &[u8]) -> Result<(), CartridgeError> {
+        cu::check_fits(rom, self.memory.len().saturating_sub(self.load_address))?;
+
         // Load fontset
-        for (i, font) in self.font_set.iter().enumerate() {
-            self.memory[i] = *font;
+        self.memory[..self.font_set.len()].copy_from_slice(&self.font_set);
+        if self.trace {
+            println!("Font loaded");
+        }
+
+        // Load ROM into memory, at 0x200 unless configured otherwise
+        self.memory[self.load_address..self.load_address + rom.len()].copy_from_slice(rom);
+        if self.trace {
+            println!("Cartridge loaded successfully");
+        }
+        Ok(())
+    }
+
+    // Bytes of memory, 4K unless the platform has more. Changing it clears memory
+    pub fn set_memory_size(&mut self, size: usize) {
+        self.memory = vec![0; size];
+    }
+
+    pub fn memory_size(&self) -> usize {
+        self.memory.len()
+    }
+
+    // Where the ROM goes and where running starts, set before init. Both have to be inside memory,
+    // with room for a whole instruction at the entry point. init checks the ROM fits after the load address
+    pub fn set_load_address(
+        &mut self,
+        load_address: usize,
+        entry_point: usize,
+    ) -> Result<(), String> {
+        for (what, address, size) in [
+            ("load address", load_address, 1),
+            ("entry point", entry_point, 2),
+        ] {
+            if address + size > self.memory.len() {
+                return Err(format!(
+                    "{} {:#05X} doesn't fit in the {} bytes of memory",
+                    what,
+                    address,
+                    self.memory.len()
+                ));
+            }
         }
-        println!("Font loaded");
+        self.load_address = load_address;
+        self.entry_point = entry_point as u16;
+        self.pc = self.entry_point;
+        Ok(())
+    }
+
+    pub fn load_address(&self) -> usize {
+        self.load_address
+    }
+
+    pub fn entry_point(&self) -> u16 {
+        self.entry_point
+    }
+
+    // Put the CPU, timers and display back to power on, memory is left alone so init can reload the ROM
+    pub fn reset(&mut self) {
+        self.opcode = 0;
+        self.v = [0; 16];
+        self.i = 0;
+        self.pc = self.entry_point;
+        self.delay_timer = 0;
+        self.set_sound_timer(0);
+        self.stack = [0; 16];
+        self.sp = 0;
+        self.gfx = [[0; 64]; 32];
+        self.draw_flag = true;
+        self.waiting_key = None;
+    }
+
+    // Reset with memory cleared and the random number generator restarted from its seed
+    pub fn hard_reset(&mut self) {
+        self.reset();
+        self.memory.fill(0);
+        self.set_seed(self.seed);
+    }
 
-        // Load ROM into memory at address 0x200
-        for (i, byte) in buffer.iter().enumerate() {
-            self.memory[i + 512] = *byte
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    // Restart the random number generator from the given seed
+    pub fn set_seed(&mut self, seed: u64) {
+        self.seed = seed;
+        self.rng = StdRng::seed_from_u64(seed);
+    }
+
+    // SHA-1 of the registers, timers, stack, memory and display, to check two runs ended up the same
+    pub fn state_hash(&self) -> String {
+        let mut state = Vec::with_capacity(4096 + 64 * 32 * 2 + 64);
+        state.extend_from_slice(&self.v);
+        state.extend_from_slice(&(self.i as u16).to_be_bytes());
+        state.extend_from_slice(&self.pc.to_be_bytes());
+        state.push(self.delay_timer);
+        state.push(self.sound_timer);
+        for address in self.stack.iter() {
+            state.extend_from_slice(&address.to_be_bytes());
         }
-        println!("Cartridge loaded successfully");
+        state.push(self.sp as u8);
+        state.extend_from_slice(&self.memory);
+        for pixel in self.gfx.iter().flatten() {
+            state.extend_from_slice(&pixel.to_be_bytes());
+        }
+        hash::sha1_hex(&state)
+    }
+
+    // SHA-1 of the display alone, one byte per pixel row by row, for checking what a ROM drew
+    pub fn display_hash(&self) -> String {
+        let pixels: Vec<u8> = self
+            .gfx
+            .iter()
+            .flatten()
+            .map(|&pixel| pixel as u8)
+            .collect();
+        hash::sha1_hex(&pixels)
+    }
+
+    // Render the current framebuffer to an RGB image
+    pub fn screenshot(&self, palette: &Palette, scale: u32) -> Image {
+        Image::from_gfx(&self.gfx, palette, scale)
     }
 
     // Turn off draw flag
@@ -61,19 +234,105 @@ impl System {
         self.draw_flag = false;
     }
 
+    // Whether the buzzer is sounding right now
+    pub fn sound_on(&self) -> bool {
+        self.sound_until.is_some()
+    }
+
+    // Seconds of emulated time, advancing 1/60 per frame and a share of that per instruction
+    pub fn emulated_time(&self) -> f64 {
+        let ipf = self.ipf.max(1) as f64;
+        (self.frame as f64 + f64::from(self.cycle_in_frame).min(ipf) / ipf) / f64::from(TIMER_HZ)
+    }
+
+    // Buzzer changes since the last call, oldest first
+    pub fn take_buzzer_events(&mut self) -> Vec<BuzzerEvent> {
+        std::mem::take(&mut self.buzzer_events)
+    }
+
+    // Run one 60hz frame worth of instructions then count the timers down
+    pub fn run_frame(&mut self, keys: &[bool; 16]) {
+        while self.cycle_in_frame < self.ipf {
+            self.emulate_cycle(keys);
+        }
+        // Once a frame, the keypad only changes between frames so a press seen late in one isn't lost
+        self.previous_keys = *keys;
+        self.tick_timers();
+    }
+
+    // Count the timers down once, called at 60hz
+    pub fn tick_timers(&mut self) {
+        self.frame += 1;
+        self.cycle_in_frame = 0;
+
+        if self.delay_timer > 0 {
+            self.delay_timer -= 1;
+        }
+        if self.sound_timer > 0 {
+            self.sound_timer -= 1;
+        }
+
+        // Stop the buzzer at the exact time the sound timer ran out
+        if let Some(until) = self.sound_until {
+            if until <= self.emulated_time() {
+                self.buzzer_events.push(BuzzerEvent {
+                    time: until,
+                    on: false,
+                });
+                self.sound_until = None;
+            }
+        }
+
+        // Print timer state to console
+        if self.trace {
+            println!(
+                "Delay Timer: {}\nSound Timer: {}",
+                self.delay_timer, self.sound_timer
+            );
+        }
+    }
+
+    // FX18, the buzzer sounds for exactly value/60 seconds from now
+    fn set_sound_timer(&mut self, value: u8) {
+        self.sound_timer = value;
+        let now = self.emulated_time();
+
+        if value == 0 {
+            if self.sound_until.take().is_some() {
+                self.buzzer_events.push(BuzzerEvent {
+                    time: now,
+                    on: false,
+                });
+            }
+            return;
+        }
+
+        if self.sound_until.is_none() {
+            self.buzzer_events.push(BuzzerEvent {
+                time: now,
+                on: true,
+            });
+        }
+        self.sound_until = Some(now + f64::from(value) / f64::from(TIMER_HZ));
+    }
+
     // Test hardcoded opcodes
-    pub fn emulate_cycle(&mut self, audio_unit: &mut AU, keys: &[bool; 16], keyboard_unit: &KU) {
-        // Fetch
-        let index = self.pc as usize;
-        self.opcode = u16::from(self.memory[index]) << 8 | u16::from(self.memory[index + 1]);
+    pub fn emulate_cycle(&mut self, keys: &[bool; 16]) {
+        // Fetch, running off the end of memory carries on from the start
+        let index = usize::from(self.pc) % self.memory.len();
+        self.pc = index as u16;
+        let next = (index + 1) % self.memory.len();
+        self.opcode = u16::from(self.memory[index]) << 8 | u16::from(self.memory[next]);
         let op_index = self.opcode as usize;
         let x_reg: usize = (op_index & 0x0F00) >> 8;
         let y_reg: usize = (op_index & 0x00F0) >> 4;
         let vx = self.v[x_reg] as u16;
         let vy = self.v[y_reg] as u16;
-        let nn = (self.opcode & 0x00FF) as u16;
+        let nn = self.opcode & 0x00FF;
 
-        println!("Executing: {:#x}", self.opcode);
+        if self.trace {
+            println!("Executing: {:#x}", self.opcode);
+        }
 
         // Decode
         match self.opcode & 0xF000 {
@@ -88,7 +347,7 @@ impl System {
                             }
                         }
                         self.draw_flag = true;
-                        self.pc += 2;
+                        self.pc = self.pc.wrapping_add(2);
                     }
                     // Returns from subroutine
                     0x00EE => {
@@ -106,65 +365,68 @@ impl System {
             }
             // // Calls the subroutine at address NNN
             0x2000 => {
-                self.stack[self.sp] = self.pc + 2;
+                self.stack[self.sp] = self.pc.wrapping_add(2);
                 self.sp += 1;
                 self.pc = self.opcode & 0x0FFF;
             }
             // Skips the next instruction if VX == NN
             0x3000 => {
                 if self.v[x_reg] == (self.opcode & 0x00FF) as u8 {
-                    self.pc += 4;
+                    self.pc = self.pc.wrapping_add(4);
                 } else {
-                    self.pc += 2;
+                    self.pc = self.pc.wrapping_add(2);
                 }
             }
             // Skips the next instruction if VX != NN
             0x4000 => {
                 if self.v[x_reg] != (self.opcode & 0x00FF) as u8 {
-                    self.pc += 4;
+                    self.pc = self.pc.wrapping_add(4);
                 } else {
-                    self.pc += 2;
+                    self.pc = self.pc.wrapping_add(2);
                 }
             }
             // Skips the next instruction if VX == VY
             0x5000 => {
                 if vx == vy {
-                    self.pc += 4;
+                    self.pc = self.pc.wrapping_add(4);
                 } else {
-                    self.pc += 2;
+                    self.pc = self.pc.wrapping_add(2);
                 }
             }
             // Sets VX to NN
             0x6000 => {
                 self.v[x_reg] = (self.opcode & 0x00FF) as u8;
-                self.pc += 2;
+                self.pc = self.pc.wrapping_add(2);
             }
             // // Adds NN to VX
             0x7000 => {
                 self.v[x_reg] = (vx + nn) as u8;
-                self.pc += 2;
+                self.pc = self.pc.wrapping_add(2);
             }
             0x8000 => {
                 match self.opcode & 0x000F {
                     // Sets VX to the value of VY
                     0x0000 => {
                         self.v[x_reg] = self.v[y_reg];
-                        self.pc += 2;
+                        self.pc = self.pc.wrapping_add(2);
                     }
                     // Sets VX to VX or VY
                     0x0001 => {
-                        self.v[x_reg] = self.v[x_reg] | self.v[y_reg];
-                        self.pc += 2;
+                        self.v[x_reg] |= self.v[y_reg];
+                        self.reset_vf();
+                        self.pc = self.pc.wrapping_add(2);
                     }
                     // Sets VX to VX and VY
                     0x0002 => {
-                        self.v[x_reg] = self.v[x_reg] & self.v[y_reg];
-                        self.pc += 2;
+                        self.v[x_reg] &= self.v[y_reg];
+                        self.reset_vf();
+                        self.pc = self.pc.wrapping_add(2);
                     }
                     // Sets VX to VX xor VY
                     0x0003 => {
-                        self.v[x_reg] = self.v[x_reg] ^ self.v[y_reg];
-                        self.pc += 2;
+                        self.v[x_reg] ^= self.v[y_reg];
+                        self.reset_vf();
+                        self.pc = self.pc.wrapping_add(2);
                     }
                     // Adds the value of register VY to VX
                     0x0004 => {
@@ -176,7 +438,7 @@ impl System {
                         }
                         let sum = (vx + vy) as u8;
                         self.v[x_reg] = sum;
-                        self.pc += 2;
+                        self.pc = self.pc.wrapping_add(2);
                     }
                     // VY is subtracted from VX and VF is set to 0 when there is a borrow and 1 when there is not
                     0x0005 => {
@@ -187,13 +449,14 @@ impl System {
                         }
 
                         self.v[x_reg] = self.v[x_reg].wrapping_sub(self.v[y_reg]);
-                        self.pc += 2;
+                        self.pc = self.pc.wrapping_add(2);
                     }
                     // Stores the least significant bit of VX in VF and then shifts VX to the right by 1
                     0x0006 => {
-                        self.v[0xF] = self.v[x_reg] & 1;
-                        self.v[x_reg] >>= 1;
-                        self.pc += 2;
+                        let value = self.shift_source(x_reg, y_reg);
+                        self.v[x_reg] = value >> 1;
+                        self.v[0xF] = value & 1;
+                        self.pc = self.pc.wrapping_add(2);
                     }
                     // Sets VX to VY - VX and VF is set to 0 when there is a borrow and 1 when there is not
                     0x0007 => {
@@ -204,13 +467,14 @@ impl System {
                         }
                         let difference = vy - vx;
                         self.v[x_reg] = difference as u8;
-                        self.pc += 2;
+                        self.pc = self.pc.wrapping_add(2);
                     }
                     // Stores the most significant bit of VX in VF and then shifts VX to the left by 1
                     0x000E => {
-                        self.v[0xF] = (self.v[x_reg] & 0b10000000) >> 7;
-                        self.v[x_reg] <<= 1;
-                        self.pc += 2;
+                        let value = self.shift_source(x_reg, y_reg);
+                        self.v[x_reg] = value << 1;
+                        self.v[0xF] = (value & 0b10000000) >> 7;
+                        self.pc = self.pc.wrapping_add(2);
                     }
                     _ => {
                         panic!("ERROR: Unknown opcode: {:#X}", self.opcode);
@@ -220,26 +484,30 @@ impl System {
             // // Skips the next instruction if VX != VY
             0x9000 => {
                 if vx != vy {
-                    self.pc += 4;
+                    self.pc = self.pc.wrapping_add(4);
                 } else {
-                    self.pc += 2;
+                    self.pc = self.pc.wrapping_add(2);
                 }
             }
             // Sets i to the address NNN
             0xA000 => {
                 self.i = (self.opcode & 0x0FFF) as usize;
-                self.pc += 2;
+                self.pc = self.pc.wrapping_add(2);
             }
-            // Jumps to the address NNN plus V0
+            // Jumps to the address NNN plus V0, or XNN plus VX with the jump quirk
             0xB000 => {
-                self.pc = (self.opcode & 0x0FFF) + self.v[0] as u16;
+                let offset = if self.quirks.jump_vx {
+                    vx
+                } else {
+                    self.v[0] as u16
+                };
+                self.pc = (self.opcode & 0x0FFF) + offset;
             }
             // Sets VX to equal a random number & NN
             0xC000 => {
-                let mut rng = rand::thread_rng();
-                let number: u8 = rng.gen::<u8>();
+                let number: u8 = self.rng.gen::<u8>();
                 self.v[x_reg] = number & (self.opcode & 0x00FF) as u8;
-                self.pc += 2;
+                self.pc = self.pc.wrapping_add(2);
             }
             // Draw a sprite at coord (VX, VY)
             0xD000 => {
@@ -251,34 +519,45 @@ impl System {
 
                 // This looping block is heavily inspired by starrhorne's chip-8 impl
                 // Credit due to her
+
+                // With clipping only the starting position wraps and the sprite is cut off at the edges
+                let (start_x, start_y) = (vx as usize % 64, vy as usize % 32);
                 for byte in 0..height {
-                    let y = (self.v[y_reg] + byte as u8) % 32;
+                    let y = start_y + byte as usize;
+                    if self.quirks.clip && y >= 32 {
+                        break;
+                    }
+                    let y = y % 32;
                     for bit in 0..8 {
-                        let x = (self.v[x_reg] + bit) % 64;
+                        let x = start_x + bit as usize;
+                        if self.quirks.clip && x >= 64 {
+                            break;
+                        }
+                        let x = x % 64;
                         let color = (self.memory[self.i + byte as usize] >> (7 - bit)) & 1;
-                        self.v[0xF] |= color as u8 & self.gfx[y as usize][x as usize] as u8;
-                        self.gfx[y as usize][x as usize] ^= color as u16;
+                        self.v[0xF] |= color & self.gfx[y][x] as u8;
+                        self.gfx[y][x] ^= color as u16;
                     }
                 }
 
                 self.draw_flag = true;
-                self.pc += 2;
+                self.pc = self.pc.wrapping_add(2);
             }
             0xE000 => {
                 match self.opcode & 0x00FF {
                     // Skips next instruction if key stored in VX is pressed
                     0x009E => {
                         if keys[vx as usize] {
-                            self.pc += 4;
+                            self.pc = self.pc.wrapping_add(4);
                         } else {
-                            self.pc += 2;
+                            self.pc = self.pc.wrapping_add(2);
                         }
                     }
                     0x00A1 => {
                         if !keys[vx as usize] {
-                            self.pc += 4;
+                            self.pc = self.pc.wrapping_add(4);
                         } else {
-                            self.pc += 2;
+                            self.pc = self.pc.wrapping_add(2);
                         }
                     }
                     _ => {
@@ -292,54 +571,51 @@ impl System {
                     // Sets VX to the value of the delay timer
                     0x0007 => {
                         self.v[x_reg] = self.delay_timer;
-                        self.pc += 2;
+                        self.pc = self.pc.wrapping_add(2);
                     }
                     // Blocks and then put key value into VX
                     0x000A => {
-                        if keyboard_unit.kd == false {
-                            self.pc -= 2;
-                        } else {
-                            for (i, key) in keys.iter().enumerate() {
-                                if *key {
-                                    self.v[x_reg] = i as u8;
-                                }
-                            }
-                            self.pc += 2;
+                        if let Some(key) = self.key_wait(keys) {
+                            self.v[x_reg] = key;
+                            self.pc = self.pc.wrapping_add(2);
                         }
                     }
                     // Sets the delay timer to VX
                     0x0015 => {
                         self.delay_timer = self.v[x_reg];
-                        self.pc += 2;
+                        self.pc = self.pc.wrapping_add(2);
                     }
                     // Sets the sound timer to VX
                     0x0018 => {
-                        self.sound_timer = self.v[x_reg];
-                        self.pc += 2;
+                        self.set_sound_timer(self.v[x_reg]);
+                        self.pc = self.pc.wrapping_add(2);
                     }
                     // Adds VX to I without VF being affected
                     0x001E => {
                         self.i += vx as usize;
-                        self.pc += 2;
+                        self.pc = self.pc.wrapping_add(2);
                     }
                     // Sets i to the location of the sprite for the character in VX
                     0x0029 => {
                         self.i = vx as usize;
-                        self.pc += 2;
+                        self.pc = self.pc.wrapping_add(2);
                     }
                     // Stores binary decimal representation of VX at address i, i + 1, and i + 2
                     0x0033 => {
                         self.memory[self.i] = self.v[x_reg] / 100;
                         self.memory[self.i + 1] = (self.v[x_reg] / 10) % 10;
                         self.memory[self.i + 2] = (self.v[x_reg] % 100) % 10;
-                        self.pc += 2;
+                        self.pc = self.pc.wrapping_add(2);
                     }
                     // Dump values from V0 to VX into memory starting at address i  with + 1 offset with i left unmodified
                     0x0055 => {
                         for (i_offset, v) in self.v[0..x_reg + 1].iter().enumerate() {
                             self.memory[self.i + i_offset] = *v;
                         }
-                        self.pc += 2;
+                        if self.quirks.memory_increment {
+                            self.i += x_reg + 1;
+                        }
+                        self.pc = self.pc.wrapping_add(2);
                     }
                     // Fills values from VO to VX with values from memory starting at address i with + 1 offest with i left unmodified
                     0x0065 => {
@@ -347,7 +623,10 @@ impl System {
                         for i_offset in 0..=x {
                             self.v[i_offset as usize] = self.memory[self.i + i_offset as usize];
                         }
-                        self.pc += 2;
+                        if self.quirks.memory_increment {
+                            self.i += x_reg + 1;
+                        }
+                        self.pc = self.pc.wrapping_add(2);
                     }
                     _ => {
                         panic!("ERROR: Unknown opcode: {:#X}", self.opcode);
@@ -359,22 +638,244 @@ impl System {
             }
         }
 
-        // Update timers
-        if self.delay_timer > 0 {
-            self.delay_timer -= 1;
+        self.cycle_in_frame += 1;
+    }
+
+    // The VIP's logic instructions leave VF cleared
+    fn reset_vf(&mut self) {
+        if self.quirks.vf_reset {
+            self.v[0xF] = 0;
         }
+    }
 
-        if self.sound_timer > 0 {
-            audio_unit.device.resume();
-            self.sound_timer -= 1;
+    // Register 8XY6 and 8XYE shift, VY on the VIP and VX in place on later interpreters
+    fn shift_source(&self, x_reg: usize, y_reg: usize) -> u8 {
+        if self.quirks.shift_vy {
+            self.v[y_reg]
         } else {
-            audio_unit.device.pause();
+            self.v[x_reg]
         }
+    }
 
-        // Print timer state to console
-        println!(
-            "Delay Timer: {}\nSound Timer: {}",
-            self.delay_timer, self.sound_timer
+    // FX0A, the key to store once it has been pressed and released, or just pressed with the quirk off
+    fn key_wait(&mut self, keys: &[bool; 16]) -> Option<u8> {
+        if let Some(key) = self.waiting_key {
+            if keys[key as usize] {
+                return None;
+            }
+            self.waiting_key = None;
+            return Some(key);
+        }
+
+        // Only a fresh press counts, a key already held when FX0A started has to be let go first
+        let pressed = (0..16u8).find(|&i| keys[i as usize] && !self.previous_keys[i as usize])?;
+        if self.quirks.key_wait_release {
+            self.waiting_key = Some(pressed);
+            None
+        } else {
+            // Taken, another FX0A this frame waits for the next press
+            self.previous_keys[pressed as usize] = true;
+            Some(pressed)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NO_KEYS: [bool; 16] = [false; 16];
+
+    fn boot(rom: &[u8], ipf: u32) -> System {
+        let mut system = System::new();
+        system.trace = false;
+        system.ipf = ipf;
+        system.init(rom).unwrap();
+        system
+    }
+
+    fn buzzer(system: &mut System, frames: usize) -> Vec<(f64, bool)> {
+        for _ in 0..frames {
+            system.run_frame(&NO_KEYS);
+        }
+        let events = system.take_buzzer_events();
+        events.iter().map(|event| (event.time, event.on)).collect()
+    }
+
+    fn assert_times(events: &[(f64, bool)], expected: &[(f64, bool)]) {
+        assert_eq!(events.len(), expected.len(), "{:?}", events);
+        for (&(time, on), &(expected_time, expected_on)) in events.iter().zip(expected) {
+            assert_eq!(on, expected_on, "{:?}", events);
+            assert!((time - expected_time).abs() < 1e-9, "{:?}", events);
+        }
+    }
+
+    #[test]
+    fn buzzer_sounds_for_the_sound_timer_over_60_seconds() {
+        // V[A] = 10, then FX18 as the third instruction of the first frame
+        let mut system = boot(&[0x6A, 0x0A, 0x6B, 0x00, 0xFA, 0x18, 0x12, 0x06], 4);
+        let on = 2.0 / 4.0 / 60.0;
+        assert_times(&buzzer(&mut system, 1), &[(on, true)]);
+        assert!(system.sound_on());
+
+        // The off event is stamped with the exact time even though it's seen at a frame's end
+        assert_times(&buzzer(&mut system, 9), &[]);
+        assert_times(&buzzer(&mut system, 1), &[(on + 10.0 / 60.0, false)]);
+        assert!(!system.sound_on());
+    }
+
+    #[test]
+    fn sound_timer_of_zero_stops_the_buzzer_straight_away() {
+        // FX18 with 10, then with 0 two instructions later
+        let rom = [0x6A, 0x0A, 0xFA, 0x18, 0x6B, 0x00, 0xFB, 0x18, 0x12, 0x08];
+        let mut system = boot(&rom, 4);
+        assert_times(
+            &buzzer(&mut system, 20),
+            &[(1.0 / 4.0 / 60.0, true), (3.0 / 4.0 / 60.0, false)],
+        );
+        // A zero while the buzzer is already off adds nothing
+        let mut other = boot(&[0x6B, 0x00, 0xFB, 0x18, 0x12, 0x04], 4);
+        assert_times(&buzzer(&mut other, 5), &[]);
+    }
+
+    #[test]
+    fn retriggering_moves_the_end_without_another_start() {
+        // FX18 with 10, then with 3 while it's still sounding
+        let rom = [0x6A, 0x0A, 0xFA, 0x18, 0x6B, 0x03, 0xFB, 0x18, 0x12, 0x08];
+        let mut system = boot(&rom, 4);
+        assert_times(
+            &buzzer(&mut system, 20),
+            &[
+                (1.0 / 4.0 / 60.0, true),
+                (3.0 / 4.0 / 60.0 + 3.0 / 60.0, false),
+            ],
         );
     }
+
+    fn keys(down: &[usize]) -> [bool; 16] {
+        let mut keys = [false; 16];
+        for &key in down {
+            keys[key] = true;
+        }
+        keys
+    }
+
+    // F00A then a jump to itself, so pc shows whether the wait is over
+    const WAIT: [u8; 4] = [0xF0, 0x0A, 0x12, 0x02];
+
+    #[test]
+    fn key_wait_takes_a_key_once_it_is_released() {
+        let mut system = boot(&WAIT, 4);
+        system.run_frame(&keys(&[]));
+        system.run_frame(&keys(&[5]));
+        system.run_frame(&keys(&[5]));
+        assert_eq!(system.pc, 0x200);
+        system.run_frame(&keys(&[]));
+        assert_eq!(system.pc, 0x202);
+        assert_eq!(system.v[0], 5);
+    }
+
+    #[test]
+    fn key_wait_ignores_a_key_held_before_it_started() {
+        // V0 = 0 takes the first frame, with key 5 already down
+        let mut system = boot(&[0x60, 0x00, 0xF0, 0x0A, 0x12, 0x04], 1);
+        system.run_frame(&keys(&[5]));
+        system.run_frame(&keys(&[5]));
+        system.run_frame(&keys(&[]));
+        assert_eq!(system.pc, 0x202);
+
+        system.run_frame(&keys(&[5]));
+        system.run_frame(&keys(&[]));
+        assert_eq!(system.pc, 0x204);
+        assert_eq!(system.v[0], 5);
+    }
+
+    #[test]
+    fn key_wait_press_only_quirk_takes_the_press() {
+        let mut system = boot(&WAIT, 4);
+        system.quirks.key_wait_release = false;
+        system.run_frame(&keys(&[9]));
+        assert_eq!(system.pc, 0x202);
+        assert_eq!(system.v[0], 9);
+    }
+
+    #[test]
+    fn one_press_only_ends_one_key_wait() {
+        // F00A then F10A in the same frame, the second needs a new press
+        let mut system = boot(&[0xF0, 0x0A, 0xF1, 0x0A, 0x12, 0x04], 4);
+        system.quirks.key_wait_release = false;
+        system.run_frame(&keys(&[5]));
+        assert_eq!((system.pc, system.v[0]), (0x202, 5));
+        system.run_frame(&keys(&[5]));
+        system.run_frame(&keys(&[]));
+        assert_eq!(system.pc, 0x202);
+        system.run_frame(&keys(&[7]));
+        assert_eq!((system.pc, system.v[1]), (0x204, 7));
+
+        // Waiting for release, the second wait starts after the release and needs its own press
+        let mut system = boot(&[0xF0, 0x0A, 0xF1, 0x0A, 0x12, 0x04], 4);
+        system.run_frame(&keys(&[5]));
+        system.run_frame(&keys(&[]));
+        assert_eq!((system.pc, system.v[0]), (0x202, 5));
+        system.run_frame(&keys(&[5]));
+        system.run_frame(&keys(&[]));
+        assert_eq!((system.pc, system.v[1]), (0x204, 5));
+    }
+
+    #[test]
+    fn load_address_and_entry_point_have_to_fit_in_memory() {
+        let mut system = System::new();
+        assert!(system.set_load_address(0xFFF, 0xFFE).is_ok());
+        assert_eq!(
+            system.set_load_address(0x1000, 0x200),
+            Err("load address 0x1000 doesn't fit in the 4096 bytes of memory".to_string())
+        );
+        // The entry point needs both bytes of an instruction
+        assert_eq!(
+            system.set_load_address(0x200, 0xFFF),
+            Err("entry point 0xFFF doesn't fit in the 4096 bytes of memory".to_string())
+        );
+
+        system.set_memory_size(65536);
+        assert!(system.set_load_address(0x200, 0xFFFE).is_ok());
+        assert!(system.set_load_address(0x200, 0xFFFF).is_err());
+    }
+
+    #[test]
+    fn roms_have_to_fit_after_the_load_address() {
+        let mut system = System::new();
+        system.trace = false;
+        system.set_load_address(0xFF0, 0xFF0).unwrap();
+        assert!(system.init(&[0x12; 16]).is_ok());
+        assert!(matches!(
+            system.init(&[0x12; 17]),
+            Err(CartridgeError::TooLarge { size: 17, max: 16 })
+        ));
+    }
+
+    #[test]
+    fn running_off_the_end_of_memory_wraps_around() {
+        // 6105 in the last two bytes of XO-CHIP's 64K, then 6207 at the start
+        let mut system = System::new();
+        system.trace = false;
+        system.set_memory_size(65536);
+        system.set_load_address(0xFFFE, 0xFFFE).unwrap();
+        system.init(&[0x61, 0x05]).unwrap();
+        system.memory[..2].copy_from_slice(&[0x62, 0x07]);
+        system.emulate_cycle(&NO_KEYS);
+        assert_eq!((system.pc, system.v[1]), (0, 5));
+        system.emulate_cycle(&NO_KEYS);
+        assert_eq!((system.pc, system.v[2]), (2, 7));
+
+        // A skip over the last instruction in 4K
+        let mut system = System::new();
+        system.trace = false;
+        system.set_load_address(0xFFC, 0xFFC).unwrap();
+        system.init(&[0x30, 0x00, 0x00, 0x00]).unwrap();
+        system.memory[..2].copy_from_slice(&[0x62, 0x07]);
+        system.emulate_cycle(&NO_KEYS);
+        assert_eq!(system.pc, 0x1000);
+        system.emulate_cycle(&NO_KEYS);
+        assert_eq!((system.pc, system.v[2]), (2, 7));
+    }
 }
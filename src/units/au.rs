@@ -1,59 +1,425 @@
+use crate::capture::wav::WavWriter;
+use crate::system::BuzzerEvent;
 use sdl2::{
     audio::AudioSpecDesired,
     audio::{AudioCallback, AudioDevice},
-    Sdl,
+    AudioSubsystem,
 };
+use std::collections::VecDeque;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+// Shape of the buzzer tone
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Waveform {
+    Square,
+    // Square wave high for the given fraction of each period
+    Pulse(f32),
+    Triangle,
+    Sine,
+    Noise,
+}
+
+impl FromStr for Waveform {
+    type Err = String;
+
+    // Parses square, pulse:DUTY, triangle, sine or noise
+    fn from_str(s: &str) -> Result<Waveform, String> {
+        let s = s.to_ascii_lowercase();
+        match s.split_once(':') {
+            Some(("pulse", duty)) => match duty.parse::<f32>() {
+                Ok(duty) if duty > 0.0 && duty < 1.0 => Ok(Waveform::Pulse(duty)),
+                _ => Err(format!(
+                    "pulse duty cycle must be between 0 and 1: {}",
+                    duty
+                )),
+            },
+            Some(_) => Err(format!("unknown waveform: {}", s)),
+            None => match s.as_str() {
+                "square" => Ok(Waveform::Square),
+                "pulse" => Ok(Waveform::Pulse(0.25)),
+                "triangle" => Ok(Waveform::Triangle),
+                "sine" => Ok(Waveform::Sine),
+                "noise" => Ok(Waveform::Noise),
+                _ => Err(format!("unknown waveform: {}", s)),
+            },
+        }
+    }
+}
+
+// Settings for the buzzer sound
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Tone {
+    pub frequency: f32,
+    pub waveform: Waveform,
+    // Output level between 0.0 and 1.0
+    pub volume: f32,
+    // Seconds to fade in when the buzzer starts and out when it stops, avoids clicks
+    pub attack: f32,
+    pub release: f32,
+}
+
+impl Default for Tone {
+    fn default() -> Self {
+        Tone {
+            frequency: 440.0,
+            waveform: Waveform::Square,
+            volume: 0.25,
+            attack: 0.005,
+            release: 0.005,
+        }
+    }
+}
+
+// Most audio kept queued for the device, anything beyond this is dropped to keep latency down
+const MAX_QUEUED_SECONDS: f64 = 0.1;
+// Audio queued before playback starts, two 60hz frames, so frame to frame jitter doesn't run the ring dry
+const PREFILL_SECONDS: f64 = 2.0 / 60.0;
 
 // Audio Unit
 pub struct AU {
-    pub device: AudioDevice<SquareWave>,
+    // None for the null sink used when running headless
+    pub device: Option<AudioDevice<RingPlayer>>,
+    pub sample_rate: u32,
+    pub generator: ToneGenerator,
+    ring: Arc<Mutex<VecDeque<f32>>>,
+    // Buzzer state and how many samples of emulated time have been rendered so far
+    buzzer: bool,
+    rendered: u64,
+    // Samples rendered with the buzzer on
+    beeping: u64,
+    recording: Option<WavWriter>,
 }
 
 impl AU {
-    pub fn new(context: &Sdl) -> AU {
-        let audio_subsystem = context.audio().unwrap();
-
+    pub fn new(audio_subsystem: &AudioSubsystem, tone: Tone) -> AU {
         let desired_spec = AudioSpecDesired {
             freq: Some(44_100),
             channels: Some(1),
-            samples: None,
+            samples: Some(512),
         };
 
+        let ring = Arc::new(Mutex::new(VecDeque::new()));
+
         let device = audio_subsystem
             .open_playback(None, &desired_spec, |spec| {
-                println!("{:?}", spec);
-
                 // init callback device
-                SquareWave {
-                    phase_inc: 440.0 / spec.freq as f32,
-                    phase: 0.0,
-                    volume: 0.25,
+                RingPlayer {
+                    ring: ring.clone(),
+                    prefill: (f64::from(spec.freq) * PREFILL_SECONDS) as usize,
+                    primed: false,
                 }
             })
             .unwrap();
 
-        AU { device }
+        // The device always runs, playing whatever has been rendered into the ring
+        device.resume();
+        let sample_rate = device.spec().freq as u32;
+
+        AU {
+            device: Some(device),
+            sample_rate,
+            generator: ToneGenerator::new(tone, sample_rate),
+            ring,
+            buzzer: false,
+            rendered: 0,
+            beeping: 0,
+            recording: None,
+        }
+    }
+
+    // Null sink that never touches SDL, it still renders the timeline for recordings and beep time
+    pub fn headless(tone: Tone) -> AU {
+        let sample_rate = 44_100;
+        AU {
+            device: None,
+            sample_rate,
+            generator: ToneGenerator::new(tone, sample_rate),
+            ring: Arc::new(Mutex::new(VecDeque::new())),
+            buzzer: false,
+            rendered: 0,
+            beeping: 0,
+            recording: None,
+        }
+    }
+
+    // Render samples up to the given emulated time, switching the buzzer at each event's exact sample
+    pub fn render(&mut self, events: &[BuzzerEvent], until: f64) {
+        let mut samples = Vec::new();
+        for event in events {
+            let at = self.sample_at(event.time);
+            self.render_until(&mut samples, at);
+            self.buzzer = event.on;
+        }
+        let end = self.sample_at(until);
+        self.render_until(&mut samples, end);
+
+        self.write_recording(&samples);
+        if self.device.is_none() {
+            return;
+        }
+
+        let mut ring = self.ring.lock().unwrap();
+        ring.extend(samples);
+        let limit = (f64::from(self.sample_rate) * MAX_QUEUED_SECONDS) as usize;
+        if ring.len() > limit {
+            let excess = ring.len() - limit;
+            ring.drain(..excess);
+        }
+    }
+
+    fn render_until(&mut self, samples: &mut Vec<f32>, at: u64) {
+        let count = at.saturating_sub(self.rendered);
+        self.generator.generate_into(samples, count, self.buzzer);
+        if self.buzzer {
+            self.beeping += count;
+        }
+        self.rendered = self.rendered.max(at);
+    }
+
+    // Total emulated seconds the buzzer has sounded
+    pub fn beep_time(&self) -> f64 {
+        self.beeping as f64 / f64::from(self.sample_rate)
+    }
+
+    // Sample index for a point in emulated time
+    fn sample_at(&self, time: f64) -> u64 {
+        (time * f64::from(self.sample_rate)).round() as u64
+    }
+
+    pub fn volume(&self) -> f32 {
+        self.generator.tone.volume
+    }
+
+    pub fn set_volume(&mut self, volume: f32) {
+        self.generator.tone.volume = volume.clamp(0.0, 1.0);
+    }
+
+    pub fn is_muted(&self) -> bool {
+        self.generator.muted
+    }
+
+    pub fn toggle_mute(&mut self) -> bool {
+        self.generator.muted = !self.generator.muted;
+        self.generator.muted
+    }
+
+    // Start writing every rendered sample, silence included, to a WAV file
+    pub fn start_recording(&mut self, path: &Path) -> io::Result<()> {
+        self.stop_recording()?;
+        self.recording = Some(WavWriter::create(path, self.sample_rate)?);
+        Ok(())
+    }
+
+    // Finish the current recording, returning how many samples it holds
+    pub fn stop_recording(&mut self) -> io::Result<Option<u32>> {
+        match self.recording.take() {
+            Some(writer) => {
+                let samples = writer.samples();
+                writer.finish()?;
+                Ok(Some(samples))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn write_recording(&mut self, samples: &[f32]) {
+        if let Some(writer) = self.recording.as_mut() {
+            if let Err(e) = writer.write_samples(samples) {
+                eprintln!("ERROR: Audio recording stopped: {}", e);
+                self.recording = None;
+            }
+        }
     }
 }
 
-pub struct SquareWave {
-    phase_inc: f32,
-    phase: f32,
-    volume: f32,
+// Device callback feeding queued samples to SDL, playing silence until enough is queued
+pub struct RingPlayer {
+    ring: Arc<Mutex<VecDeque<f32>>>,
+    prefill: usize,
+    primed: bool,
 }
 
-impl AudioCallback for SquareWave {
+impl AudioCallback for RingPlayer {
     type Channel = f32;
 
     fn callback(&mut self, out: &mut [f32]) {
-        // gen square wave
+        let mut ring = self.ring.lock().unwrap();
+        if !self.primed && ring.len() < self.prefill {
+            out.fill(0.0);
+            return;
+        }
+        self.primed = true;
+
         for x in out.iter_mut() {
-            *x = if self.phase <= 0.5 {
-                self.volume
-            } else {
-                -self.volume
+            *x = match ring.pop_front() {
+                Some(sample) => sample,
+                None => {
+                    // Ran dry, e.g. while paused, so build the cushion back up before resuming
+                    self.primed = false;
+                    0.0
+                }
             };
-            self.phase = (self.phase + self.phase_inc) % 1.0;
         }
     }
 }
+
+pub struct ToneGenerator {
+    pub tone: Tone,
+    pub muted: bool,
+    sample_rate: f32,
+    phase: f32,
+    // Envelope level between 0.0 (silent) and 1.0 (full volume)
+    level: f32,
+    noise: f32,
+    noise_state: u32,
+}
+
+impl ToneGenerator {
+    pub fn new(tone: Tone, sample_rate: u32) -> ToneGenerator {
+        ToneGenerator {
+            tone,
+            muted: false,
+            sample_rate: sample_rate as f32,
+            phase: 0.0,
+            level: 0.0,
+            noise: 0.0,
+            noise_state: 0x1234_5678,
+        }
+    }
+
+    // Append count samples with the buzzer on or off, usable without an audio device
+    pub fn generate_into(&mut self, out: &mut Vec<f32>, count: u64, on: bool) {
+        let start = out.len();
+        out.resize(start + count as usize, 0.0);
+        self.generate(&mut out[start..], on);
+    }
+
+    // Fill a buffer with samples, fading in or out towards the buzzer state
+    pub fn generate(&mut self, out: &mut [f32], on: bool) {
+        let phase_inc = self.tone.frequency / self.sample_rate;
+        let attack_step = ToneGenerator::envelope_step(self.tone.attack, self.sample_rate);
+        let release_step = ToneGenerator::envelope_step(self.tone.release, self.sample_rate);
+        let volume = if self.muted { 0.0 } else { self.tone.volume };
+
+        for x in out.iter_mut() {
+            if on {
+                self.level = (self.level + attack_step).min(1.0);
+            } else {
+                self.level = (self.level - release_step).max(0.0);
+            }
+
+            *x = self.wave() * self.level * volume;
+
+            let next = self.phase + phase_inc;
+            // Pick a new noise value every half period so the noise keeps some pitch
+            if (next * 2.0).floor() != (self.phase * 2.0).floor() {
+                self.noise = self.next_noise();
+            }
+            self.phase = next % 1.0;
+        }
+    }
+
+    // Current sample of the waveform between -1.0 and 1.0
+    fn wave(&self) -> f32 {
+        match self.tone.waveform {
+            Waveform::Square => {
+                if self.phase < 0.5 {
+                    1.0
+                } else {
+                    -1.0
+                }
+            }
+            Waveform::Pulse(duty) => {
+                if self.phase < duty {
+                    1.0
+                } else {
+                    -1.0
+                }
+            }
+            Waveform::Triangle => 1.0 - 4.0 * (self.phase - 0.5).abs(),
+            Waveform::Sine => (self.phase * std::f32::consts::TAU).sin(),
+            Waveform::Noise => self.noise,
+        }
+    }
+
+    // xorshift32, mapped to -1.0..1.0
+    fn next_noise(&mut self) -> f32 {
+        let mut x = self.noise_state;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.noise_state = x;
+        (x as f32 / u32::MAX as f32) * 2.0 - 1.0
+    }
+
+    // Level change per sample for a fade of the given length
+    fn envelope_step(seconds: f32, sample_rate: f32) -> f32 {
+        if seconds <= 0.0 {
+            1.0
+        } else {
+            1.0 / (seconds * sample_rate)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn beeps_last_exactly_as_long_as_the_timeline_says() {
+        // No envelope, so every sample with the buzzer on is non-zero
+        let tone = Tone {
+            attack: 0.0,
+            release: 0.0,
+            ..Tone::default()
+        };
+        let mut audio_unit = AU::headless(tone);
+        let path = std::env::temp_dir().join(format!("cj-8-test-beep-{}.wav", std::process::id()));
+        audio_unit.start_recording(&path).unwrap();
+
+        // A sound timer of 10 set a tenth of a second in, rendered over two calls
+        let events = [
+            BuzzerEvent {
+                time: 0.1,
+                on: true,
+            },
+            BuzzerEvent {
+                time: 0.1 + 10.0 / 60.0,
+                on: false,
+            },
+        ];
+        audio_unit.render(&events[..1], 0.2);
+        audio_unit.render(&events[1..], 0.5);
+        assert_eq!(audio_unit.stop_recording().unwrap(), Some(22_050));
+        assert!((audio_unit.beep_time() - 10.0 / 60.0).abs() < 1e-9);
+
+        let data = std::fs::read(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        let samples: Vec<i16> = data[44..]
+            .chunks(2)
+            .map(|sample| i16::from_le_bytes([sample[0], sample[1]]))
+            .collect();
+        assert_eq!(samples.len(), 22_050);
+        let sounding: Vec<usize> = (0..samples.len()).filter(|&i| samples[i] != 0).collect();
+        assert_eq!(sounding.len(), 7_350);
+        assert_eq!(sounding.first(), Some(&4_410));
+        assert_eq!(sounding.last(), Some(&11_759));
+    }
+
+    #[test]
+    fn events_in_the_past_switch_at_the_current_sample() {
+        let mut audio_unit = AU::headless(Tone::default());
+        audio_unit.render(&[], 1.0);
+        // An event before what was already rendered can't go back, it starts the beep now
+        let events = [BuzzerEvent {
+            time: 0.5,
+            on: true,
+        }];
+        audio_unit.render(&events, 1.5);
+        assert!((audio_unit.beep_time() - 0.5).abs() < 1e-9);
+    }
+}
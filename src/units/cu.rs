@@ -1,14 +1,267 @@
+use crate::database::{Database, RomInfo};
+use crate::detect::{self, Detection};
+use crate::hash;
+use crate::octo::Cartridge;
+use crate::patch::{self, PatchFormat};
+use crate::romfile::{self, Format, UnpackError};
+use crate::variant::Variant;
+use ::std::error::Error;
+use ::std::fmt;
 use ::std::fs;
+use ::std::io::{self, Read};
+use ::std::path::{Path, PathBuf};
+
+// Why a ROM couldn't be loaded
+#[derive(Debug)]
+pub enum CartridgeError {
+    NotFound(PathBuf),
+    // Anything else going wrong while reading, e.g. permissions or a directory
+    Unreadable(String, io::Error),
+    Empty(String),
+    // Bigger than the memory after the load address
+    TooLarge { size: usize, max: usize },
+    // A zip, gzip or text encoded ROM that couldn't be unpacked
+    Unpack(String, UnpackError),
+    // The patch file's name and what was wrong with it
+    Patch(String, String),
+}
+
+impl fmt::Display for CartridgeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CartridgeError::NotFound(path) => write!(f, "ROM not found: {}", path.display()),
+            CartridgeError::Unreadable(name, e) => write!(f, "Unable to read ROM {}: {}", name, e),
+            CartridgeError::Empty(name) => write!(f, "ROM is empty: {}", name),
+            CartridgeError::TooLarge { size, max } => {
+                write!(f, "ROM is {} bytes, only {} fit in memory", size, max)
+            }
+            CartridgeError::Unpack(name, e) => write!(f, "Unable to unpack ROM {}: {}", name, e),
+            CartridgeError::Patch(name, e) => write!(f, "Unable to apply patch {}: {}", name, e),
+        }
+    }
+}
+
+impl Error for CartridgeError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            CartridgeError::Unreadable(_, e) => Some(e),
+            _ => None,
+        }
+    }
+}
 
 // Cartridge Unit
 pub struct CU {
     pub buffer: Vec<u8>,
+    // Where the ROM came from, for messages
+    pub name: String,
+    // How the ROM was packed or encoded on the way in, outermost first, empty for a plain binary
+    pub formats: Vec<Format>,
+    // Options an Octo cartridge was saved with
+    pub cartridge: Option<Cartridge>,
+    // Patches applied since loading, in order
+    pub patches: Vec<(String, PatchFormat)>,
 }
 
 impl CU {
-    pub fn new(path: &str) -> Result<CU, ()> {
-        let buffer = fs::read(path).expect("ERROR: Unable to locate ROM");
+    // Read a ROM file, - reads standard input. archive.zip#name picks a file inside a zip
+    pub fn new<P: AsRef<Path>>(path: P) -> Result<CU, CartridgeError> {
+        let (path, entry) = split_entry(path.as_ref());
+        CU::open(path, entry)
+    }
+
+    // Read a ROM file, taking the named file if it's a zip
+    pub fn open<P: AsRef<Path>>(path: P, entry: Option<&str>) -> Result<CU, CartridgeError> {
+        let path = path.as_ref();
+        if path == Path::new("-") {
+            let mut buffer = Vec::new();
+            io::stdin()
+                .lock()
+                .read_to_end(&mut buffer)
+                .map_err(|e| CartridgeError::Unreadable("standard input".to_string(), e))?;
+            return CU::from_vec(buffer, "standard input", entry);
+        }
+
+        let name = path.display().to_string();
+        let buffer = fs::read(path).map_err(|e| match e.kind() {
+            io::ErrorKind::NotFound => CartridgeError::NotFound(path.to_path_buf()),
+            _ => CartridgeError::Unreadable(name.clone(), e),
+        })?;
+        CU::from_vec(buffer, &name, entry)
+    }
+
+    pub fn from_reader<R: Read>(mut reader: R, name: &str) -> Result<CU, CartridgeError> {
+        let mut buffer = Vec::new();
+        reader
+            .read_to_end(&mut buffer)
+            .map_err(|e| CartridgeError::Unreadable(name.to_string(), e))?;
+        CU::from_vec(buffer, name, None)
+    }
+
+    // A ROM already in memory, e.g. embedded in another program
+    pub fn from_bytes(bytes: &[u8], name: &str) -> Result<CU, CartridgeError> {
+        CU::from_vec(bytes.to_vec(), name, None)
+    }
+
+    // Archives, Octo cartridges and text encodings are recognised by their contents, whatever the file is called
+    fn from_vec(buffer: Vec<u8>, name: &str, entry: Option<&str>) -> Result<CU, CartridgeError> {
+        if buffer.is_empty() {
+            return Err(CartridgeError::Empty(name.to_string()));
+        }
+        let unpacked = romfile::unpack(buffer, entry)
+            .map_err(|e| CartridgeError::Unpack(name.to_string(), e))?;
+        if unpacked.rom.is_empty() {
+            return Err(CartridgeError::Empty(name.to_string()));
+        }
+        Ok(CU {
+            buffer: unpacked.rom,
+            name: name.to_string(),
+            formats: unpacked.formats,
+            cartridge: unpacked.cartridge,
+            patches: Vec::new(),
+        })
+    }
+
+    // Apply an IPS or BPS patch file, BPS patches are checked against the ROM they were made for
+    pub fn patch<P: AsRef<Path>>(&mut self, path: P) -> Result<PatchFormat, CartridgeError> {
+        let path = path.as_ref();
+        let name = path.display().to_string();
+        let data =
+            fs::read(path).map_err(|e| CartridgeError::Patch(name.clone(), e.to_string()))?;
+        let (buffer, format) = patch::apply(&self.buffer, &data)
+            .map_err(|e| CartridgeError::Patch(name.clone(), e))?;
+        if buffer.is_empty() {
+            return Err(CartridgeError::Patch(
+                name,
+                "the patched ROM is empty".to_string(),
+            ));
+        }
+        self.buffer = buffer;
+        self.patches.push((name, format));
+        Ok(format)
+    }
+
+    pub fn size(&self) -> usize {
+        self.buffer.len()
+    }
+
+    pub fn sha1(&self) -> String {
+        hash::sha1_hex(&self.buffer)
+    }
+
+    pub fn crc32(&self) -> u32 {
+        hash::crc32(&self.buffer)
+    }
+
+    // Check the ROM fits in the platform's memory after the load address
+    pub fn check_fits(&self, platform: Variant) -> Result<(), CartridgeError> {
+        check_fits(&self.buffer, platform.max_rom_size())
+    }
+
+    // Look the ROM up in the ROM database by its SHA-1
+    pub fn lookup(&self) -> Option<RomInfo> {
+        Database::load().lookup(&self.sha1())
+    }
+
+    // Guess the platform from the instructions the ROM uses, for ROMs the database doesn't know
+    pub fn detect(&self, load_address: usize, entry_point: usize) -> Detection {
+        detect::detect(&self.buffer, load_address, entry_point)
+    }
+}
+
+// The file a ROM path names and the zip entry after a # in it, if the file exists without it
+pub fn split_entry(path: &Path) -> (&Path, Option<&str>) {
+    if !path.exists() {
+        if let Some((archive, entry)) = path.to_str().and_then(|path| path.rsplit_once('#')) {
+            if Path::new(archive).is_file() {
+                return (Path::new(archive), Some(entry));
+            }
+        }
+    }
+    (path, None)
+}
+
+// Shared with System::init, which checks against the memory it actually has
+pub fn check_fits(rom: &[u8], max: usize) -> Result<(), CartridgeError> {
+    if rom.len() > max {
+        return Err(CartridgeError::TooLarge {
+            size: rom.len(),
+            max,
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Fails every read, like a pipe that broke
+    struct Broken;
+
+    impl Read for Broken {
+        fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+            Err(io::Error::new(io::ErrorKind::BrokenPipe, "broken"))
+        }
+    }
+
+    #[test]
+    fn missing_files_are_not_found() {
+        let path = std::env::temp_dir().join(format!("cj-8-missing-{}.ch8", std::process::id()));
+        match CU::new(&path) {
+            Err(CartridgeError::NotFound(missing)) => assert_eq!(missing, path),
+            other => panic!("expected NotFound, got {:?}", other.err()),
+        }
+        let message = CU::new(&path).err().unwrap().to_string();
+        assert_eq!(message, format!("ROM not found: {}", path.display()));
+    }
+
+    #[test]
+    fn empty_roms_are_rejected() {
+        let path = std::env::temp_dir().join(format!("cj-8-empty-{}.ch8", std::process::id()));
+        fs::write(&path, b"").unwrap();
+        let from_file = CU::new(&path);
+        fs::remove_file(&path).unwrap();
+        assert!(matches!(from_file, Err(CartridgeError::Empty(_))));
+
+        assert!(matches!(
+            CU::from_bytes(&[], "bytes"),
+            Err(CartridgeError::Empty(name)) if name == "bytes"
+        ));
+        let message = CU::from_reader(io::empty(), "reader")
+            .err()
+            .unwrap()
+            .to_string();
+        assert_eq!(message, "ROM is empty: reader");
+    }
+
+    #[test]
+    fn read_errors_keep_their_cause() {
+        let e = CU::from_reader(Broken, "pipe").err().unwrap();
+        assert!(matches!(&e, CartridgeError::Unreadable(name, _) if name == "pipe"));
+        assert_eq!(e.to_string(), "Unable to read ROM pipe: broken");
+        assert!(e.source().is_some());
+    }
+
+    #[test]
+    fn roms_have_to_fit_after_the_load_address() {
+        let fits = CU::from_bytes(&[0x12; 3584], "fits").unwrap();
+        assert!(fits.check_fits(Variant::Chip8).is_ok());
 
-        Ok(CU { buffer })
+        let big = CU::from_bytes(&[0x12; 3585], "big").unwrap();
+        assert_eq!(big.size(), 3585);
+        match big.check_fits(Variant::Schip) {
+            Err(CartridgeError::TooLarge { size, max }) => assert_eq!((size, max), (3585, 3584)),
+            other => panic!("expected TooLarge, got {:?}", other.err()),
+        }
+        assert_eq!(
+            big.check_fits(Variant::Chip8).err().unwrap().to_string(),
+            "ROM is 3585 bytes, only 3584 fit in memory"
+        );
+        assert!(big.check_fits(Variant::XoChip).is_ok());
+        assert!(matches!(
+            big.check_fits(Variant::Eti660),
+            Err(CartridgeError::TooLarge { max: 2560, .. })
+        ));
     }
 }
@@ -1,51 +1,278 @@
-use sdl2::{pixels::Color, rect::Rect, render::Canvas, Sdl};
+use crate::capture::video::Recorder;
+use crate::palette::Palette;
+use crate::units::osd::{self, Osd};
+use sdl2::{
+    pixels::Color,
+    rect::Rect,
+    render::{BlendMode, Canvas},
+    video::{FullscreenType, Window},
+    VideoSubsystem,
+};
+use std::io;
+use std::path::Path;
 
 // Graphical Unit
 pub struct GU {
-    pub canvas: Canvas<sdl2::video::Window>,
+    // None when running headless
+    pub canvas: Option<Canvas<Window>>,
+    // Offscreen copy of the last presented frame
+    pub frame: [[u16; 64]; 32],
+    headless_scale: u32,
+    // Emulated seconds used to time recordings started at power on, the wall clock is used when unset
+    pub clock: Option<f64>,
+    pub palette: Palette,
+    pub recorder: Option<Recorder>,
+    // Only scale by whole numbers, leaving a wider border instead of uneven pixels
+    pub integer_scaling: bool,
+    pub osd: Osd,
 }
 
 impl GU {
-    pub fn new(context: &Sdl, title: &str, window_width: u32, window_height: u32) -> GU {
-        let video_subsystem = context.video().unwrap();
-
+    pub fn new(
+        video_subsystem: &VideoSubsystem,
+        title: &str,
+        window_width: u32,
+        window_height: u32,
+    ) -> GU {
         let window = video_subsystem
             .window(title, window_width, window_height)
             .position_centered()
+            .resizable()
             .build()
             .unwrap();
 
-        let canvas = window.into_canvas().build().unwrap();
+        let mut canvas = window.into_canvas().build().unwrap();
+        let _ = canvas.window_mut().set_minimum_size(64, 32);
+        canvas.set_blend_mode(BlendMode::Blend);
 
-        GU { canvas }
+        GU {
+            canvas: Some(canvas),
+            frame: [[0; 64]; 32],
+            headless_scale: 1,
+            clock: None,
+            palette: Palette::default(),
+            recorder: None,
+            integer_scaling: false,
+            osd: Osd::new(),
+        }
+    }
+
+    // Framebuffer only display that never touches SDL, scale stands in for the window size
+    pub fn headless(scale: u32) -> GU {
+        GU {
+            canvas: None,
+            frame: [[0; 64]; 32],
+            headless_scale: scale.max(1),
+            clock: None,
+            palette: Palette::default(),
+            recorder: None,
+            integer_scaling: false,
+            osd: Osd::new(),
+        }
     }
 
     pub fn init(&mut self) {
-        self.canvas.clear();
-        self.canvas.set_draw_color(Color::RGB(0, 0, 0));
-        self.canvas.present();
+        if let Some(canvas) = self.canvas.as_mut() {
+            canvas.clear();
+            canvas.set_draw_color(Color::RGB(0, 0, 0));
+            canvas.present();
+        }
     }
 
-    pub fn draw(&mut self, scale: u32, gfx: &[[u16; 64]; 32]) {
+    // Present a new frame and pass it on to the recorder
+    pub fn draw(&mut self, gfx: &[[u16; 64]; 32]) {
+        self.frame = *gfx;
+        self.redraw(gfx);
+        self.osd.frame_presented();
+        // Hand the presented frame to the recorder, stopping it if the file can't be written
+        if let Some(recorder) = self.recorder.as_mut() {
+            let pushed = match self.clock {
+                Some(seconds) => recorder.push_at(gfx, seconds),
+                None => recorder.push(gfx),
+            };
+            if let Err(e) = pushed {
+                eprintln!("ERROR: Recording stopped: {}", e);
+                self.recorder = None;
+            }
+        }
+    }
+
+    // Present a frame again without recording it, e.g. after the window changed size
+    pub fn redraw(&mut self, gfx: &[[u16; 64]; 32]) {
+        let canvas = match self.canvas.as_mut() {
+            Some(canvas) => canvas,
+            None => return,
+        };
+        let output = canvas.output_size().unwrap_or((64, 32));
+        let (offset_x, offset_y, pixel) = GU::viewport(output, 64, 32, self.integer_scaling);
+
+        // Letterbox the unused area
+        canvas.set_draw_color(Color::RGB(0, 0, 0));
+        canvas.clear();
+
         for (y, row) in gfx.iter().enumerate() {
             for (x, &col) in row.iter().enumerate() {
-                let x = (x as u32) * scale;
-                let y = (y as u32) * scale;
+                // Round both edges so fractional scales leave no gaps between cells
+                let left = offset_x + (x as f32 * pixel).round() as i32;
+                let top = offset_y + (y as f32 * pixel).round() as i32;
+                let right = offset_x + ((x + 1) as f32 * pixel).round() as i32;
+                let bottom = offset_y + ((y + 1) as f32 * pixel).round() as i32;
 
-                self.canvas.set_draw_color(GU::color(col));
-                let _ = self
-                    .canvas
-                    .fill_rect(Rect::new(x as i32, y as i32, scale, scale));
+                let [r, g, b] = self.palette.color(col);
+                canvas.set_draw_color(Color::RGB(r, g, b));
+                let _ = canvas.fill_rect(Rect::new(
+                    left,
+                    top,
+                    (right - left).max(1) as u32,
+                    (bottom - top).max(1) as u32,
+                ));
             }
         }
-        self.canvas.present();
+        GU::draw_osd(canvas, &self.osd);
+        canvas.present();
+    }
+
+    // Overlay counters, messages and the pause banner in window pixels
+    fn draw_osd(canvas: &mut Canvas<Window>, osd: &Osd) {
+        let (width, height) = canvas.output_size().unwrap_or((64, 32));
+        // Keep text readable but small, one font pixel per 160 window rows
+        let px = (height / 160).max(1) as i32;
+        let line = (osd::GLYPH_HEIGHT as i32 + 2) * px;
+        let margin = 2 * px;
+
+        if let Some(stats) = osd.stats() {
+            GU::draw_text(canvas, &stats, margin, margin, px);
+        }
+
+        if let Some(status) = osd.status() {
+            let x = width as i32 - margin - GU::text_width(status, px);
+            GU::draw_text(canvas, status, x, margin, px);
+        }
+
+        let messages = osd.messages();
+        let mut y = height as i32 - margin - line * messages.len() as i32;
+        for message in messages {
+            GU::draw_text(canvas, &message, margin, y, px);
+            y += line;
+        }
+
+        if let Some(banner) = osd.banner() {
+            let px = px * 2;
+            let text_width = GU::text_width(banner, px);
+            let x = (width as i32 - text_width) / 2;
+            let y = (height as i32 - osd::GLYPH_HEIGHT as i32 * px) / 2;
+            GU::draw_text(canvas, banner, x, y, px);
+        }
+    }
+
+    fn text_width(text: &str, px: i32) -> i32 {
+        text.chars().count() as i32 * (osd::GLYPH_WIDTH as i32 + 1) * px - px
     }
 
-    fn color(i: u16) -> Color {
-        if i == 0 {
-            Color::RGB(0, 0, 0)
+    // Draw text on a translucent backing box so it stays readable over any palette
+    fn draw_text(canvas: &mut Canvas<Window>, text: &str, x: i32, y: i32, px: i32) {
+        let backing = Rect::new(
+            x - px,
+            y - px,
+            (GU::text_width(text, px) + 2 * px).max(0) as u32,
+            ((osd::GLYPH_HEIGHT as i32 + 2) * px) as u32,
+        );
+        canvas.set_draw_color(Color::RGBA(0, 0, 0, 160));
+        let _ = canvas.fill_rect(backing);
+
+        canvas.set_draw_color(Color::RGB(255, 255, 255));
+        let advance = (osd::GLYPH_WIDTH as i32 + 1) * px;
+        for (i, c) in text.chars().enumerate() {
+            let left = x + i as i32 * advance;
+            for (row, bits) in osd::glyph(c).iter().enumerate() {
+                for column in 0..osd::GLYPH_WIDTH {
+                    if bits & (1 << (osd::GLYPH_WIDTH - 1 - column)) != 0 {
+                        let _ = canvas.fill_rect(Rect::new(
+                            left + column as i32 * px,
+                            y + row as i32 * px,
+                            px as u32,
+                            px as u32,
+                        ));
+                    }
+                }
+            }
+        }
+    }
+
+    // Largest centered area of the window keeping the display's aspect ratio, as (x, y, pixel size)
+    fn viewport(output: (u32, u32), columns: u32, rows: u32, integer: bool) -> (i32, i32, f32) {
+        let (width, height) = output;
+        let columns = columns.max(1) as f32;
+        let rows = rows.max(1) as f32;
+
+        let mut pixel = (width as f32 / columns).min(height as f32 / rows);
+        if integer && pixel >= 1.0 {
+            pixel = pixel.floor();
+        }
+
+        let offset_x = ((width as f32 - columns * pixel) / 2.0).round() as i32;
+        let offset_y = ((height as f32 - rows * pixel) / 2.0).round() as i32;
+        (offset_x, offset_y, pixel)
+    }
+
+    // Whole number scale of a 64x32 frame in the current window, used for screenshots and recordings
+    pub fn scale(&self) -> u32 {
+        match self.canvas.as_ref() {
+            Some(canvas) => {
+                let (width, height) = canvas.output_size().unwrap_or((64, 32));
+                (width / 64).min(height / 32).max(1)
+            }
+            None => self.headless_scale,
+        }
+    }
+
+    pub fn is_fullscreen(&self) -> bool {
+        self.canvas
+            .as_ref()
+            .is_some_and(|canvas| canvas.window().fullscreen_state() != FullscreenType::Off)
+    }
+
+    // Switch between windowed and borderless fullscreen at the desktop resolution
+    pub fn set_fullscreen(&mut self, fullscreen: bool) {
+        let state = if fullscreen {
+            FullscreenType::Desktop
         } else {
-            Color::RGB(255, 255, 255)
+            FullscreenType::Off
+        };
+        if let Some(canvas) = self.canvas.as_mut() {
+            if let Err(e) = canvas.window_mut().set_fullscreen(state) {
+                eprintln!("ERROR: Failed to change fullscreen mode: {}", e);
+            }
+        }
+    }
+
+    pub fn toggle_fullscreen(&mut self) {
+        self.set_fullscreen(!self.is_fullscreen());
+    }
+
+    // Start capturing presented frames to a .gif or .y4m file
+    pub fn start_recording(&mut self, path: &Path, scale: u32) -> io::Result<()> {
+        self.stop_recording()?;
+        self.recorder = Some(Recorder::create(path, self.palette, scale)?);
+        Ok(())
+    }
+
+    // Finish the current recording, returning how many frames it holds
+    pub fn stop_recording(&mut self) -> io::Result<Option<u64>> {
+        match self.recorder.take() {
+            Some(mut recorder) => {
+                if let Some(seconds) = self.clock {
+                    recorder.advance_to(seconds);
+                }
+                let frames = recorder.frames();
+                recorder.finish()?;
+                Ok(Some(frames))
+            }
+            None => Ok(None),
         }
     }
+
+    pub fn recorded_frames(&self) -> Option<u64> {
+        self.recorder.as_ref().map(|recorder| recorder.frames())
+    }
 }
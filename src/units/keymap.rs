@@ -0,0 +1,459 @@
+use crate::units::ku::Hotkey;
+use sdl2::controller::{Axis, Button, GameController};
+use sdl2::keyboard::{Keycode, Scancode};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+// A host key, either by physical position or by the character the layout puts on it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HostKey {
+    Scancode(Scancode),
+    Keycode(Keycode),
+}
+
+impl HostKey {
+    // Parses an SDL scancode name like "W" or "Up", or "key:NAME" to bind by keycode instead
+    pub fn parse(name: &str) -> Option<HostKey> {
+        match name.strip_prefix("key:") {
+            Some(name) => Keycode::from_name(name).map(HostKey::Keycode),
+            None => Scancode::from_name(name).map(HostKey::Scancode),
+        }
+    }
+
+    pub fn matches(&self, scancode: Scancode) -> bool {
+        match *self {
+            HostKey::Scancode(bound) => bound == scancode,
+            HostKey::Keycode(bound) => Keycode::from_scancode(scancode) == Some(bound),
+        }
+    }
+}
+
+impl fmt::Display for HostKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HostKey::Scancode(scancode) => write!(f, "{}", scancode.name()),
+            HostKey::Keycode(keycode) => write!(f, "key:{}", keycode.name()),
+        }
+    }
+}
+
+// A game controller input, a button or one direction of an analog axis
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PadInput {
+    Button(Button),
+    // Axis pushed past the deadzone, towards the positive end when the flag is set
+    Axis(Axis, bool),
+}
+
+impl PadInput {
+    // Parses SDL controller names like "a", "dpup" or "leftshoulder", axes take a direction as in "leftx-"
+    pub fn parse(name: &str) -> Option<PadInput> {
+        if let Some(axis) = name.strip_suffix('+') {
+            return Axis::from_string(axis).map(|axis| PadInput::Axis(axis, true));
+        }
+        if let Some(axis) = name.strip_suffix('-') {
+            return Axis::from_string(axis).map(|axis| PadInput::Axis(axis, false));
+        }
+        Button::from_string(name).map(PadInput::Button)
+    }
+
+    pub fn is_held(&self, controller: &GameController, deadzone: i16) -> bool {
+        match *self {
+            PadInput::Button(button) => controller.button(button),
+            PadInput::Axis(axis, true) => controller.axis(axis) > deadzone,
+            PadInput::Axis(axis, false) => controller.axis(axis) < -deadzone,
+        }
+    }
+}
+
+impl fmt::Display for PadInput {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PadInput::Button(button) => write!(f, "pad:{}", button.string()),
+            PadInput::Axis(axis, positive) => {
+                write!(
+                    f,
+                    "pad:{}{}",
+                    axis.string(),
+                    if *positive { '+' } else { '-' }
+                )
+            }
+        }
+    }
+}
+
+// Analog stick travel ignored around the center, about a quarter of the range
+pub const DEFAULT_DEADZONE: i16 = 8000;
+
+// A host key, with or without Shift, that triggers a hotkey
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HotkeyBinding {
+    pub key: HostKey,
+    pub shift: bool,
+    pub hotkey: Hotkey,
+}
+
+impl HotkeyBinding {
+    // Parses a host key name, optionally prefixed with "Shift+"
+    pub fn parse(name: &str, hotkey: Hotkey) -> Option<HotkeyBinding> {
+        let (name, shift) = match name.strip_prefix("Shift+") {
+            Some(name) => (name, true),
+            None => (name, false),
+        };
+        Some(HotkeyBinding {
+            key: HostKey::parse(name)?,
+            shift,
+            hotkey,
+        })
+    }
+}
+
+// Host keys and controller inputs bound to each of the 16 Chip-8 keys, and the hotkey keys
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Keymap {
+    pub bindings: [Vec<HostKey>; 16],
+    pub pad: [Vec<PadInput>; 16],
+    pub deadzone: i16,
+    pub hotkeys: Vec<HotkeyBinding>,
+}
+
+impl Default for Keymap {
+    // The 1234/QWER/ASDF/ZXCV block by position, so it stays in place on AZERTY or Dvorak,
+    // and directions on 5/7/8/9 (W/A/S/D) with A and B on 6 and 4 (E and Q) like Octo
+    fn default() -> Self {
+        let layout = [
+            (0x1, Scancode::Num1),
+            (0x2, Scancode::Num2),
+            (0x3, Scancode::Num3),
+            (0xc, Scancode::Num4),
+            (0x4, Scancode::Q),
+            (0x5, Scancode::W),
+            (0x6, Scancode::E),
+            (0xd, Scancode::R),
+            (0x7, Scancode::A),
+            (0x8, Scancode::S),
+            (0x9, Scancode::D),
+            (0xe, Scancode::F),
+            (0xa, Scancode::Z),
+            (0x0, Scancode::X),
+            (0xb, Scancode::C),
+            (0xf, Scancode::V),
+        ];
+        let pad = [
+            (0x5, PadInput::Button(Button::DPadUp)),
+            (0x5, PadInput::Axis(Axis::LeftY, false)),
+            (0x7, PadInput::Button(Button::DPadLeft)),
+            (0x7, PadInput::Axis(Axis::LeftX, false)),
+            (0x8, PadInput::Button(Button::DPadDown)),
+            (0x8, PadInput::Axis(Axis::LeftY, true)),
+            (0x9, PadInput::Button(Button::DPadRight)),
+            (0x9, PadInput::Axis(Axis::LeftX, true)),
+            (0x6, PadInput::Button(Button::A)),
+            (0x4, PadInput::Button(Button::B)),
+        ];
+        let hotkeys = [
+            (Hotkey::Screenshot, Scancode::F12, false),
+            (Hotkey::ScreenshotNative, Scancode::F12, true),
+            (Hotkey::ToggleRecording, Scancode::F10, false),
+            (Hotkey::ToggleFullscreen, Scancode::F11, false),
+            (Hotkey::ToggleOsd, Scancode::F1, false),
+            (Hotkey::ToggleMute, Scancode::F6, false),
+            (Hotkey::VolumeDown, Scancode::F7, false),
+            (Hotkey::VolumeUp, Scancode::F8, false),
+            (Hotkey::Pause, Scancode::F2, false),
+            (Hotkey::FrameAdvance, Scancode::F3, false),
+            (Hotkey::SoftReset, Scancode::F4, false),
+            (Hotkey::HardReset, Scancode::F4, true),
+            (Hotkey::FastForward, Scancode::Tab, false),
+            (Hotkey::SlowMotion, Scancode::Backspace, false),
+            (Hotkey::SpeedDown, Scancode::Minus, false),
+            (Hotkey::SpeedUp, Scancode::Equals, false),
+        ];
+        let mut keymap = Keymap {
+            bindings: Default::default(),
+            pad: Default::default(),
+            deadzone: DEFAULT_DEADZONE,
+            hotkeys: hotkeys
+                .iter()
+                .map(|&(hotkey, scancode, shift)| HotkeyBinding {
+                    key: HostKey::Scancode(scancode),
+                    shift,
+                    hotkey,
+                })
+                .collect(),
+        };
+        for (key, scancode) in layout {
+            keymap.bindings[key].push(HostKey::Scancode(scancode));
+        }
+        for (key, input) in pad {
+            keymap.pad[key].push(input);
+        }
+        keymap
+    }
+}
+
+impl Keymap {
+    // Chip-8 keys held given the scancodes currently pressed
+    pub fn keys<I: IntoIterator<Item = Scancode>>(&self, pressed: I) -> [bool; 16] {
+        let mut keys = [false; 16];
+        for scancode in pressed {
+            for (key, bindings) in self.bindings.iter().enumerate() {
+                if bindings.iter().any(|binding| binding.matches(scancode)) {
+                    keys[key] = true;
+                }
+            }
+        }
+        keys
+    }
+
+    // Add the Chip-8 keys held on a game controller
+    pub fn pad_keys(&self, controller: &GameController, keys: &mut [bool; 16]) {
+        for (key, inputs) in self.pad.iter().enumerate() {
+            if inputs
+                .iter()
+                .any(|input| input.is_held(controller, self.deadzone))
+            {
+                keys[key] = true;
+            }
+        }
+    }
+
+    // Move a controller direction or button to another Chip-8 key, using the ROM database's names
+    // (up, down, left, right, a, b). Returns false for names it doesn't know
+    pub fn bind_pad(&mut self, name: &str, key: u8) -> bool {
+        let inputs = match name {
+            "up" => [
+                PadInput::Button(Button::DPadUp),
+                PadInput::Axis(Axis::LeftY, false),
+            ]
+            .to_vec(),
+            "down" => [
+                PadInput::Button(Button::DPadDown),
+                PadInput::Axis(Axis::LeftY, true),
+            ]
+            .to_vec(),
+            "left" => [
+                PadInput::Button(Button::DPadLeft),
+                PadInput::Axis(Axis::LeftX, false),
+            ]
+            .to_vec(),
+            "right" => [
+                PadInput::Button(Button::DPadRight),
+                PadInput::Axis(Axis::LeftX, true),
+            ]
+            .to_vec(),
+            "a" => vec![PadInput::Button(Button::A)],
+            "b" => vec![PadInput::Button(Button::B)],
+            _ => return false,
+        };
+        for bound in self.pad.iter_mut() {
+            bound.retain(|input| !inputs.contains(input));
+        }
+        self.pad[usize::from(key & 0xF)].extend(inputs);
+        true
+    }
+
+    // Hotkey for a key press, a binding made with Shift wins over the plain one while Shift is held
+    pub fn hotkey(&self, scancode: Scancode, shift: bool) -> Option<Hotkey> {
+        let mut matching = self
+            .hotkeys
+            .iter()
+            .filter(|binding| binding.key.matches(scancode));
+        let exact = matching.clone().find(|binding| binding.shift == shift);
+        exact
+            .or_else(|| matching.find(|binding| !binding.shift))
+            .map(|binding| binding.hotkey)
+    }
+
+    // Read a keymap file on top of this one, keys it mentions are rebound and the rest kept
+    pub fn load(&mut self, path: &Path) -> io::Result<()> {
+        let text = fs::read_to_string(path)?;
+        self.apply(&text).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}: {}", path.display(), e),
+            )
+        })
+    }
+
+    // One binding per line as "KEY = HOST, HOST, ...", KEY is a hex digit, # starts a comment.
+    // Controller inputs start with pad: and replace only the controller bindings of that key
+    //
+    //     # Arrows for a game using 2/4/6/8
+    //     2 = Up, W, pad:dpup, pad:lefty-
+    //     8 = Down, S, Keypad 2
+    //     4 = Left, A
+    //     6 = Right, D
+    //     deadzone = 12000
+    //
+    // Hotkeys are bound by name in the same way, replacing their default keys
+    //
+    //     pause = P, Pause
+    //     hard-reset = Shift+F5
+    pub fn apply(&mut self, text: &str) -> Result<(), String> {
+        for (number, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (key, hosts) = line
+                .split_once('=')
+                .ok_or_else(|| format!("line {}: expected KEY = HOST, ...", number + 1))?;
+            if key.trim() == "deadzone" {
+                self.deadzone = hosts
+                    .trim()
+                    .parse::<i16>()
+                    .ok()
+                    .filter(|&deadzone| deadzone >= 0)
+                    .ok_or_else(|| {
+                        format!("line {}: invalid deadzone: {}", number + 1, hosts.trim())
+                    })?;
+                continue;
+            }
+            if let Some(hotkey) = Hotkey::from_name(key.trim()) {
+                let mut bindings = Vec::new();
+                for name in hosts
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|name| !name.is_empty())
+                {
+                    bindings.push(HotkeyBinding::parse(name, hotkey).ok_or_else(|| {
+                        format!("line {}: unknown host key: {}", number + 1, name)
+                    })?);
+                }
+                self.hotkeys.retain(|binding| binding.hotkey != hotkey);
+                self.hotkeys.extend(bindings);
+                continue;
+            }
+            let key = usize::from_str_radix(key.trim(), 16)
+                .ok()
+                .filter(|&key| key < 16)
+                .ok_or_else(|| {
+                    format!("line {}: unknown Chip-8 key: {}", number + 1, key.trim())
+                })?;
+
+            let mut bindings = Vec::new();
+            let mut pad = Vec::new();
+            for name in hosts
+                .split(',')
+                .map(str::trim)
+                .filter(|name| !name.is_empty())
+            {
+                let unknown = || format!("line {}: unknown host key: {}", number + 1, name);
+                match name.strip_prefix("pad:") {
+                    Some(input) => pad.push(PadInput::parse(input).ok_or_else(unknown)?),
+                    None => bindings.push(HostKey::parse(name).ok_or_else(unknown)?),
+                }
+            }
+            // A line naming only keyboard keys leaves the controller alone and the other way round
+            if !bindings.is_empty() || pad.is_empty() {
+                self.bindings[key] = bindings;
+            }
+            if !pad.is_empty() {
+                self.pad[key] = pad;
+            }
+        }
+        Ok(())
+    }
+
+    // Per-ROM keymap kept next to the ROM, pong.ch8 reads pong.keymap
+    pub fn rom_path(rom: &Path) -> PathBuf {
+        rom.with_extension("keymap")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keymap(text: &str) -> Keymap {
+        let mut keymap = Keymap::default();
+        keymap.apply(text).unwrap();
+        keymap
+    }
+
+    fn error(text: &str) -> String {
+        Keymap::default().apply(text).unwrap_err()
+    }
+
+    #[test]
+    fn key_lines_replace_only_the_keyboard_bindings() {
+        let keymap = keymap("# Arrows\n2 = Up, W   # and WASD\n8 = Down, S, Keypad 2\n5 = key:Q\n");
+        assert_eq!(
+            keymap.bindings[2],
+            [
+                HostKey::Scancode(Scancode::Up),
+                HostKey::Scancode(Scancode::W)
+            ]
+        );
+        assert_eq!(keymap.bindings[8].len(), 3);
+        assert_eq!(keymap.bindings[5], [HostKey::Keycode(Keycode::Q)]);
+        // Untouched keys and the controller keep their defaults
+        assert_eq!(keymap.bindings[1], [HostKey::Scancode(Scancode::Num1)]);
+        assert_eq!(keymap.pad, Keymap::default().pad);
+    }
+
+    #[test]
+    fn pad_lines_replace_only_the_controller_bindings() {
+        let keymap = keymap("5 = pad:a, pad:lefty-\n2 = Up, pad:dpup\n");
+        assert_eq!(
+            keymap.pad[5],
+            [
+                PadInput::Button(Button::A),
+                PadInput::Axis(Axis::LeftY, false)
+            ]
+        );
+        assert_eq!(keymap.bindings[5], [HostKey::Scancode(Scancode::W)]);
+        // Both kinds on one line set both
+        assert_eq!(keymap.bindings[2], [HostKey::Scancode(Scancode::Up)]);
+        assert_eq!(keymap.pad[2], [PadInput::Button(Button::DPadUp)]);
+    }
+
+    #[test]
+    fn hotkey_lines_replace_the_default_keys() {
+        let keymap = keymap("pause = P, Pause\nhard-reset = Shift+F5\n");
+        assert_eq!(keymap.hotkey(Scancode::P, false), Some(Hotkey::Pause));
+        assert_eq!(keymap.hotkey(Scancode::Pause, false), Some(Hotkey::Pause));
+        assert_eq!(keymap.hotkey(Scancode::F2, false), None);
+        assert_eq!(keymap.hotkey(Scancode::F5, true), Some(Hotkey::HardReset));
+        assert_eq!(keymap.hotkey(Scancode::F5, false), None);
+        // Shift+F4 falls back to the plain F4 binding once hard-reset has moved
+        assert_eq!(keymap.hotkey(Scancode::F4, true), Some(Hotkey::SoftReset));
+    }
+
+    #[test]
+    fn deadzone_is_read() {
+        assert_eq!(keymap("deadzone = 12000\n").deadzone, 12000);
+        assert_eq!(Keymap::default().deadzone, DEFAULT_DEADZONE);
+        assert_eq!(error("deadzone = -1\n"), "line 1: invalid deadzone: -1");
+        assert_eq!(error("deadzone = lots\n"), "line 1: invalid deadzone: lots");
+    }
+
+    #[test]
+    fn later_lines_win_and_a_host_key_can_press_several_keys() {
+        let keymap = keymap("2 = W\n2 = S\npause = P\npause = Pause\n3 = S\n");
+        assert_eq!(keymap.bindings[2], [HostKey::Scancode(Scancode::S)]);
+        assert_eq!(keymap.hotkey(Scancode::P, false), None);
+        assert_eq!(keymap.hotkey(Scancode::Pause, false), Some(Hotkey::Pause));
+
+        let keys = keymap.keys([Scancode::S]);
+        let held: Vec<usize> = (0..16).filter(|&key| keys[key]).collect();
+        // S was also key 8 by default
+        assert_eq!(held, [2, 3, 8]);
+    }
+
+    #[test]
+    fn malformed_lines_name_the_line() {
+        assert_eq!(error("\n2 W\n"), "line 2: expected KEY = HOST, ...");
+        assert_eq!(error("g = W\n"), "line 1: unknown Chip-8 key: g");
+        assert_eq!(error("10 = W\n"), "line 1: unknown Chip-8 key: 10");
+        assert_eq!(error("2 = Nope\n"), "line 1: unknown host key: Nope");
+        assert_eq!(
+            error("2 = pad:nope\n"),
+            "line 1: unknown host key: pad:nope"
+        );
+        assert_eq!(error("pause = Nope\n"), "line 1: unknown host key: Nope");
+    }
+}
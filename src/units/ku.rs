@@ -1,36 +1,179 @@
-use sdl2::{event::Event, keyboard::Keycode, EventPump, Sdl};
+use crate::units::keymap::Keymap;
+use sdl2::{
+    controller::GameController,
+    event::{Event, WindowEvent},
+    keyboard::{Keycode, Mod},
+    EventPump, GameControllerSubsystem,
+};
+
+// Emulator actions triggered by keys outside the Chip-8 keypad, default keys in brackets
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Hotkey {
+    // Save the current frame at window scale (F12)
+    Screenshot,
+    // Save the current frame at 64x32 (Shift+F12)
+    ScreenshotNative,
+    // Start or stop recording gameplay to a GIF (F10)
+    ToggleRecording,
+    // Switch between windowed and fullscreen (F11)
+    ToggleFullscreen,
+    // Show or hide the on-screen display (F1)
+    ToggleOsd,
+    // Silence or restore the buzzer (F6)
+    ToggleMute,
+    // Lower or raise the buzzer volume (F7, F8)
+    VolumeDown,
+    VolumeUp,
+    // Stop or resume emulation (F2)
+    Pause,
+    // Run a single frame and stay paused (F3)
+    FrameAdvance,
+    // Restart the ROM keeping memory outside it (F4)
+    SoftReset,
+    // Restart from a cleared machine (Shift+F4)
+    HardReset,
+    // Run as fast as possible while held (Tab)
+    FastForward,
+    // Run at half speed until pressed again (Backspace)
+    SlowMotion,
+    // Run fewer or more instructions per frame (-, =)
+    SpeedDown,
+    SpeedUp,
+}
+
+impl Hotkey {
+    pub const ALL: [Hotkey; 16] = [
+        Hotkey::Screenshot,
+        Hotkey::ScreenshotNative,
+        Hotkey::ToggleRecording,
+        Hotkey::ToggleFullscreen,
+        Hotkey::ToggleOsd,
+        Hotkey::ToggleMute,
+        Hotkey::VolumeDown,
+        Hotkey::VolumeUp,
+        Hotkey::Pause,
+        Hotkey::FrameAdvance,
+        Hotkey::SoftReset,
+        Hotkey::HardReset,
+        Hotkey::FastForward,
+        Hotkey::SlowMotion,
+        Hotkey::SpeedDown,
+        Hotkey::SpeedUp,
+    ];
+
+    // Name used in keymap files
+    pub fn name(self) -> &'static str {
+        match self {
+            Hotkey::Screenshot => "screenshot",
+            Hotkey::ScreenshotNative => "screenshot-native",
+            Hotkey::ToggleRecording => "record",
+            Hotkey::ToggleFullscreen => "fullscreen",
+            Hotkey::ToggleOsd => "osd",
+            Hotkey::ToggleMute => "mute",
+            Hotkey::VolumeDown => "volume-down",
+            Hotkey::VolumeUp => "volume-up",
+            Hotkey::Pause => "pause",
+            Hotkey::FrameAdvance => "frame-advance",
+            Hotkey::SoftReset => "soft-reset",
+            Hotkey::HardReset => "hard-reset",
+            Hotkey::FastForward => "fast-forward",
+            Hotkey::SlowMotion => "slow-motion",
+            Hotkey::SpeedDown => "speed-down",
+            Hotkey::SpeedUp => "speed-up",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Hotkey> {
+        Hotkey::ALL.into_iter().find(|hotkey| hotkey.name() == name)
+    }
+
+    // Whether holding the key fires it again with the keyboard's repeat rate
+    fn repeats(self) -> bool {
+        matches!(
+            self,
+            Hotkey::VolumeDown
+                | Hotkey::VolumeUp
+                | Hotkey::FrameAdvance
+                | Hotkey::SpeedDown
+                | Hotkey::SpeedUp
+        )
+    }
+}
 
 // Keyboard Unit
 pub struct KU {
-    event_pump: EventPump,
-    pub kd: bool,
+    // None when running headless, no keys are ever pressed
+    event_pump: Option<EventPump>,
+    // Controllers are opened as they are plugged in, SDL reports the ones present at startup too
+    controller_subsystem: Option<GameControllerSubsystem>,
+    controllers: Vec<GameController>,
     pub key_state: Option<u8>,
+    pub keymap: Keymap,
+    hotkeys: Vec<Hotkey>,
+    window_changed: bool,
 }
 
 impl KU {
-    // Create new keyboard unit instance
-    pub fn new(context: &Sdl) -> KU {
-        let event_pump = context.event_pump().ok().unwrap();
-
+    // Create new keyboard unit instance, without controllers when SDL has no support for them
+    pub fn new(event_pump: EventPump, controller_subsystem: Option<GameControllerSubsystem>) -> KU {
         KU {
-            event_pump,
-            kd: false,
+            event_pump: Some(event_pump),
+            controller_subsystem,
+            controllers: Vec::new(),
             key_state: None,
+            keymap: Keymap::default(),
+            hotkeys: Vec::new(),
+            window_changed: false,
         }
     }
 
-    pub fn reset_key_down(&mut self) {
-        self.kd = false;
+    // Keypad that never touches SDL and never asks to quit
+    pub fn headless() -> KU {
+        KU {
+            event_pump: None,
+            controller_subsystem: None,
+            controllers: Vec::new(),
+            key_state: None,
+            keymap: Keymap::default(),
+            hotkeys: Vec::new(),
+            window_changed: false,
+        }
     }
 
     pub fn get_key_state(&self) -> Option<u8> {
         self.key_state
     }
 
+    // Whether the window was resized or uncovered since the last call and needs redrawing
+    pub fn take_window_changed(&mut self) -> bool {
+        std::mem::take(&mut self.window_changed)
+    }
+
+    // Drain hotkeys pressed since the last call
+    pub fn take_hotkeys(&mut self) -> Vec<Hotkey> {
+        std::mem::take(&mut self.hotkeys)
+    }
+
+    // Whether a key bound to the hotkey is held right now, for hotkeys that act while held
+    pub fn hotkey_held(&self, hotkey: Hotkey) -> bool {
+        match self.event_pump.as_ref() {
+            Some(event_pump) => event_pump
+                .keyboard_state()
+                .pressed_scancodes()
+                .any(|scancode| self.keymap.hotkey(scancode, false) == Some(hotkey)),
+            None => false,
+        }
+    }
+
     // Loop over events and process keystrokes
+    #[allow(clippy::result_unit_err)]
     pub fn process_input(&mut self) -> Result<[bool; 16], ()> {
-        for event_type in self.event_pump.poll_iter() {
-            self.kd = true;
+        let event_pump = match self.event_pump.as_mut() {
+            Some(event_pump) => event_pump,
+            None => return Ok([false; 16]),
+        };
+
+        for event_type in event_pump.poll_iter() {
             match event_type {
                 Event::Quit { .. }
                 | Event::KeyDown {
@@ -39,44 +182,55 @@ impl KU {
                 } => {
                     return Err(());
                 }
+                Event::KeyDown {
+                    scancode: Some(scancode),
+                    keymod,
+                    repeat,
+                    ..
+                } => {
+                    let shift = keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD);
+                    if let Some(hotkey) = self.keymap.hotkey(scancode, shift) {
+                        if !repeat || hotkey.repeats() {
+                            self.hotkeys.push(hotkey);
+                        }
+                    }
+                }
+                Event::ControllerDeviceAdded { which, .. } => {
+                    if let Some(subsystem) = self.controller_subsystem.as_ref() {
+                        match subsystem.open(which) {
+                            Ok(controller) => {
+                                println!("Controller connected: {}", controller.name());
+                                self.controllers.push(controller);
+                            }
+                            Err(e) => eprintln!("ERROR: Failed to open controller: {}", e),
+                        }
+                    }
+                }
+                Event::ControllerDeviceRemoved { which, .. } => {
+                    self.controllers.retain(|controller| {
+                        if controller.instance_id() == which {
+                            println!("Controller disconnected: {}", controller.name());
+                            false
+                        } else {
+                            true
+                        }
+                    });
+                }
+                Event::Window {
+                    win_event: WindowEvent::SizeChanged(..) | WindowEvent::Exposed,
+                    ..
+                } => {
+                    self.window_changed = true;
+                }
                 _ => {}
             }
         }
 
-        let keys: Vec<Keycode> = self
-            .event_pump
-            .keyboard_state()
-            .pressed_scancodes()
-            .filter_map(Keycode::from_scancode)
-            .collect();
-
-        let mut chip_keys = [false; 16];
-
-        for key in keys {
-            let index = match key {
-                Keycode::Num1 => Some(0x1),
-                Keycode::Num2 => Some(0x2),
-                Keycode::Num3 => Some(0x3),
-                Keycode::Num4 => Some(0xc),
-                Keycode::Q => Some(0x4),
-                Keycode::W => Some(0x5),
-                Keycode::E => Some(0x6),
-                Keycode::R => Some(0xd),
-                Keycode::A => Some(0x7),
-                Keycode::S => Some(0x8),
-                Keycode::D => Some(0x9),
-                Keycode::F => Some(0xe),
-                Keycode::Z => Some(0xa),
-                Keycode::X => Some(0x0),
-                Keycode::C => Some(0xb),
-                Keycode::V => Some(0xf),
-                _ => None,
-            };
-
-            if let Some(i) = index {
-                self.kd = true;
-                chip_keys[i] = true;
-            }
+        let mut chip_keys = self
+            .keymap
+            .keys(event_pump.keyboard_state().pressed_scancodes());
+        for controller in &self.controllers {
+            self.keymap.pad_keys(controller, &mut chip_keys);
         }
 
         Ok(chip_keys)
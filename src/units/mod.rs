@@ -1,5 +1,7 @@
 pub mod au;
 pub mod cu;
 pub mod gu;
+pub mod keymap;
 pub mod ku;
-pub mod platform;
+pub mod osd;
+pub mod tu;
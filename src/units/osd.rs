@@ -0,0 +1,194 @@
+use std::time::{Duration, Instant};
+
+// How long a transient message stays on screen
+const MESSAGE_TIME: Duration = Duration::from_secs(3);
+// Most messages shown at once, older ones are dropped first
+const MAX_MESSAGES: usize = 4;
+// Timers run at 60hz so a Chip-8 frame is a sixtieth of a second
+const FRAMES_PER_SECOND: f32 = 60.0;
+
+pub const GLYPH_WIDTH: u32 = 3;
+pub const GLYPH_HEIGHT: u32 = 5;
+
+// On-Screen Display state drawn by GU over the game
+pub struct Osd {
+    pub enabled: bool,
+    paused: bool,
+    status: Option<&'static str>,
+    messages: Vec<(String, Instant)>,
+    window_start: Instant,
+    frames: u32,
+    instructions: u64,
+    fps: f32,
+    ipf: f32,
+    dirty: bool,
+}
+
+impl Default for Osd {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Osd {
+    pub fn new() -> Osd {
+        Osd {
+            enabled: true,
+            paused: false,
+            status: None,
+            messages: Vec::new(),
+            window_start: Instant::now(),
+            frames: 0,
+            instructions: 0,
+            fps: 0.0,
+            ipf: 0.0,
+            dirty: false,
+        }
+    }
+
+    pub fn toggle(&mut self) {
+        self.enabled = !self.enabled;
+        self.dirty = true;
+    }
+
+    // Show a message for a few seconds
+    pub fn message(&mut self, text: &str) {
+        if self.messages.len() == MAX_MESSAGES {
+            self.messages.remove(0);
+        }
+        self.messages.push((text.to_string(), Instant::now()));
+        self.dirty = true;
+    }
+
+    pub fn set_paused(&mut self, paused: bool) {
+        self.dirty |= self.paused != paused;
+        self.paused = paused;
+    }
+
+    // Lasting indicator for the top right corner, e.g. while fast forwarding
+    pub fn set_status(&mut self, status: Option<&'static str>) {
+        self.dirty |= self.status != status;
+        self.status = status;
+    }
+
+    pub fn frame_presented(&mut self) {
+        self.frames += 1;
+    }
+
+    pub fn instructions_executed(&mut self, count: u64) {
+        self.instructions += count;
+    }
+
+    // Refresh the counters once a second and expire old messages
+    pub fn update(&mut self) {
+        let elapsed = self.window_start.elapsed();
+        if elapsed >= Duration::from_secs(1) {
+            let seconds = elapsed.as_secs_f32();
+            self.fps = self.frames as f32 / seconds;
+            self.ipf = self.instructions as f32 / seconds / FRAMES_PER_SECOND;
+            self.frames = 0;
+            self.instructions = 0;
+            self.window_start = Instant::now();
+            self.dirty |= self.enabled;
+        }
+
+        let count = self.messages.len();
+        self.messages
+            .retain(|(_, shown)| shown.elapsed() < MESSAGE_TIME);
+        self.dirty |= count != self.messages.len();
+    }
+
+    // Whether the overlay changed since the last call and the window needs redrawing
+    pub fn take_dirty(&mut self) -> bool {
+        std::mem::take(&mut self.dirty)
+    }
+
+    // Counter line for the top left corner
+    pub fn stats(&self) -> Option<String> {
+        if !self.enabled {
+            return None;
+        }
+        Some(format!("{:.0} FPS  {:.1} IPF", self.fps, self.ipf))
+    }
+
+    pub fn status(&self) -> Option<&'static str> {
+        if !self.enabled {
+            return None;
+        }
+        self.status
+    }
+
+    // Messages for the bottom left corner, oldest first
+    pub fn messages(&self) -> Vec<String> {
+        if !self.enabled {
+            return Vec::new();
+        }
+        self.messages.iter().map(|(text, _)| text.clone()).collect()
+    }
+
+    // Banner for the middle of the screen
+    pub fn banner(&self) -> Option<&'static str> {
+        if self.paused {
+            Some("PAUSED")
+        } else {
+            None
+        }
+    }
+}
+
+// Rows of a 3x5 glyph, bit 2 is the leftmost column, lowercase shares the uppercase shapes
+pub fn glyph(c: char) -> [u8; 5] {
+    match c.to_ascii_uppercase() {
+        '0' => [0b111, 0b101, 0b101, 0b101, 0b111],
+        '1' => [0b010, 0b110, 0b010, 0b010, 0b111],
+        '2' => [0b111, 0b001, 0b111, 0b100, 0b111],
+        '3' => [0b111, 0b001, 0b111, 0b001, 0b111],
+        '4' => [0b101, 0b101, 0b111, 0b001, 0b001],
+        '5' => [0b111, 0b100, 0b111, 0b001, 0b111],
+        '6' => [0b111, 0b100, 0b111, 0b101, 0b111],
+        '7' => [0b111, 0b001, 0b001, 0b001, 0b001],
+        '8' => [0b111, 0b101, 0b111, 0b101, 0b111],
+        '9' => [0b111, 0b101, 0b111, 0b001, 0b111],
+        'A' => [0b010, 0b101, 0b111, 0b101, 0b101],
+        'B' => [0b110, 0b101, 0b110, 0b101, 0b110],
+        'C' => [0b011, 0b100, 0b100, 0b100, 0b011],
+        'D' => [0b110, 0b101, 0b101, 0b101, 0b110],
+        'E' => [0b111, 0b100, 0b110, 0b100, 0b111],
+        'F' => [0b111, 0b100, 0b110, 0b100, 0b100],
+        'G' => [0b011, 0b100, 0b101, 0b101, 0b011],
+        'H' => [0b101, 0b101, 0b111, 0b101, 0b101],
+        'I' => [0b111, 0b010, 0b010, 0b010, 0b111],
+        'J' => [0b001, 0b001, 0b001, 0b101, 0b010],
+        'K' => [0b101, 0b101, 0b110, 0b101, 0b101],
+        'L' => [0b100, 0b100, 0b100, 0b100, 0b111],
+        'M' => [0b101, 0b111, 0b111, 0b101, 0b101],
+        'N' => [0b110, 0b101, 0b101, 0b101, 0b101],
+        'O' => [0b010, 0b101, 0b101, 0b101, 0b010],
+        'P' => [0b110, 0b101, 0b110, 0b100, 0b100],
+        'Q' => [0b010, 0b101, 0b101, 0b110, 0b011],
+        'R' => [0b110, 0b101, 0b110, 0b101, 0b101],
+        'S' => [0b011, 0b100, 0b010, 0b001, 0b110],
+        'T' => [0b111, 0b010, 0b010, 0b010, 0b010],
+        'U' => [0b101, 0b101, 0b101, 0b101, 0b111],
+        'V' => [0b101, 0b101, 0b101, 0b101, 0b010],
+        'W' => [0b101, 0b101, 0b111, 0b111, 0b101],
+        'X' => [0b101, 0b101, 0b010, 0b101, 0b101],
+        'Y' => [0b101, 0b101, 0b010, 0b010, 0b010],
+        'Z' => [0b111, 0b001, 0b010, 0b100, 0b111],
+        ' ' => [0b000, 0b000, 0b000, 0b000, 0b000],
+        '.' => [0b000, 0b000, 0b000, 0b000, 0b010],
+        ',' => [0b000, 0b000, 0b000, 0b010, 0b100],
+        ':' => [0b000, 0b010, 0b000, 0b010, 0b000],
+        '-' => [0b000, 0b000, 0b111, 0b000, 0b000],
+        '+' => [0b000, 0b010, 0b111, 0b010, 0b000],
+        '=' => [0b000, 0b111, 0b000, 0b111, 0b000],
+        '_' => [0b000, 0b000, 0b000, 0b000, 0b111],
+        '/' => [0b001, 0b001, 0b010, 0b100, 0b100],
+        '%' => [0b101, 0b001, 0b010, 0b100, 0b101],
+        '!' => [0b010, 0b010, 0b010, 0b000, 0b010],
+        '(' => [0b001, 0b010, 0b010, 0b010, 0b001],
+        ')' => [0b100, 0b010, 0b010, 0b010, 0b100],
+        '\'' => [0b010, 0b010, 0b000, 0b000, 0b000],
+        _ => [0b111, 0b001, 0b010, 0b000, 0b010],
+    }
+}
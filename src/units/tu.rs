@@ -0,0 +1,227 @@
+#[cfg(unix)]
+use crate::palette::Palette;
+#[cfg(unix)]
+use std::{
+    io::{self, Read, Write},
+    time::{Duration, Instant},
+};
+
+// Terminals only report key presses, so a key counts as held for this long after its last press
+#[cfg(unix)]
+const KEY_HOLD: Duration = Duration::from_millis(150);
+
+// How the framebuffer is packed into character cells
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TerminalMode {
+    // One cell covers 1x2 pixels using upper half blocks, 64x16 cells, keeps the palette colors
+    HalfBlock,
+    // One cell covers 2x4 pixels using braille dots, 32x8 cells, foreground color only
+    Braille,
+}
+
+// Terminal Unit, a display, keypad and bell on a raw mode tty for running without SDL
+#[cfg(unix)]
+pub struct TU {
+    mode: TerminalMode,
+    palette: Palette,
+    bell: bool,
+    saved: libc::termios,
+    saved_flags: libc::c_int,
+    pressed: [Option<Instant>; 16],
+    last_frame: Option<[[u16; 64]; 32]>,
+    last_sound: bool,
+    out: io::Stdout,
+}
+
+#[cfg(unix)]
+impl TU {
+    // Switch stdin to raw non-blocking mode and take over the screen
+    pub fn new(mode: TerminalMode, palette: Palette, bell: bool) -> io::Result<TU> {
+        let fd = libc::STDIN_FILENO;
+        let mut saved: libc::termios = unsafe { std::mem::zeroed() };
+        if unsafe { libc::tcgetattr(fd, &mut saved) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut raw = saved;
+        unsafe { libc::cfmakeraw(&mut raw) };
+        // Keep output post processing so \n still returns the carriage
+        raw.c_oflag |= libc::OPOST;
+        if unsafe { libc::tcsetattr(fd, libc::TCSANOW, &raw) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let saved_flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+        unsafe { libc::fcntl(fd, libc::F_SETFL, saved_flags | libc::O_NONBLOCK) };
+
+        let mut out = io::stdout();
+        // Alternate screen, hide cursor, clear
+        write!(out, "\x1b[?1049h\x1b[?25l\x1b[2J")?;
+        out.flush()?;
+
+        Ok(TU {
+            mode,
+            palette,
+            bell,
+            saved,
+            saved_flags,
+            pressed: [None; 16],
+            last_frame: None,
+            last_sound: false,
+            out,
+        })
+    }
+
+    // Read pending bytes from stdin and report which keys count as held
+    #[allow(clippy::result_unit_err)]
+    pub fn process_input(&mut self) -> Result<[bool; 16], ()> {
+        let mut buffer = [0u8; 64];
+        let now = Instant::now();
+
+        loop {
+            let read = match io::stdin().read(&mut buffer) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(_) => break,
+            };
+            let bytes = &buffer[..read];
+
+            // A lone Escape or Ctrl+C quits, longer escape sequences are arrow keys and the like
+            if bytes == [0x1b] || bytes.contains(&0x03) {
+                return Err(());
+            }
+            if bytes[0] == 0x1b {
+                continue;
+            }
+
+            for &byte in bytes {
+                if let Some(i) = TU::keypad_index(byte) {
+                    self.pressed[i] = Some(now);
+                }
+            }
+        }
+
+        let mut chip_keys = [false; 16];
+        for (i, pressed) in self.pressed.iter_mut().enumerate() {
+            match pressed {
+                Some(at) if now.duration_since(*at) < KEY_HOLD => chip_keys[i] = true,
+                _ => *pressed = None,
+            }
+        }
+
+        Ok(chip_keys)
+    }
+
+    // Same 1234/QWER/ASDF/ZXCV layout as the default Keymap
+    fn keypad_index(byte: u8) -> Option<usize> {
+        match byte.to_ascii_lowercase() {
+            b'1' => Some(0x1),
+            b'2' => Some(0x2),
+            b'3' => Some(0x3),
+            b'4' => Some(0xc),
+            b'q' => Some(0x4),
+            b'w' => Some(0x5),
+            b'e' => Some(0x6),
+            b'r' => Some(0xd),
+            b'a' => Some(0x7),
+            b's' => Some(0x8),
+            b'd' => Some(0x9),
+            b'f' => Some(0xe),
+            b'z' => Some(0xa),
+            b'x' => Some(0x0),
+            b'c' => Some(0xb),
+            b'v' => Some(0xf),
+            _ => None,
+        }
+    }
+
+    // Redraw the screen, skipping frames identical to the last one drawn
+    pub fn draw(&mut self, gfx: &[[u16; 64]; 32]) -> io::Result<()> {
+        if self.last_frame.as_ref() == Some(gfx) {
+            return Ok(());
+        }
+
+        let frame = match self.mode {
+            TerminalMode::HalfBlock => self.render_half_block(gfx),
+            TerminalMode::Braille => self.render_braille(gfx),
+        };
+        self.out.write_all(frame.as_bytes())?;
+        self.out.flush()?;
+        self.last_frame = Some(*gfx);
+        Ok(())
+    }
+
+    fn render_half_block(&self, gfx: &[[u16; 64]; 32]) -> String {
+        let mut frame = String::from("\x1b[H");
+        let mut colors = None;
+
+        for rows in gfx.chunks(2) {
+            for (&top, &bottom) in rows[0].iter().zip(rows[1].iter()) {
+                let top = self.palette.color(top);
+                let bottom = self.palette.color(bottom);
+                if colors != Some((top, bottom)) {
+                    frame.push_str(&format!(
+                        "\x1b[38;2;{};{};{}m\x1b[48;2;{};{};{}m",
+                        top[0], top[1], top[2], bottom[0], bottom[1], bottom[2]
+                    ));
+                    colors = Some((top, bottom));
+                }
+                frame.push('\u{2580}');
+            }
+            frame.push_str("\x1b[0m\r\n");
+            colors = None;
+        }
+        frame
+    }
+
+    fn render_braille(&self, gfx: &[[u16; 64]; 32]) -> String {
+        // Dot bit for each (x, y) position inside a 2x4 braille cell
+        const DOTS: [[u32; 2]; 4] = [[0x01, 0x08], [0x02, 0x10], [0x04, 0x20], [0x40, 0x80]];
+
+        let [r, g, b] = self.palette.foreground;
+        let [br, bg, bb] = self.palette.background;
+        let mut frame = format!(
+            "\x1b[H\x1b[38;2;{};{};{}m\x1b[48;2;{};{};{}m",
+            r, g, b, br, bg, bb
+        );
+
+        for rows in gfx.chunks(4) {
+            for cell in 0..32 {
+                let mut bits = 0;
+                for (dy, row) in rows.iter().enumerate() {
+                    for (dx, dot) in DOTS[dy].iter().enumerate() {
+                        if row[cell * 2 + dx] != 0 {
+                            bits |= dot;
+                        }
+                    }
+                }
+                frame.push(char::from_u32(0x2800 + bits).unwrap_or(' '));
+            }
+            frame.push_str("\r\n");
+        }
+        frame.push_str("\x1b[0m");
+        frame
+    }
+
+    // Ring the terminal bell when the buzzer switches on
+    pub fn set_buzzer(&mut self, on: bool) {
+        if on && !self.last_sound && self.bell {
+            let _ = self.out.write_all(b"\x07");
+            let _ = self.out.flush();
+        }
+        self.last_sound = on;
+    }
+}
+
+#[cfg(unix)]
+impl Drop for TU {
+    // Hand the terminal back the way we found it
+    fn drop(&mut self) {
+        let _ = write!(self.out, "\x1b[0m\x1b[?25h\x1b[?1049l");
+        let _ = self.out.flush();
+        unsafe {
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.saved);
+            libc::fcntl(libc::STDIN_FILENO, libc::F_SETFL, self.saved_flags);
+        }
+    }
+}
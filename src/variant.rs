@@ -0,0 +1,108 @@
+use crate::quirks::Quirks;
+use crate::system::ROM_START;
+use std::fmt;
+use std::str::FromStr;
+
+// Chip-8 platform a ROM was written for, chosen with --platform
+//
+// Only the quirks differ for now, the extra SCHIP and XO-CHIP instructions aren't emulated
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Variant {
+    // The original interpreter on the COSMAC VIP
+    Chip8,
+    // SUPER-CHIP 1.1 on the HP 48
+    Schip,
+    // Octo's XO-CHIP extension
+    XoChip,
+    // CHIP-8 on the ETI-660 kit computer, programs start at 0x600. Its 64x48 screen isn't emulated
+    Eti660,
+}
+
+impl Variant {
+    pub const ALL: [Variant; 4] = [
+        Variant::Chip8,
+        Variant::Schip,
+        Variant::XoChip,
+        Variant::Eti660,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Variant::Chip8 => "chip8",
+            Variant::Schip => "schip",
+            Variant::XoChip => "xochip",
+            Variant::Eti660 => "eti660",
+        }
+    }
+
+    // Bytes of memory, XO-CHIP extends it to the whole 16 bit address space
+    pub fn memory_size(self) -> usize {
+        match self {
+            Variant::Chip8 | Variant::Schip | Variant::Eti660 => 4096,
+            Variant::XoChip => 65536,
+        }
+    }
+
+    // Where ROMs are loaded and start running
+    pub fn load_address(self) -> usize {
+        match self {
+            Variant::Eti660 => 0x600,
+            _ => ROM_START,
+        }
+    }
+
+    // Largest ROM that fits after the load address
+    pub fn max_rom_size(self) -> usize {
+        self.memory_size() - self.load_address()
+    }
+
+    // How the platform's own interpreter behaves
+    pub fn quirks(self) -> Quirks {
+        match self {
+            Variant::Chip8 | Variant::Eti660 => Quirks {
+                vf_reset: true,
+                memory_increment: true,
+                shift_vy: true,
+                jump_vx: false,
+                clip: true,
+                key_wait_release: true,
+            },
+            Variant::Schip => Quirks {
+                vf_reset: false,
+                memory_increment: false,
+                shift_vy: false,
+                jump_vx: true,
+                clip: true,
+                key_wait_release: false,
+            },
+            Variant::XoChip => Quirks {
+                vf_reset: false,
+                memory_increment: true,
+                shift_vy: true,
+                jump_vx: false,
+                clip: false,
+                key_wait_release: false,
+            },
+        }
+    }
+}
+
+impl FromStr for Variant {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Variant, String> {
+        match s.to_ascii_lowercase().as_str() {
+            "chip8" | "chip-8" | "vip" => Ok(Variant::Chip8),
+            "schip" | "superchip" | "super-chip" => Ok(Variant::Schip),
+            "xochip" | "xo-chip" => Ok(Variant::XoChip),
+            "eti660" | "eti-660" => Ok(Variant::Eti660),
+            _ => Err(format!("unknown platform: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for Variant {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}